@@ -52,6 +52,37 @@ fn main() {
         .wait()
         .expect("failed to compile boot.S");
 
+    // User programs are flat binaries, embedded with include_bytes!
+    Command::new("nasm")
+        .stdout(Stdio::inherit())
+        .args(&["src/user/hello.S", "-o", "BUILD_FILES/hello.bin", "-fbin"])
+        .spawn()
+        .expect("failed to run nasm")
+        .wait()
+        .expect("failed to assemble hello.S");
+
+    Command::new("nasm")
+        .stdout(Stdio::inherit())
+        .args(&["src/user/args.S", "-o", "BUILD_FILES/args.o", "-felf64"])
+        .spawn()
+        .expect("failed to run nasm")
+        .wait()
+        .expect("failed to assemble args.S");
+
+    Command::new("ld")
+        .stdout(Stdio::inherit())
+        .args(&[
+            "-static",
+            "-nostdlib",
+            "BUILD_FILES/args.o",
+            "-o",
+            "BUILD_FILES/args.elf",
+        ])
+        .spawn()
+        .expect("failed to run ld")
+        .wait()
+        .expect("failed to link args.elf");
+
     cc::Build::new()
         .object("BUILD_FILES/boot.o")
         .compile("boot.o");
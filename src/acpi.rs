@@ -0,0 +1,313 @@
+use crate::config::CONFIG;
+use crate::vmm;
+use crate::{debug, info, warn};
+
+use core::str::from_utf8;
+
+/*
+ * The ACPI tables, found through the RSDP the bootloader copies into the
+ * multiboot information. ACPI 2.0 firmware points at an XSDT, whose entries
+ * are 64 bit addresses; older firmware only has an RSDT, with 32 bit ones.
+ *
+ * init checks every table's checksum and keeps the good ones, plus the DSDT the
+ * FADT points to, in a registry that is looked up by type:
+ *   let fadt = acpi::find::<Fadt>();
+ * Tables are used in place through the direct map, and never freed.
+ */
+
+/// The most tables the registry holds, including the RSDT or XSDT itself
+pub const MAX_TABLES: usize = 32;
+
+/// Only written by init, before other cores run
+static mut TABLES: [Option<&'static ACPIHeader>; MAX_TABLES] = [None; MAX_TABLES];
+
+/// The RSDP. The fields from length on only exist in revision 2 and later.
+#[repr(C, packed)]
+pub struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oemid: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+impl Rsdp {
+    /// The size of a revision 0 RSDP, which the first checksum covers
+    const V1_LENGTH: usize = 20;
+
+    fn bytes(&self, length: usize) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self as *const Rsdp as *const u8, length) }
+    }
+
+    pub fn revision(&self) -> u8 {
+        self.revision
+    }
+
+    /// Checks the signature and every checksum this revision has
+    pub fn is_valid(&self) -> bool {
+        if &self.signature != b"RSD PTR " || checksum(self.bytes(Rsdp::V1_LENGTH)) != 0 {
+            return false;
+        }
+        self.revision < 2 || checksum(self.bytes(self.length as usize)) == 0
+    }
+
+    /// The physical address of the XSDT, if there is one
+    pub fn xsdt_address(&self) -> Option<u64> {
+        match self.revision {
+            0 | 1 => None,
+            _ if self.xsdt_address == 0 => None,
+            _ => Some(self.xsdt_address),
+        }
+    }
+
+    pub fn print(&self) {
+        debug!(
+            "RSDP: oemid {} revision {} rsdt_address 0x{:x} xsdt_address {:x?}",
+            from_utf8(&self.oemid).unwrap_or("?"),
+            self.revision,
+            { self.rsdt_address },
+            self.xsdt_address()
+        );
+    }
+}
+
+/// The header every system description table starts with
+#[repr(C, packed)]
+pub struct ACPIHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oemid: [u8; 6],
+    oemtableid: [u8; 8],
+    oemrevision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+impl ACPIHeader {
+    pub fn print(&self) {
+        debug!(
+            "ACPIHeader: signature: {} length {} revision {} oemid {} oemtableid {}",
+            from_utf8(&self.signature).unwrap_or("?"),
+            { self.length },
+            self.revision,
+            from_utf8(&self.oemid).unwrap_or("?"),
+            from_utf8(&self.oemtableid).unwrap_or("?")
+        );
+    }
+
+    pub fn signature(&self) -> &[u8; 4] {
+        &self.signature
+    }
+
+    pub fn length(&self) -> u32 {
+        self.length
+    }
+
+    pub fn revision(&self) -> u8 {
+        self.revision
+    }
+
+    /// The whole table, header included
+    pub fn bytes(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(
+                self as *const ACPIHeader as *const u8,
+                self.length as usize,
+            )
+        }
+    }
+
+    /// Returns true if the bytes of the table sum to 0
+    pub fn is_valid(&self) -> bool {
+        (self.length as usize) >= core::mem::size_of::<ACPIHeader>() && checksum(self.bytes()) == 0
+    }
+}
+
+/// A table find can look up. Implementations must be repr(C, packed) and start with
+/// an ACPIHeader, since the registry's headers are cast to them.
+pub unsafe trait Table: Sized {
+    const SIGNATURE: &'static [u8; 4];
+    /// Shorter tables are not handed out. Tables that grew over ACPI revisions
+    /// set this to their oldest size, and check the length before using later fields.
+    const MIN_LENGTH: usize = core::mem::size_of::<Self>();
+}
+
+/// A Generic Address Structure: a register in some address space
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+pub struct GenericAddress {
+    /// 0 for memory, 1 for I/O ports
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+/// The Fixed ACPI Description Table, laid out as in ACPI 2.0.
+/// ACPI 1.0 tables end just before reset_register.
+#[repr(C, packed)]
+pub struct Fadt {
+    pub header: ACPIHeader,
+    pub firmware_control: u32,
+    pub dsdt: u32,
+    reserved: u8,
+    pub preferred_pm_profile: u8,
+    pub sci_interrupt: u16,
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub s4bios_request: u8,
+    pub pstate_control: u8,
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm2_control_block: u32,
+    pub pm_timer_block: u32,
+    pub gpe0_block: u32,
+    pub gpe1_block: u32,
+    pub pm1_event_length: u8,
+    pub pm1_control_length: u8,
+    pub pm2_control_length: u8,
+    pub pm_timer_length: u8,
+    pub gpe0_length: u8,
+    pub gpe1_length: u8,
+    pub gpe1_base: u8,
+    pub cstate_control: u8,
+    pub worst_c2_latency: u16,
+    pub worst_c3_latency: u16,
+    pub flush_size: u16,
+    pub flush_stride: u16,
+    pub duty_offset: u8,
+    pub duty_width: u8,
+    pub day_alarm: u8,
+    pub month_alarm: u8,
+    pub century: u8,
+    pub boot_architecture_flags: u16,
+    reserved2: u8,
+    pub flags: u32,
+    pub reset_register: GenericAddress,
+    pub reset_value: u8,
+    reserved3: [u8; 3],
+    pub x_firmware_control: u64,
+    pub x_dsdt: u64,
+    pub x_pm1a_event_block: GenericAddress,
+    pub x_pm1b_event_block: GenericAddress,
+    pub x_pm1a_control_block: GenericAddress,
+    pub x_pm1b_control_block: GenericAddress,
+    pub x_pm2_control_block: GenericAddress,
+    pub x_pm_timer_block: GenericAddress,
+    pub x_gpe0_block: GenericAddress,
+    pub x_gpe1_block: GenericAddress,
+}
+
+unsafe impl Table for Fadt {
+    const SIGNATURE: &'static [u8; 4] = b"FACP";
+    const MIN_LENGTH: usize = 116;
+}
+
+impl Fadt {
+    /// Returns true if the table is long enough to hold the field that ends at offset
+    pub fn has(&self, offset: usize) -> bool {
+        self.header.length as usize >= offset
+    }
+
+    /// The physical address of the DSDT
+    pub fn dsdt_address(&self) -> u64 {
+        // x_dsdt ends at byte 148
+        if self.has(148) && self.x_dsdt != 0 {
+            self.x_dsdt
+        } else {
+            self.dsdt as u64
+        }
+    }
+}
+
+/// Returns the byte that would make bytes sum to 0. Valid tables checksum to 0.
+pub fn checksum(bytes: &[u8]) -> u8 {
+    bytes
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+        .wrapping_neg()
+}
+
+fn header_at(paddr: u64) -> &'static ACPIHeader {
+    unsafe { &*(vmm::phys_to_virt(paddr) as *const ACPIHeader) }
+}
+
+/// Adds table to the registry if its checksum is good
+fn register(table: &'static ACPIHeader) {
+    table.print();
+    if !table.is_valid() {
+        warn!(
+            "ignoring ACPI table {}, bad checksum",
+            from_utf8(table.signature()).unwrap_or("?")
+        );
+        return;
+    }
+    match unsafe { TABLES.iter_mut().find(|slot| slot.is_none()) } {
+        Some(slot) => *slot = Some(table),
+        None => warn!("ignoring ACPI table, the registry is full"),
+    }
+}
+
+/// Finds and checks every table. Requires the RSDP from config::init.
+pub fn init() {
+    let rsdp = match unsafe { CONFIG.rsdp } {
+        Some(rsdp) => rsdp,
+        None => panic!("The bootloader did not pass an RSDP"),
+    };
+    rsdp.print();
+    if !rsdp.is_valid() {
+        panic!("The RSDP is corrupt");
+    }
+    let (root, entry_size) = match rsdp.xsdt_address() {
+        Some(xsdt) => (header_at(xsdt), 8),
+        None => (header_at(rsdp.rsdt_address as u64), 4),
+    };
+    if !root.is_valid() {
+        panic!("The root ACPI table is corrupt");
+    }
+    register(root);
+    let entries = &root.bytes()[core::mem::size_of::<ACPIHeader>()..];
+    for entry in entries.chunks_exact(entry_size) {
+        let mut address = [0; 8];
+        address[..entry_size].copy_from_slice(entry);
+        register(header_at(u64::from_le_bytes(address)));
+    }
+    // The DSDT is only reachable through the FADT
+    if let Some(fadt) = find::<Fadt>() {
+        register(header_at(fadt.dsdt_address()));
+    }
+    info!(
+        "Found {} ACPI tables through the {}",
+        tables().count(),
+        if entry_size == 8 { "XSDT" } else { "RSDT" }
+    );
+}
+
+/// Every table in the registry
+pub fn tables() -> impl Iterator<Item = &'static ACPIHeader> {
+    unsafe { TABLES.iter() }.filter_map(|table| *table)
+}
+
+/// The first table with signature
+pub fn find_signature(signature: &[u8; 4]) -> Option<&'static ACPIHeader> {
+    tables().find(|table| table.signature() == signature)
+}
+
+/// The table of type T, if the firmware has a good one. Requires init.
+pub fn find<T: Table>() -> Option<&'static T> {
+    let table = find_signature(T::SIGNATURE)?;
+    if (table.length() as usize) < T::MIN_LENGTH {
+        return None;
+    }
+    Some(unsafe { &*(table as *const ACPIHeader as *const T) })
+}
@@ -2,8 +2,12 @@ use core::ops::Range;
 
 use x86_64::instructions::port::Port;
 
+use crate::clock;
+use crate::config::CONFIG;
+use crate::debug;
 use crate::machine;
-use crate::println;
+use crate::smp;
+use crate::vmm;
 
 pub struct Apic {
     apic_base: usize,
@@ -17,7 +21,13 @@ impl Apic {
     const PIC2_DATA: u16 = 0xa1;
     const INIT_IPI_MSG: u32 = 0x4500;
     const STARTUP_IPI_MSG: u32 = 0x4600;
-    const PIT_FREQ: u32 = 1193182;
+    const NMI_IPI_MSG: u32 = 0x4400;
+    const ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+    /// Divide configuration for the timer: count at the bus rate
+    const APIT_DIVIDE: u32 = 0xB;
+    /// LVT bits for a LINT pin that delivers an NMI
+    const LVT_NMI: u32 = 0b100 << 8;
+    const LVT_ACTIVE_LOW: u32 = 1 << 13;
 
     /// Creates a new LAPIC at the default LAPIC address
     pub fn new() -> Self {
@@ -36,6 +46,7 @@ impl Apic {
     /// 1. Registering the Spurious Interrupt Vector with the LAPIC. By convention, this is 0xFF.
     /// 1. Disabling the PIC by masking IRQs
     /// 1. Enabling the LAPIC by writing to the appropriate MSR
+    /// 1. Making the LINT pins the MADT lists as NMI inputs deliver NMIs
     ///
     /// WARNING: Ensure that the PIC's IRQs have been remapped to >= 32.
     /// While the PIC's interrupts have been masked, spurious interrupts can still occur.
@@ -48,6 +59,27 @@ impl Apic {
         }
         Apic::disable_8259_pic();
         self.enable_apic();
+        self.configure_nmi_pins();
+    }
+
+    /// Sets up the LINT pins that the MADT's LAPIC NMI records name for this core.
+    /// Requires smp::init_bsp, so smp::me() knows the core.
+    fn configure_nmi_pins(&self) {
+        for nmi in unsafe { CONFIG.topology.nmis_for(smp::me()) } {
+            let mut lvt = Apic::LVT_NMI;
+            // Polarity is bits 0-1 of the flags, and 3 means active low
+            if nmi.flags & 0b11 == 0b11 {
+                lvt |= Apic::LVT_ACTIVE_LOW;
+            }
+            let register = match nmi.lint {
+                0 => ApicRegisterWritable::LvtLint0,
+                _ => ApicRegisterWritable::LvtLint1,
+            };
+            debug!("LINT{} delivers NMIs", nmi.lint);
+            unsafe {
+                self.write_register(register, lvt).unwrap();
+            }
+        }
     }
 
     // Disable the PIC by masking IRQs
@@ -73,7 +105,8 @@ impl Apic {
     /// Read a register from the APIC
     pub fn read_register(&self, reg: ApicRegisterReadable) -> Result<u32, ApicError> {
         let reg: ApicRegister = reg.into();
-        let register_ptr = (self.apic_base + reg.get_offset()?) as *const u32;
+        let register_ptr =
+            vmm::phys_to_virt((self.apic_base + reg.get_offset()?) as u64) as *const u32;
         Ok(unsafe { core::ptr::read_volatile(register_ptr) })
     }
 
@@ -85,7 +118,8 @@ impl Apic {
         val: u32,
     ) -> Result<(), ApicError> {
         let reg: ApicRegister = reg.into();
-        let register_ptr = (self.apic_base + reg.get_offset()?) as *mut u32;
+        let register_ptr =
+            vmm::phys_to_virt((self.apic_base + reg.get_offset()?) as u64) as *mut u32;
         Ok(core::ptr::write_volatile(register_ptr, val))
     }
 
@@ -131,47 +165,87 @@ impl Apic {
         {}
     }
 
+    /// Sends a fixed interrupt with the given vector to the core with lapic_id
+    pub fn send_ipi(&self, lapic_id: u32, vector: u8) {
+        unsafe {
+            self.write_register(ApicRegisterWritable::InterruptCommand(1), lapic_id << 24)
+                .unwrap();
+            self.write_register(ApicRegisterWritable::InterruptCommand(0), vector as u32)
+                .unwrap();
+        }
+        while (self
+            .read_register(ApicRegisterReadable::InterruptCommand(0))
+            .unwrap()
+            & (1 << 12))
+            > 0
+        {}
+    }
+
+    /// Sends a fixed interrupt with the given vector to every core but this one
+    pub fn send_ipi_all_excluding_self(&self, vector: u8) {
+        unsafe {
+            self.write_register(ApicRegisterWritable::InterruptCommand(1), 0)
+                .unwrap();
+            self.write_register(
+                ApicRegisterWritable::InterruptCommand(0),
+                Apic::ALL_EXCLUDING_SELF | vector as u32,
+            )
+            .unwrap();
+        }
+        while (self
+            .read_register(ApicRegisterReadable::InterruptCommand(0))
+            .unwrap()
+            & (1 << 12))
+            > 0
+        {}
+    }
+
+    /// Sends a non-maskable interrupt to every core but this one
+    pub fn send_nmi_all_excluding_self(&self) {
+        unsafe {
+            self.write_register(ApicRegisterWritable::InterruptCommand(1), 0)
+                .unwrap();
+            self.write_register(
+                ApicRegisterWritable::InterruptCommand(0),
+                Apic::ALL_EXCLUDING_SELF | Apic::NMI_IPI_MSG,
+            )
+            .unwrap();
+        }
+        while (self
+            .read_register(ApicRegisterReadable::InterruptCommand(0))
+            .unwrap()
+            & (1 << 12))
+            > 0
+        {}
+    }
+
     /// Retrieves the ID of the core's LAPIC.
     /// The ID is a unique, per-core identifer of the LAPIC
     pub fn id(&self) -> usize {
         (self.read_register(ApicRegisterReadable::Id).unwrap() >> 24) as usize
     }
 
+    /// Returns the initial count that makes the timer fire hz times a second, at the
+    /// divide configuration it is measured with. Requires clock::init.
     pub fn calibrate(&self, hz: u32) -> u32 {
-        let d = (Apic::PIT_FREQ / 20) as u8;
         let initial = 0xffffffff;
-        let mut port_0: Port<u8> = Port::new(0x61);
-        let mut port_1: Port<u8> = Port::new(0x43);
-        let mut port_2: Port<u8> = Port::new(0x42);
-        unsafe {
-            self.write_register(ApicRegisterWritable::ApitLvtTimer, 0x00010000).unwrap();
-            self.write_register(ApicRegisterWritable::ApitDivide, 0x00010000).unwrap();
-            self.write_register(ApicRegisterWritable::ApitInitialCount, initial).unwrap();
-            port_0.write(1);
-            port_1.write(0b10110110);
-            port_2.write(d);
-            port_2.write(d >> 8);
-        }
-        let mut last = unsafe { port_0.read() & 0x20 };
-        let mut changes = 0;
-
-        while changes < 40 {
-            let t = unsafe { port_0.read() & 0x20 };
-            if t != last {
-                changes += 1;
-                last = t;
-            }
-        }
-        let current_count = self.read_register(ApicRegisterReadable::ApitCurrentCount).unwrap();
-        println!("current count {:x}", current_count);
-        let diff = initial - current_count;
         unsafe {
-            port_0.write(0);
+            self.write_register(ApicRegisterWritable::ApitLvtTimer, 0x00010000)
+                .unwrap();
+            self.write_register(ApicRegisterWritable::ApitDivide, Apic::APIT_DIVIDE)
+                .unwrap();
+            self.write_register(ApicRegisterWritable::ApitInitialCount, initial)
+                .unwrap();
         }
-        println!("diff {:x}", diff);
-        println!("APIT running at {} hz", diff);
-        let counter = diff / hz;
-        println!("apit counter: {}", counter);
+        let apit_hz = clock::calibrate(|| {
+            let current_count = self
+                .read_register(ApicRegisterReadable::ApitCurrentCount)
+                .unwrap();
+            (initial - current_count) as u64
+        });
+        debug!("APIT running at {} hz", apit_hz);
+        let counter = (apit_hz / hz as u64) as u32;
+        debug!("apit counter: {}", counter);
         counter
     }
 }
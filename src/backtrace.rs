@@ -0,0 +1,161 @@
+use crate::config::CONFIG;
+use crate::machine;
+use crate::println;
+use crate::vmm;
+use crate::{info, warn};
+
+/*
+ * Stack backtraces, found by following the chain of saved frame pointers.
+ * The kernel is built with frame pointers, so every function starts with
+ *   push rbp; mov rbp, rsp
+ * and [rbp] holds the caller's rbp, with the return address just above it.
+ * context_switch saves rbp without replacing it, so the chain of a thread
+ * runs straight through the switches it made. Each thread starts with rbp 0,
+ * which ends its chain.
+ *
+ * Frames are named with the symbol table the Makefile ships as a multiboot
+ * module called "symbols": the output of nm -n, one "address type name" per line.
+ * Without it, backtraces show bare addresses.
+ */
+
+/// Frames printed at most, in case the chain is corrupt and loops
+const MAX_FRAMES: usize = 32;
+
+/// The offsets context_switch saves rbp and its return address at, in words
+const SWITCH_RBP: usize = 6;
+const SWITCH_RETURN: usize = 8;
+
+static mut SYMBOLS: Option<&'static str> = None;
+
+/// Finds the symbol table module. Requires config::init.
+pub fn init() {
+    let module = match unsafe { CONFIG.find_module("symbols") } {
+        Some(module) => module,
+        None => {
+            info!("no symbols module, backtraces will not be symbolized");
+            return;
+        }
+    };
+    match core::str::from_utf8(module.data()) {
+        Ok(table) => unsafe { SYMBOLS = Some(table) },
+        Err(_) => warn!("the symbols module is not text"),
+    }
+}
+
+/// Finds the function containing address in table, which is in the format of nm -n,
+/// and returns its name and the offset of address into it
+pub fn lookup(table: &'static str, address: u64) -> Option<(&'static str, u64)> {
+    let mut best = None;
+    for line in table.lines() {
+        let mut fields = line.splitn(3, ' ');
+        let start = match fields.next().map(|a| u64::from_str_radix(a, 16)) {
+            Some(Ok(start)) => start,
+            _ => continue,
+        };
+        let kind = fields.next().unwrap_or("");
+        let name = match fields.next() {
+            Some(name) => name,
+            None => continue,
+        };
+        // nm -n sorts by address, so nothing later can contain address
+        if start > address {
+            break;
+        }
+        if kind == "t" || kind == "T" || kind == "w" || kind == "W" {
+            best = Some((name, address - start));
+        }
+    }
+    best
+}
+
+/// Returns the name of the function containing address and the offset into it
+pub fn symbolize(address: u64) -> Option<(&'static str, u64)> {
+    unsafe { SYMBOLS }.and_then(|table| lookup(table, address))
+}
+
+/// Calls f with the return address of each frame on the chain that starts at rbp,
+/// innermost first
+pub fn walk<F: FnMut(u64)>(mut rbp: u64, mut f: F) {
+    for _ in 0..MAX_FRAMES {
+        // Only follow frames on mapped kernel memory, so a corrupt chain cannot fault
+        if rbp == 0 || rbp % 8 != 0 || rbp < vmm::PHYS_OFFSET || rbp > u64::MAX - 16 {
+            return;
+        }
+        if !vmm::is_mapped(rbp) || !vmm::is_mapped(rbp + 8) {
+            return;
+        }
+        let frame = rbp as *const u64;
+        let (caller_rbp, return_address) = unsafe { (*frame, *frame.add(1)) };
+        if return_address == 0 {
+            return;
+        }
+        f(return_address);
+        // Stacks grow down, so callers are always higher up
+        if caller_rbp <= rbp {
+            return;
+        }
+        rbp = caller_rbp;
+    }
+}
+
+fn print_frame(number: usize, address: u64) {
+    match symbolize(address) {
+        Some((name, offset)) => println!(
+            "  #{:<2} 0x{:016x} {}+0x{:x}",
+            number, address, name, offset
+        ),
+        None => println!("  #{:<2} 0x{:016x}", number, address),
+    }
+}
+
+/// Prints a backtrace that starts at rip, in the function whose frame rbp points at.
+/// Exception dumps pass the interrupted rip and rbp.
+pub fn print_from(rip: u64, rbp: u64) {
+    println!("Backtrace:");
+    print_frame(0, rip);
+    let mut number = 1;
+    walk(rbp, |address| {
+        print_frame(number, address);
+        number += 1;
+    });
+}
+
+/// Prints a backtrace of the caller
+#[inline(never)]
+pub fn print_current() {
+    println!("Backtrace:");
+    let mut number = 0;
+    walk(unsafe { machine::get_rbp() }, |address| {
+        print_frame(number, address);
+        number += 1;
+    });
+}
+
+/// The return address and rbp context_switch saved at stack_pointer
+fn switched_frame(stack_pointer: usize) -> Option<(u64, u64)> {
+    let start = stack_pointer as u64;
+    let end = start + (SWITCH_RETURN * 8) as u64;
+    if start < vmm::PHYS_OFFSET || !vmm::is_mapped(start) || !vmm::is_mapped(end) {
+        return None;
+    }
+    let saved = stack_pointer as *const u64;
+    Some(unsafe { (*saved.add(SWITCH_RETURN), *saved.add(SWITCH_RBP)) })
+}
+
+/// Like walk, for a thread that is switched out, given the stack pointer
+/// context_switch saved for it. The first address is where context_switch returns to.
+pub fn walk_switched<F: FnMut(u64)>(stack_pointer: usize, mut f: F) {
+    if let Some((rip, rbp)) = switched_frame(stack_pointer) {
+        f(rip);
+        walk(rbp, f);
+    }
+}
+
+/// Prints a backtrace of a thread that is switched out, given the stack pointer
+/// context_switch saved for it
+pub fn print_switched(stack_pointer: usize) {
+    match switched_frame(stack_pointer) {
+        Some((rip, rbp)) => print_from(rip, rbp),
+        None => println!("Backtrace: no stack at 0x{:x}", stack_pointer),
+    }
+}
@@ -0,0 +1,189 @@
+use crate::config::CONFIG;
+use crate::log;
+use crate::log::LogLevel;
+use crate::{info, warn};
+
+/*
+ * Options passed on the kernel command line, e.g.
+ *   multiboot2 /boot/oxos.bin smp=2 hz=250 timer=oneshot sched=priority log=debug log.pci=warn
+ *     test=semaphore
+ * Unknown options and bad values are reported and otherwise ignored,
+ * so a typo never stops the kernel from booting.
+ */
+
+/// How much pci::check_all_buses prints
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PciDump {
+    /// Skip the scan
+    Off,
+    /// One line per device
+    Summary,
+    /// Every device's configuration header
+    Full,
+}
+
+/// How the local APIC timer is programmed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimerMode {
+    /// Interrupts hz times a second, whether or not anything is due
+    Periodic,
+    /// Counts down to the next deadline, in bus cycles
+    OneShot,
+    /// Fires when the TSC reaches the next deadline
+    TscDeadline,
+}
+
+/// Which ready thread runs next, see scheduler
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SchedPolicy {
+    RoundRobin,
+    Priority,
+    FairShare,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BootOptions {
+    /// The most cores to run, including the bootstrap core. None starts every core the MADT lists.
+    pub smp: Option<u32>,
+    /// Timer interrupts per second on each core
+    pub hz: u32,
+    /// None picks TSC-deadline mode if the CPU has it, and one-shot mode otherwise
+    pub timer: Option<TimerMode>,
+    pub sched: SchedPolicy,
+    pub log: LogLevel,
+    /// Levels for single targets, set with log.<target>=<level>
+    pub log_targets: [Option<(&'static str, LogLevel)>; log::MAX_TARGETS],
+    /// The test the kernel binary runs instead of its default program
+    pub test: Option<&'static str>,
+    pub pci: PciDump,
+    /// Wait for a debugger on COM2 at the end of boot
+    pub gdb: bool,
+}
+
+static mut BOOT_OPTIONS: BootOptions = BootOptions::new();
+
+impl BootOptions {
+    pub const fn new() -> BootOptions {
+        BootOptions {
+            smp: None,
+            hz: 1000,
+            timer: None,
+            sched: SchedPolicy::RoundRobin,
+            log: LogLevel::Info,
+            log_targets: [None; log::MAX_TARGETS],
+            test: None,
+            pci: PciDump::Full,
+            gdb: false,
+        }
+    }
+
+    /// Parses space separated key=value pairs on top of the defaults
+    pub fn parse(cmdline: &'static str) -> BootOptions {
+        let mut options = BootOptions::new();
+        for option in cmdline.split_whitespace() {
+            let mut parts = option.splitn(2, '=');
+            let key = parts.next().unwrap();
+            let value = match parts.next() {
+                Some(value) => value,
+                None => {
+                    warn!("ignoring {}, expected key=value", option);
+                    continue;
+                }
+            };
+            if !options.set(key, value) {
+                warn!("ignoring {}={}", key, value);
+            }
+        }
+        options
+    }
+
+    /// Applies one option, and returns false if it is not understood
+    fn set(&mut self, key: &'static str, value: &'static str) -> bool {
+        if key.starts_with("log.") {
+            return self.set_log_target(&key[4..], value);
+        }
+        match key {
+            "smp" => match value.parse::<u32>() {
+                Ok(cores) if cores > 0 => self.smp = Some(cores),
+                _ => return false,
+            },
+            "hz" => match value.parse::<u32>() {
+                Ok(hz) if hz > 0 => self.hz = hz,
+                _ => return false,
+            },
+            "timer" => {
+                self.timer = match value {
+                    "periodic" => Some(TimerMode::Periodic),
+                    "oneshot" => Some(TimerMode::OneShot),
+                    "deadline" => Some(TimerMode::TscDeadline),
+                    _ => return false,
+                }
+            }
+            "sched" => {
+                self.sched = match value {
+                    "rr" => SchedPolicy::RoundRobin,
+                    "priority" => SchedPolicy::Priority,
+                    "fair" => SchedPolicy::FairShare,
+                    _ => return false,
+                }
+            }
+            "log" => match LogLevel::parse(value) {
+                Some(level) => self.log = level,
+                None => return false,
+            },
+            "test" => self.test = Some(value),
+            "pci" => {
+                self.pci = match value {
+                    "off" => PciDump::Off,
+                    "summary" => PciDump::Summary,
+                    "full" => PciDump::Full,
+                    _ => return false,
+                }
+            }
+            "gdb" => {
+                self.gdb = match value {
+                    "on" => true,
+                    "off" => false,
+                    _ => return false,
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// Sets the level of one target, replacing an earlier setting for it
+    fn set_log_target(&mut self, target: &'static str, value: &str) -> bool {
+        let level = match LogLevel::parse(value) {
+            Some(level) if !target.is_empty() => level,
+            _ => return false,
+        };
+        let slot = self
+            .log_targets
+            .iter()
+            .position(|entry| matches!(entry, Some((name, _)) if *name == target))
+            .or_else(|| self.log_targets.iter().position(|entry| entry.is_none()));
+        match slot {
+            Some(index) => {
+                self.log_targets[index] = Some((target, level));
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Parses the command line the bootloader passed. Requires config::init.
+pub fn init() {
+    unsafe {
+        if let Some(cmdline) = CONFIG.cmdline {
+            BOOT_OPTIONS = BootOptions::parse(cmdline);
+        }
+        info!("{:?}", BOOT_OPTIONS);
+    }
+}
+
+/// The options in effect. Before init these are the defaults.
+pub fn get() -> &'static BootOptions {
+    unsafe { &BOOT_OPTIONS }
+}
@@ -0,0 +1,106 @@
+use crate::hpet;
+use crate::machine;
+use crate::{debug, info};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/*
+ * Monotonic time, in nanoseconds since the clock started.
+ *
+ * When there is an HPET, now() reads it. Otherwise time comes from the TSC,
+ * whose rate is measured against the PIT, which is coarser. Either way init
+ * measures the TSC, for code that wants to count cycles.
+ *
+ * calibrate times other counters, such as the local APIC timer, against the
+ * same reference.
+ */
+
+pub const NANOS_PER_SEC: u64 = 1_000_000_000;
+pub const NANOS_PER_MILLI: u64 = 1_000_000;
+/// How long calibrate measures for. The PIT can count down at most 55 ms.
+const CALIBRATION_NANOS: u64 = 50_000_000;
+
+const PIT_FREQ: u64 = 1_193_182;
+const PIT_CHANNEL2: u32 = 0x42;
+const PIT_COMMAND: u32 = 0x43;
+/// Channel 2, low then high byte, mode 0: the output rises when the count reaches 0
+const PIT_CHANNEL2_ONE_SHOT: u32 = 0b10110000;
+/// Port 0x61 gates channel 2 and shows its output
+const PIT_GATE: u32 = 0x61;
+const GATE_ENABLE: u8 = 1 << 0;
+const SPEAKER_ENABLE: u8 = 1 << 1;
+const CHANNEL2_OUT: u8 = 1 << 5;
+
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+static TSC_START: AtomicU64 = AtomicU64::new(0);
+
+/// Busy waits for nanos, up to about 55 ms, by counting down PIT channel 2
+fn pit_wait(nanos: u64) {
+    let count = (nanos * PIT_FREQ / NANOS_PER_SEC) as u32;
+    unsafe {
+        let gate = machine::inb(PIT_GATE) & !(GATE_ENABLE | SPEAKER_ENABLE);
+        // The count is held while the gate is low
+        machine::outb(PIT_GATE, gate as u32);
+        machine::outb(PIT_COMMAND, PIT_CHANNEL2_ONE_SHOT);
+        machine::outb(PIT_CHANNEL2, count & 0xff);
+        machine::outb(PIT_CHANNEL2, count >> 8);
+        machine::outb(PIT_GATE, (gate | GATE_ENABLE) as u32);
+        while machine::inb(PIT_GATE) & CHANNEL2_OUT == 0 {
+            core::sync::atomic::spin_loop_hint();
+        }
+        machine::outb(PIT_GATE, gate as u32);
+    }
+}
+
+/// Returns how fast counter counts up, in ticks per second, measured against the HPET
+/// if there is one and the PIT otherwise. Requires hpet::init.
+pub fn calibrate<F: Fn() -> u64>(counter: F) -> u64 {
+    let was = machine::disable();
+    let (ticks, nanos) = match hpet::get() {
+        Some(hpet) => {
+            let start = hpet.counter();
+            let counter_start = counter();
+            while hpet.to_nanos(hpet.counter() - start) < CALIBRATION_NANOS {
+                core::sync::atomic::spin_loop_hint();
+            }
+            let ticks = counter().wrapping_sub(counter_start);
+            (ticks, hpet.to_nanos(hpet.counter() - start))
+        }
+        None => {
+            let counter_start = counter();
+            pit_wait(CALIBRATION_NANOS);
+            (counter().wrapping_sub(counter_start), CALIBRATION_NANOS)
+        }
+    };
+    machine::enable(was);
+    (ticks as u128 * NANOS_PER_SEC as u128 / nanos as u128) as u64
+}
+
+/// Measures the TSC and starts the clock. Requires hpet::init.
+pub fn init() {
+    let tsc_hz = calibrate(|| unsafe { machine::rdtsc() });
+    debug!("TSC runs at {} hz", tsc_hz);
+    TSC_HZ.store(tsc_hz, Ordering::SeqCst);
+    TSC_START.store(unsafe { machine::rdtsc() }, Ordering::SeqCst);
+    info!(
+        "the clock runs on the {}",
+        if hpet::get().is_some() { "HPET" } else { "TSC" }
+    );
+}
+
+/// TSC ticks per second, or 0 before init
+pub fn tsc_hz() -> u64 {
+    TSC_HZ.load(Ordering::SeqCst)
+}
+
+/// Nanoseconds since the clock started, or 0 before init
+pub fn now() -> u64 {
+    if let Some(hpet) = hpet::get() {
+        return hpet.to_nanos(hpet.counter());
+    }
+    let tsc_hz = tsc_hz();
+    if tsc_hz == 0 {
+        return 0;
+    }
+    let cycles = unsafe { machine::rdtsc() }.saturating_sub(TSC_START.load(Ordering::SeqCst));
+    (cycles as u128 * NANOS_PER_SEC as u128 / tsc_hz as u128) as u64
+}
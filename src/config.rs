@@ -1,4 +1,8 @@
-use crate::println;
+use crate::acpi;
+use crate::acpi::{ACPIHeader, Rsdp};
+use crate::smp;
+use crate::vmm;
+use crate::{debug, info, trace, warn};
 
 use core::str::from_utf8;
 
@@ -7,17 +11,40 @@ use core::str::from_utf8;
 // so no race conditions here
 
 pub static mut MB_MEMORY_MAP: Option<&mb_info_memory> = None;
-pub static mut RSDP: Option<&RSDP> = None;
-pub static mut RSDT: Option<&ACPIHeader> = None;
-pub static mut MADT: Option<&MADT> = None;
 pub static mut CONFIG: Config = Config::new();
 
+pub const MAX_MODULES: usize = 8;
+pub const MAX_IO_APICS: usize = 4;
+/// Overrides can only name ISA IRQs, so there are at most 16
+pub const MAX_IRQ_OVERRIDES: usize = 16;
+pub const MAX_LAPIC_NMIS: usize = 8;
+
 pub struct Config {
-    pub local_apic: u32,
+    /// Physical address of every core's local APIC
+    pub local_apic: u64,
+    /// Physical address of the I/O APIC that starts at global system interrupt 0
     pub io_apic: u32,
+    pub io_apics: [Option<IoApicInfo>; MAX_IO_APICS],
+    /// Indexed by ISA IRQ, for the IRQs that do not arrive on the global system interrupt
+    /// of the same number
+    pub irq_overrides: [Option<IrqOverride>; MAX_IRQ_OVERRIDES],
     pub num_other_procs: u32,
+    /// The number of processors in topology
     pub total_procs: u32,
+    pub topology: CpuTopology,
     pub high_phys_mem: u64,
+    pub mb_info_start: u64,
+    pub mb_info_end: u64,
+    /// Physical address of the kernel heap, chosen by place_heap
+    pub heap_start: u64,
+    /// Strings point into the multiboot information, which is never freed
+    pub cmdline: Option<&'static str>,
+    pub bootloader_name: Option<&'static str>,
+    pub modules: [Module; MAX_MODULES],
+    pub num_modules: usize,
+    pub framebuffer: Option<Framebuffer>,
+    /// The bootloader's copy of the RSDP, the newest revision it passed
+    pub rsdp: Option<&'static Rsdp>,
 }
 
 impl Config {
@@ -25,9 +52,207 @@ impl Config {
         Config {
             local_apic: 0,
             io_apic: 0,
+            io_apics: [None; MAX_IO_APICS],
+            irq_overrides: [None; MAX_IRQ_OVERRIDES],
             num_other_procs: 0,
             total_procs: 0,
+            topology: CpuTopology::new(),
             high_phys_mem: 0,
+            mb_info_start: 0,
+            mb_info_end: 0,
+            heap_start: 0,
+            cmdline: None,
+            bootloader_name: None,
+            modules: [Module {
+                start: 0,
+                end: 0,
+                cmdline: "",
+            }; MAX_MODULES],
+            num_modules: 0,
+            framebuffer: None,
+            rsdp: None,
+        }
+    }
+
+    /// The modules loaded by the bootloader, in the order grub.cfg lists them
+    pub fn modules(&self) -> &[Module] {
+        &self.modules[..self.num_modules]
+    }
+
+    /// Finds a module by the first word of its command line, e.g. "initrd" for
+    /// `module2 /boot/initrd initrd`
+    pub fn find_module(&self, name: &str) -> Option<&Module> {
+        self.modules()
+            .iter()
+            .find(|module| module.cmdline.split_whitespace().next() == Some(name))
+    }
+}
+
+/// A file loaded into memory by the bootloader
+#[derive(Clone, Copy, Debug)]
+pub struct Module {
+    /// Physical range of the contents
+    pub start: u64,
+    pub end: u64,
+    pub cmdline: &'static str,
+}
+
+impl Module {
+    pub fn data(&self) -> &'static [u8] {
+        unsafe {
+            core::slice::from_raw_parts(
+                vmm::phys_to_virt(self.start) as *const u8,
+                (self.end - self.start) as usize,
+            )
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Framebuffer {
+    /// Physical address of the pixels
+    pub addr: u64,
+    /// Bytes per row
+    pub pitch: u32,
+    pub width: u32,
+    pub height: u32,
+    pub bpp: u8,
+    /// 0 for indexed color, 1 for direct RGB, 2 for EGA text mode
+    pub fb_type: u8,
+}
+
+/// An I/O APIC listed by the MADT
+#[derive(Clone, Copy, Debug)]
+pub struct IoApicInfo {
+    pub id: u8,
+    /// Physical address of the registers
+    pub address: u32,
+    /// The global system interrupt of its first input
+    pub gsi_base: u32,
+}
+
+/// An interrupt source override from the MADT: ISA IRQ irq arrives on gsi
+#[derive(Clone, Copy, Debug)]
+pub struct IrqOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    /// MPS INTI flags. Bits 0-1 are the polarity and bits 2-3 the trigger mode.
+    pub flags: u16,
+}
+
+/// A processor the kernel can run on
+#[derive(Clone, Copy, Debug)]
+pub struct Cpu {
+    pub apic_id: u32,
+    /// The processor's ACPI UID, which LAPIC NMI records refer to it by
+    pub acpi_uid: u32,
+}
+
+/// A LAPIC NMI record: the local APIC input that a processor's NMI line is on
+#[derive(Clone, Copy, Debug)]
+pub struct LapicNmi {
+    /// ALL_PROCESSORS, or the ACPI UID of one processor
+    pub acpi_uid: u32,
+    /// MPS INTI flags, as in IrqOverride
+    pub flags: u16,
+    /// 0 for LINT0, 1 for LINT1
+    pub lint: u8,
+}
+
+impl LapicNmi {
+    pub const ALL_PROCESSORS: u32 = u32::MAX;
+
+    pub fn applies_to(&self, cpu: &Cpu) -> bool {
+        self.acpi_uid == LapicNmi::ALL_PROCESSORS || self.acpi_uid == cpu.acpi_uid
+    }
+}
+
+/*
+ * The processors from the MADT, numbered the way smp::me() numbers cores:
+ * the bootstrap core is core 0, and the rest follow in MADT order. APIC IDs
+ * need not be contiguous or start at 0, so everything per core is indexed by
+ * this logical number, and only talking to a local APIC needs the APIC ID.
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct CpuTopology {
+    cpus: [Cpu; smp::MAX_CORES],
+    num_cpus: usize,
+    /// Processors the firmware left disabled that could be brought online later
+    pub online_capable: usize,
+    /// Enabled processors left out, as their x2APIC IDs are too big for xAPIC mode
+    pub x2apic_only: usize,
+    nmis: [Option<LapicNmi>; MAX_LAPIC_NMIS],
+}
+
+impl CpuTopology {
+    pub const fn new() -> CpuTopology {
+        CpuTopology {
+            cpus: [Cpu {
+                apic_id: 0,
+                acpi_uid: 0,
+            }; smp::MAX_CORES],
+            num_cpus: 0,
+            online_capable: 0,
+            x2apic_only: 0,
+            nmis: [None; MAX_LAPIC_NMIS],
+        }
+    }
+
+    /// Indexed by logical core number
+    pub fn cpus(&self) -> &[Cpu] {
+        &self.cpus[..self.num_cpus]
+    }
+
+    pub fn len(&self) -> usize {
+        self.num_cpus
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.num_cpus == 0
+    }
+
+    pub fn apic_id(&self, core: usize) -> Option<u32> {
+        self.cpus().get(core).map(|cpu| cpu.apic_id)
+    }
+
+    /// The logical core number of the processor with apic_id
+    pub fn core_of(&self, apic_id: u32) -> Option<usize> {
+        self.cpus().iter().position(|cpu| cpu.apic_id == apic_id)
+    }
+
+    /// The LAPIC NMI records that apply to core
+    pub fn nmis_for(&self, core: usize) -> impl Iterator<Item = &LapicNmi> + '_ {
+        let cpu = self.cpus().get(core).copied();
+        self.nmis
+            .iter()
+            .filter_map(|nmi| nmi.as_ref())
+            .filter(move |nmi| cpu.map_or(false, |cpu| nmi.applies_to(&cpu)))
+    }
+
+    /// Returns false if there is no room for cpu, or its APIC ID is already listed
+    fn add(&mut self, cpu: Cpu) -> bool {
+        if self.num_cpus == smp::MAX_CORES || self.core_of(cpu.apic_id).is_some() {
+            return false;
+        }
+        self.cpus[self.num_cpus] = cpu;
+        self.num_cpus += 1;
+        true
+    }
+
+    fn add_nmi(&mut self, nmi: LapicNmi) -> bool {
+        match self.nmis.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(nmi);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Moves the processor with apic_id to core 0, keeping the others in order
+    fn make_first(&mut self, apic_id: u32) {
+        if let Some(core) = self.core_of(apic_id) {
+            self.cpus[..=core].rotate_right(1);
         }
     }
 }
@@ -49,19 +274,83 @@ impl MADTEntry {
         (self as *const MADTEntry as usize + self.record_length as usize) as *const MADTEntry
     }
     pub fn print(&self) {
-        println!(
+        debug!(
             "entry type {} record length {} ",
             self.entry_type, self.record_length
         );
     }
 }
 
+/// Flags of LAPIC and x2APIC entries
+const PROCESSOR_ENABLED: u32 = 1 << 0;
+const PROCESSOR_ONLINE_CAPABLE: u32 = 1 << 1;
+
 #[repr(C, packed)]
 struct LAPICEntry {
     entry_type: u8,
     record_length: u8,
     acpi_processor_id: u8,
-    apic_id: u32,
+    apic_id: u8,
+    flags: u32,
+}
+
+#[repr(C, packed)]
+struct X2APICEntry {
+    entry_type: u8,
+    record_length: u8,
+    reserved: u16,
+    x2apic_id: u32,
+    flags: u32,
+    acpi_uid: u32,
+}
+
+#[repr(C, packed)]
+struct LAPICNMIEntry {
+    entry_type: u8,
+    record_length: u8,
+    /// 0xFF for every processor
+    acpi_processor_id: u8,
+    flags: u16,
+    lint: u8,
+}
+
+#[repr(C, packed)]
+struct X2APICNMIEntry {
+    entry_type: u8,
+    record_length: u8,
+    flags: u16,
+    /// 0xFFFFFFFF for every processor
+    acpi_uid: u32,
+    lint: u8,
+    reserved: [u8; 3],
+}
+
+#[repr(C, packed)]
+struct LAPICAddressOverrideEntry {
+    entry_type: u8,
+    record_length: u8,
+    reserved: u16,
+    address: u64,
+}
+
+#[repr(C, packed)]
+struct IOAPICEntry {
+    entry_type: u8,
+    record_length: u8,
+    io_apic_id: u8,
+    reserved: u8,
+    io_apic_addr: u32,
+    gsi_base: u32,
+}
+
+#[repr(C, packed)]
+struct InterruptOverrideEntry {
+    entry_type: u8,
+    record_length: u8,
+    bus: u8,
+    source: u8,
+    gsi: u32,
+    flags: u16,
 }
 
 #[repr(C)]
@@ -70,6 +359,35 @@ pub struct mb_info {
     size: u32,
 }
 
+/// Tags 1 and 2, the kernel command line and the bootloader name
+#[repr(C)]
+struct mb_info_string {
+    mb_type: u32,
+    size: u32,
+}
+
+/// Tag 3
+#[repr(C)]
+struct mb_info_module {
+    mb_type: u32,
+    size: u32,
+    mod_start: u32,
+    mod_end: u32,
+}
+
+/// Tag 8, without the color information that follows it
+#[repr(C, packed)]
+struct mb_info_framebuffer {
+    mb_type: u32,
+    size: u32,
+    addr: u64,
+    pitch: u32,
+    width: u32,
+    height: u32,
+    bpp: u8,
+    fb_type: u8,
+}
+
 #[repr(C)]
 pub struct mb_info_memory {
     pub mb_type: u32,
@@ -86,40 +404,6 @@ pub struct mb_info_memory_entry {
     pub reserved: u32,
 }
 
-#[repr(C, packed)]
-pub struct ACPIHeader {
-    signature: [u8; 4],
-    length: u32,
-    revision: u8,
-    checksum: u8,
-    oemid: [u8; 6],
-    oemtableid: [u8; 8],
-    oemrevision: u32,
-    creator_id: u32,
-    creator_revision: u32,
-}
-
-impl ACPIHeader {
-    pub fn print(&self) {
-        unsafe {
-            println!("ACPIHeader: signature: {} length {} revision {} checksum {} oemid {} oemtableid {} oemrevision {} creator_id {} creator_revision {}", from_utf8(&self.signature).unwrap(), self.length, self.revision, self.checksum, from_utf8(&self.oemid).unwrap(), from_utf8(&self.oemtableid).unwrap(), self.oemrevision, self.creator_id, self.creator_revision);
-        }
-    }
-    pub fn find_sdt(&self, signature: &[u8]) -> Result<&ACPIHeader, ()> {
-        let num_entries = (self.length as usize - core::mem::size_of::<ACPIHeader>()) / 4;
-        for i in 0..num_entries {
-            let table_ptr =
-                (self as *const ACPIHeader as usize + core::mem::size_of::<ACPIHeader>()) + (i * 4);
-            let table = unsafe { &(*(*(table_ptr as usize as *const u32) as *const ACPIHeader)) };
-            if table.signature == signature {
-                return Ok(table);
-            }
-            table.print();
-        }
-        Err(())
-    }
-}
-
 #[repr(C, packed)]
 pub struct MADT {
     header: ACPIHeader,
@@ -132,39 +416,17 @@ impl MADT {
         (self as *const MADT as usize + core::mem::size_of::<MADT>()) as *const MADTEntry
     }
     pub fn length_of_entries(&self) -> usize {
-        self.header.length as usize - core::mem::size_of::<MADT>()
+        self.header.length() as usize - core::mem::size_of::<MADT>()
     }
 }
 
-#[repr(C, packed)]
-pub struct RSDP {
-    mb_type: u32,
-    size: u32,
-    signature: [u8; 8],
-    checksum: u8,
-    oemid: [u8; 6],
-    revision: u8,
-    rsdt_address: u32,
-}
-
-impl RSDP {
-    pub fn print(&self) {
-        unsafe {
-            println!(
-                "signature: {}, checksum {} oemid {} revision {} rsdt_address 0x{:x}",
-                from_utf8(&self.signature).unwrap(),
-                self.checksum,
-                from_utf8(&self.oemid).unwrap(),
-                self.revision,
-                self.rsdt_address
-            );
-        }
-    }
+unsafe impl acpi::Table for MADT {
+    const SIGNATURE: &'static [u8; 4] = b"APIC";
 }
 
 impl mb_info {
     fn print(&self) {
-        println!("type {} size {}", self.mb_type, self.size);
+        trace!("type {} size {}", self.mb_type, self.size);
     }
 
     pub fn get_next(&self) -> &mb_info {
@@ -179,19 +441,78 @@ impl mb_info {
         ((addr + 8 - 1) / 8) * 8
     }
 
+    /// Returns the NUL terminated string that starts offset bytes into this tag
+    unsafe fn string(&self, offset: usize) -> &'static str {
+        let start = (self as *const mb_info as usize + offset) as *const u8;
+        let max_length = (self.size as usize).saturating_sub(offset);
+        let bytes = core::slice::from_raw_parts(start, max_length);
+        let length = bytes.iter().position(|b| *b == 0).unwrap_or(max_length);
+        from_utf8(&bytes[..length]).unwrap_or("")
+    }
+
     pub fn find_all(&self) {
-        println!("doing find_all");
+        trace!("doing find_all");
         let mut current: &mb_info = self;
         while current.mb_type != 0 {
             current.print();
             match current.mb_type {
+                1 => unsafe {
+                    CONFIG.cmdline = Some(current.string(core::mem::size_of::<mb_info_string>()));
+                    info!("command line: {}", CONFIG.cmdline.unwrap());
+                },
+                2 => unsafe {
+                    CONFIG.bootloader_name =
+                        Some(current.string(core::mem::size_of::<mb_info_string>()));
+                    info!("bootloader: {}", CONFIG.bootloader_name.unwrap());
+                },
+                3 => unsafe {
+                    let module = &*(current as *const mb_info as *const mb_info_module);
+                    let module = Module {
+                        start: module.mod_start as u64,
+                        end: module.mod_end as u64,
+                        cmdline: current.string(core::mem::size_of::<mb_info_module>()),
+                    };
+                    info!(
+                        "module 0x{:x}-0x{:x} \"{}\"",
+                        module.start, module.end, module.cmdline
+                    );
+                    if CONFIG.num_modules < MAX_MODULES {
+                        CONFIG.modules[CONFIG.num_modules] = module;
+                        CONFIG.num_modules += 1;
+                    } else {
+                        warn!("ignoring module, only {} are supported", MAX_MODULES);
+                    }
+                },
+                8 => unsafe {
+                    let fb = &*(current as *const mb_info as *const mb_info_framebuffer);
+                    CONFIG.framebuffer = Some(Framebuffer {
+                        addr: fb.addr,
+                        pitch: fb.pitch,
+                        width: fb.width,
+                        height: fb.height,
+                        bpp: fb.bpp,
+                        fb_type: fb.fb_type,
+                    });
+                    info!(
+                        "framebuffer {}x{}x{} type {}",
+                        { fb.width },
+                        { fb.height },
+                        fb.bpp,
+                        fb.fb_type
+                    );
+                },
                 6 => unsafe {
                     MB_MEMORY_MAP = Some(&*(current as *const mb_info as *const mb_info_memory))
                 },
+                // The RSDP follows the tag header. Tag 14 holds an ACPI 1.0 copy, tag 15 an
+                // ACPI 2.0 one, which wins when both are present.
                 14 => unsafe {
-                    let rsdp_temp = &*(current as *const mb_info as *const RSDP);
-                    rsdp_temp.print();
-                    RSDP = Some(rsdp_temp);
+                    if CONFIG.rsdp.is_none() {
+                        CONFIG.rsdp = Some(&*((current as *const mb_info).add(1) as *const Rsdp));
+                    }
+                },
+                15 => unsafe {
+                    CONFIG.rsdp = Some(&*((current as *const mb_info).add(1) as *const Rsdp));
                 },
                 _ => (),
             }
@@ -202,7 +523,7 @@ impl mb_info {
 
 impl mb_info_memory {
     pub fn print(&self) {
-        println!(
+        debug!(
             "location {:x} type {} size {}, entry size {}, version {}",
             self as *const mb_info_memory as usize,
             self.mb_type,
@@ -215,7 +536,7 @@ impl mb_info_memory {
         let mut current: &mb_info_memory_entry =
             &*(((self as *const mb_info_memory as usize) + 16) as *const mb_info_memory_entry);
         let num_entries = (self.size - 16) / self.entry_size;
-        println!("Parsing {} entries in the memory map", num_entries);
+        debug!("Parsing {} entries in the memory map", num_entries);
         for i in 0..num_entries {
             current.print();
             current = current.get_next(self.entry_size as usize);
@@ -231,7 +552,7 @@ impl mb_info_memory {
 
 impl mb_info_memory_entry {
     pub fn print(&self) {
-        println!(
+        debug!(
             "Range 0x{:x}-0x{:x} length {} num pages {:x} mem_type {} reserved {}",
             self.base_addr,
             self.base_addr + self.length,
@@ -249,7 +570,7 @@ impl mb_info_memory_entry {
 }
 
 pub fn memory_map_init() {
-    println!("initializing memory map\n");
+    debug!("initializing memory map\n");
     unsafe {
         if let Some(ref memory_map) = MB_MEMORY_MAP {
             memory_map.print();
@@ -268,50 +589,18 @@ pub fn memory_map_init() {
                 }
             }
             CONFIG.high_phys_mem = end_phys_mem;
-            println!("found high mem addr {:x}", end_phys_mem);
+            debug!("found high mem addr {:x}", end_phys_mem);
         } else {
             panic!("No memory map structure!");
         }
     }
 }
 
-pub fn initialize_rsdt() {
-    println!("initialzing rsdt");
-    unsafe {
-        if let Some(ref rsdp_temp) = RSDP {
-            let rsdt_temp = &*(rsdp_temp.rsdt_address as *const ACPIHeader);
-            rsdt_temp.print();
-            unsafe {
-                RSDT = Some(rsdt_temp);
-            }
-        } else {
-            panic!("RSDT cannot be initialized");
-        }
-    }
-}
-
-pub fn initialize_madt() {
-    println!("Initializing MADT");
-    unsafe {
-        if let Some(ref rsdt_temp) = RSDT {
-            let table = rsdt_temp.find_sdt(b"APIC");
-            match table {
-                Ok(x) => unsafe {
-                    MADT = Some(&*(x as *const ACPIHeader as *const MADT));
-                },
-                Err(()) => {
-                    panic!("Failed to find MADT");
-                }
-            }
-        }
-    }
-}
-
 pub fn initialize_config() {
     unsafe {
-        if let Some(ref madt_temp) = MADT {
-            println!("lapic base 0x{:x}", madt_temp.local_apic_addr);
-            CONFIG.local_apic = madt_temp.local_apic_addr;
+        if let Some(madt_temp) = acpi::find::<MADT>() {
+            debug!("lapic base 0x{:x}", madt_temp.local_apic_addr);
+            CONFIG.local_apic = madt_temp.local_apic_addr as u64;
             let mut total = 0;
             let length = madt_temp.length_of_entries();
             let mut entry = madt_temp.first_entry();
@@ -320,22 +609,166 @@ pub fn initialize_config() {
                 entry_as_ref.print();
                 match entry_as_ref.entry_type {
                     0 => {
-                        CONFIG.total_procs += 1;
+                        let entry = &*(entry as *const LAPICEntry);
+                        add_processor(
+                            entry.apic_id as u32,
+                            entry.acpi_processor_id as u32,
+                            entry.flags,
+                        );
+                    }
+                    1 => {
+                        let entry = &*(entry as *const IOAPICEntry);
+                        let info = IoApicInfo {
+                            id: entry.io_apic_id,
+                            address: entry.io_apic_addr,
+                            gsi_base: entry.gsi_base,
+                        };
+                        debug!("{:x?}", info);
+                        if info.gsi_base == 0 {
+                            CONFIG.io_apic = info.address;
+                        }
+                        match CONFIG.io_apics.iter_mut().find(|slot| slot.is_none()) {
+                            Some(slot) => *slot = Some(info),
+                            None => warn!("ignoring I/O APIC {}, too many", info.id),
+                        }
+                    }
+                    2 => {
+                        let entry = &*(entry as *const InterruptOverrideEntry);
+                        let irq_override = IrqOverride {
+                            irq: entry.source,
+                            gsi: entry.gsi,
+                            flags: entry.flags,
+                        };
+                        debug!("{:?}", irq_override);
+                        if (irq_override.irq as usize) < MAX_IRQ_OVERRIDES {
+                            CONFIG.irq_overrides[irq_override.irq as usize] = Some(irq_override);
+                        }
+                    }
+                    4 => {
+                        let entry = &*(entry as *const LAPICNMIEntry);
+                        let uid = match entry.acpi_processor_id {
+                            0xFF => LapicNmi::ALL_PROCESSORS,
+                            uid => uid as u32,
+                        };
+                        add_lapic_nmi(uid, entry.flags, entry.lint);
+                    }
+                    5 => {
+                        let entry = &*(entry as *const LAPICAddressOverrideEntry);
+                        CONFIG.local_apic = entry.address;
+                        debug!("lapic base overridden to 0x{:x}", CONFIG.local_apic);
+                    }
+                    9 => {
+                        let entry = &*(entry as *const X2APICEntry);
+                        add_processor(entry.x2apic_id, entry.acpi_uid, entry.flags);
+                    }
+                    0xA => {
+                        let entry = &*(entry as *const X2APICNMIEntry);
+                        add_lapic_nmi(entry.acpi_uid, entry.flags, entry.lint);
                     }
                     _ => (),
                 }
                 entry = entry_as_ref.next_entry();
                 total += entry_as_ref.record_length as usize;
             }
-            println!("Found {} processors", CONFIG.total_procs);
+            // The bootstrap core is core 0, wherever the MADT lists it
+            CONFIG.topology.make_first(smp::apic_id());
+            CONFIG.total_procs = CONFIG.topology.len() as u32;
+            info!(
+                "Found {} processors, and {} more that could come online later",
+                CONFIG.total_procs, CONFIG.topology.online_capable
+            );
+            for (core, cpu) in CONFIG.topology.cpus().iter().enumerate() {
+                debug!("core {}: {:?}", core, cpu);
+            }
+            info!("I/O APIC at 0x{:x}", CONFIG.io_apic);
+        } else {
+            panic!("Failed to find MADT");
+        }
+    }
+}
+
+/// Adds a LAPIC or x2APIC entry to the topology
+unsafe fn add_processor(apic_id: u32, acpi_uid: u32, flags: u32) {
+    if flags & PROCESSOR_ENABLED == 0 {
+        if flags & PROCESSOR_ONLINE_CAPABLE != 0 {
+            CONFIG.topology.online_capable += 1;
+        }
+        return;
+    }
+    // The local APICs run in xAPIC mode, which can only address 8 bit IDs
+    if apic_id > smp::MAX_XAPIC_ID {
+        CONFIG.topology.x2apic_only += 1;
+        warn!(
+            "ignoring processor with APIC ID {}, it needs x2APIC mode",
+            apic_id
+        );
+        return;
+    }
+    let cpu = Cpu {
+        apic_id: apic_id,
+        acpi_uid: acpi_uid,
+    };
+    if !CONFIG.topology.add(cpu) && CONFIG.topology.core_of(apic_id).is_none() {
+        warn!("ignoring processor with APIC ID {}, too many", apic_id);
+    }
+}
+
+unsafe fn add_lapic_nmi(acpi_uid: u32, flags: u16, lint: u8) {
+    let nmi = LapicNmi {
+        acpi_uid: acpi_uid,
+        flags: flags,
+        lint: lint,
+    };
+    debug!("{:?}", nmi);
+    if lint > 1 || !CONFIG.topology.add_nmi(nmi) {
+        warn!("ignoring {:?}", nmi);
+    }
+}
+
+/// Picks a physical range of size bytes for the kernel heap, above the kernel image,
+/// the multiboot information and every module, and stores it in CONFIG.heap_start.
+/// The range stays below 4GB, since the heap is used before the kernel builds its own
+/// direct map. Requires init.
+pub fn place_heap(kernel_end: u64, size: u64) -> u64 {
+    let align =
+        |addr: u64| (addr + vmm::HUGE_PAGE_SIZE - 1) / vmm::HUGE_PAGE_SIZE * vmm::HUGE_PAGE_SIZE;
+    unsafe {
+        let mut floor = core::cmp::max(kernel_end, CONFIG.mb_info_end);
+        for module in CONFIG.modules() {
+            floor = core::cmp::max(floor, module.end);
+        }
+        let memory_map = match MB_MEMORY_MAP {
+            Some(memory_map) => memory_map,
+            None => panic!("No memory map structure!"),
+        };
+        let mut entry = memory_map.first_entry();
+        for i in 0..memory_map.num_entries() {
+            let start = align(core::cmp::max(entry.base_addr, floor));
+            if entry.mem_type == 1
+                && start + size <= entry.base_addr + entry.length
+                && start + size <= 0x1_0000_0000
+            {
+                info!("Kernel heap at 0x{:x}-0x{:x}", start, start + size);
+                CONFIG.heap_start = start;
+                return start;
+            }
+            if i != memory_map.num_entries() - 1 {
+                entry = entry.get_next(memory_map.entry_size as usize);
+            }
         }
     }
+    panic!("No room for the kernel heap");
 }
 
 pub fn init(mb_config: &mb_info) {
+    // The boot information starts with its total size, 8 bytes before the first tag
+    unsafe {
+        let start = mb_config as *const mb_info as u64 - 8;
+        CONFIG.mb_info_start = vmm::virt_to_phys(start);
+        CONFIG.mb_info_end = CONFIG.mb_info_start + *(start as *const u32) as u64;
+    }
     mb_config.find_all();
     memory_map_init();
-    initialize_rsdt();
-    initialize_madt();
+    acpi::init();
     initialize_config();
 }
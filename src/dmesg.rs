@@ -0,0 +1,212 @@
+use crate::log::LogLevel;
+use crate::smp;
+use crate::u8250;
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/*
+ * The kernel log kept in memory, so it can be read after the fact: by the
+ * panic handler, or by anything that wants to look at it at runtime.
+ *
+ * Each core writes only to its own ring, so recording never takes a lock.
+ * A slot is claimed by bumping the ring's head, which also keeps an interrupt
+ * that logs in the middle of another record on the same core from sharing its
+ * slot. Every slot carries a sequence number that is odd while the slot is
+ * being written, and readers throw away any copy that changed under them.
+ */
+
+/// Records kept per core. Older ones are overwritten.
+pub const RING_ENTRIES: usize = 128;
+/// Longer messages are cut short
+pub const MAX_TEXT: usize = 120;
+
+#[derive(Clone, Copy)]
+pub struct Entry {
+    /// Orders entries across every core
+    pub seq: u64,
+    pub millis: u64,
+    pub core: usize,
+    pub level: LogLevel,
+    length: usize,
+    text: [u8; MAX_TEXT],
+}
+
+impl Entry {
+    const fn new() -> Entry {
+        Entry {
+            seq: 0,
+            millis: 0,
+            core: 0,
+            level: LogLevel::Off,
+            length: 0,
+            text: [0; MAX_TEXT],
+        }
+    }
+
+    /// The target and message, as in "vmm: initialized", or what was printed
+    pub fn text(&self) -> &str {
+        // Truncation happens on a character boundary, so this cannot fail
+        core::str::from_utf8(&self.text[..self.length]).unwrap_or("<bad utf-8>")
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[{:4}.{:03}] [core {}] {:<5} {}",
+            self.millis / 1000,
+            self.millis % 1000,
+            self.core,
+            self.level.name(),
+            self.text()
+        )
+    }
+}
+
+/// Formats into an entry's text, dropping whatever does not fit
+struct TextWriter<'a> {
+    entry: &'a mut Entry,
+}
+
+impl<'a> fmt::Write for TextWriter<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            let length = c.len_utf8();
+            if self.entry.length + length > MAX_TEXT {
+                break;
+            }
+            c.encode_utf8(&mut self.entry.text[self.entry.length..]);
+            self.entry.length += length;
+        }
+        Ok(())
+    }
+}
+
+struct Slot {
+    /// 2 * index + 1 while index is being written, 2 * index + 2 once it is complete
+    version: AtomicU64,
+    entry: UnsafeCell<Entry>,
+}
+
+struct Ring {
+    /// How many entries were ever claimed on this core
+    head: AtomicUsize,
+    slots: [Slot; RING_ENTRIES],
+}
+
+unsafe impl Sync for Ring {}
+
+const EMPTY_SLOT: Slot = Slot {
+    version: AtomicU64::new(0),
+    entry: UnsafeCell::new(Entry::new()),
+};
+const EMPTY_RING: Ring = Ring {
+    head: AtomicUsize::new(0),
+    slots: [EMPTY_SLOT; RING_ENTRIES],
+};
+
+static RINGS: [Ring; smp::MAX_CORES] = [EMPTY_RING; smp::MAX_CORES];
+static NEXT_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// Adds a record to the calling core's ring
+pub fn record(millis: u64, level: LogLevel, args: fmt::Arguments) {
+    let core = smp::me();
+    let ring = match RINGS.get(core) {
+        Some(ring) => ring,
+        None => return,
+    };
+    let index = ring.head.fetch_add(1, Ordering::SeqCst);
+    let slot = &ring.slots[index % RING_ENTRIES];
+    slot.version.store(2 * index as u64 + 1, Ordering::SeqCst);
+    let entry = unsafe { &mut *slot.entry.get() };
+    entry.seq = NEXT_SEQ.fetch_add(1, Ordering::SeqCst);
+    entry.millis = millis;
+    entry.core = core;
+    entry.level = level;
+    entry.length = 0;
+    fmt::write(&mut TextWriter { entry: entry }, args).ok();
+    // println! output ends in a newline that the entry does not need
+    while entry.length > 0 && entry.text[entry.length - 1] == b'\n' {
+        entry.length -= 1;
+    }
+    slot.version.store(2 * index as u64 + 2, Ordering::SeqCst);
+}
+
+/// Copies entry index of ring, unless it was overwritten or is still being written
+fn read(ring: &Ring, index: usize) -> Option<Entry> {
+    let slot = &ring.slots[index % RING_ENTRIES];
+    let complete = 2 * index as u64 + 2;
+    if slot.version.load(Ordering::SeqCst) != complete {
+        return None;
+    }
+    let entry = unsafe { core::ptr::read_volatile(slot.entry.get()) };
+    if slot.version.load(Ordering::SeqCst) != complete {
+        return None;
+    }
+    Some(entry)
+}
+
+/// The oldest entry of ring that has not been overwritten yet
+fn oldest(ring: &Ring) -> usize {
+    ring.head
+        .load(Ordering::SeqCst)
+        .saturating_sub(RING_ENTRIES)
+}
+
+/// Calls f on every entry still held, oldest first, merging the cores by sequence
+fn merge<F: FnMut(&Entry)>(mut f: F) {
+    let mut next = [0; smp::MAX_CORES];
+    for core in 0..smp::MAX_CORES {
+        next[core] = oldest(&RINGS[core]);
+    }
+    loop {
+        let mut earliest: Option<Entry> = None;
+        for core in 0..smp::MAX_CORES {
+            let ring = &RINGS[core];
+            // Skip anything overwritten since the last pass
+            next[core] = core::cmp::max(next[core], oldest(ring));
+            while next[core] < ring.head.load(Ordering::SeqCst) {
+                match read(ring, next[core]) {
+                    Some(entry) => {
+                        if earliest.map_or(true, |e| entry.seq < e.seq) {
+                            earliest = Some(entry);
+                        }
+                        break;
+                    }
+                    None => next[core] += 1,
+                }
+            }
+        }
+        match earliest {
+            Some(entry) => {
+                next[entry.core] += 1;
+                f(&entry);
+            }
+            None => return,
+        }
+    }
+}
+
+/// Calls f on the last count entries across every core, oldest first
+pub fn for_each_recent<F: FnMut(&Entry)>(count: usize, mut f: F) {
+    let mut total = 0;
+    merge(|_| total += 1);
+    let mut skip = total.saturating_sub(count);
+    merge(|entry| {
+        if skip > 0 {
+            skip -= 1;
+        } else {
+            f(entry);
+        }
+    });
+}
+
+/// Prints the last count entries to the serial port. Nothing printed here
+/// goes back into the rings, so the dump never shows itself.
+pub fn dump(count: usize) {
+    u8250::write_fmt(format_args!("---- last {} log entries ----\n", count));
+    for_each_recent(count, |entry| u8250::write_fmt(format_args!("{}\n", entry)));
+    u8250::write_fmt(format_args!("---- end of log ----\n"));
+}
@@ -0,0 +1,319 @@
+use crate::info;
+use crate::process;
+use crate::process::Process;
+use crate::sfs::SFS;
+use crate::vmm;
+use crate::vmm::{PageFlags, VmmError, PAGE_SIZE};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELF_CLASS_64: u8 = 2;
+const ELF_DATA_LITTLE_ENDIAN: u8 = 1;
+const ELF_VERSION_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 0x3e;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// The image is smaller than the headers it claims to have
+    Truncated,
+    BadMagic,
+    /// Not a 64 bit, little endian, version 1 ELF file
+    UnsupportedFormat,
+    /// Not a static executable
+    NotExecutable,
+    WrongMachine,
+    BadProgramHeader,
+    /// A segment reaches outside the image or outside the user half
+    BadSegment,
+    NoLoadableSegments,
+    /// argv, envp and auxv do not fit on the initial stack
+    ArgumentsTooLarge,
+    /// The file could not be read from the file system
+    NotFound,
+    /// No frames are left for the segments
+    OutOfMemory,
+    Map(VmmError),
+}
+
+impl From<VmmError> for ElfError {
+    fn from(error: VmmError) -> ElfError {
+        match error {
+            VmmError::OutOfMemory => ElfError::OutOfMemory,
+            error => ElfError::Map(error),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ElfHeader {
+    ident: [u8; 16],
+    file_type: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ProgramHeader {
+    segment_type: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+/// A validated ELF executable
+pub struct Elf<'a> {
+    image: &'a [u8],
+    header: ElfHeader,
+    segments: Vec<ProgramHeader>,
+}
+
+/// Reads a T from image at offset, which need not be aligned
+fn read<T: Copy>(image: &[u8], offset: u64) -> Result<T, ElfError> {
+    let end = offset
+        .checked_add(core::mem::size_of::<T>() as u64)
+        .ok_or(ElfError::Truncated)?;
+    if end > image.len() as u64 {
+        return Err(ElfError::Truncated);
+    }
+    Ok(unsafe { core::ptr::read_unaligned(image[offset as usize..].as_ptr() as *const T) })
+}
+
+/// The flags of a user page with the given permissions
+fn page_flags(writable: bool, executable: bool) -> PageFlags {
+    let mut flags = PageFlags::USER;
+    if writable {
+        flags = flags | PageFlags::WRITABLE;
+    }
+    if !executable {
+        flags = flags | PageFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+impl<'a> Elf<'a> {
+    /// Checks the headers of image, without loading anything
+    pub fn parse(image: &'a [u8]) -> Result<Elf<'a>, ElfError> {
+        let header: ElfHeader = read(image, 0)?;
+        if header.ident[0..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if header.ident[4] != ELF_CLASS_64
+            || header.ident[5] != ELF_DATA_LITTLE_ENDIAN
+            || header.ident[6] != ELF_VERSION_CURRENT
+        {
+            return Err(ElfError::UnsupportedFormat);
+        }
+        if header.file_type != ET_EXEC {
+            return Err(ElfError::NotExecutable);
+        }
+        if header.machine != EM_X86_64 {
+            return Err(ElfError::WrongMachine);
+        }
+        if header.phentsize as usize != core::mem::size_of::<ProgramHeader>() {
+            return Err(ElfError::BadProgramHeader);
+        }
+        let mut segments = Vec::new();
+        for i in 0..header.phnum as u64 {
+            let offset = header
+                .phoff
+                .checked_add(i * header.phentsize as u64)
+                .ok_or(ElfError::Truncated)?;
+            let segment: ProgramHeader = read(image, offset)?;
+            if segment.segment_type != PT_LOAD {
+                continue;
+            }
+            let file_end = segment.offset.checked_add(segment.filesz);
+            let memory_end = segment.vaddr.checked_add(segment.memsz);
+            match (file_end, memory_end) {
+                (Some(file_end), Some(memory_end))
+                    if file_end <= image.len() as u64
+                        && memory_end <= vmm::USER_END
+                        && segment.filesz <= segment.memsz => {}
+                _ => return Err(ElfError::BadSegment),
+            }
+            // Loadable segments are sorted by address, as the ELF format requires
+            if let Some(previous) = segments.last() {
+                if segment.vaddr < previous.vaddr + previous.memsz {
+                    return Err(ElfError::BadSegment);
+                }
+            }
+            segments.push(segment);
+        }
+        if segments.is_empty() {
+            return Err(ElfError::NoLoadableSegments);
+        }
+        if !segments
+            .iter()
+            .any(|s| header.entry >= s.vaddr && header.entry < s.vaddr + s.memsz)
+        {
+            return Err(ElfError::BadSegment);
+        }
+        Ok(Elf {
+            image: image,
+            header: header,
+            segments: segments,
+        })
+    }
+
+    pub fn entry(&self) -> u64 {
+        self.header.entry
+    }
+
+    /// Where the program headers end up in memory, if a segment loads them
+    fn program_headers_address(&self) -> Option<u64> {
+        let phoff = self.header.phoff;
+        self.segments
+            .iter()
+            .find(|s| phoff >= s.offset && phoff < s.offset + s.filesz)
+            .map(|s| s.vaddr + (phoff - s.offset))
+    }
+
+    /// Maps every PT_LOAD segment into process. Segments are sorted and do not overlap,
+    /// so a page can only be shared by the end of one segment and the start of the next,
+    /// and it gets the union of their permissions.
+    pub fn load(&self, process: &Process) -> Result<(), ElfError> {
+        // The last page mapped, and whether it is writable and executable
+        let mut last_mapped: Option<(u64, bool, bool)> = None;
+        for segment in self.segments.iter() {
+            let mut first = segment.vaddr / PAGE_SIZE;
+            let last = (segment.vaddr + segment.memsz + PAGE_SIZE - 1) / PAGE_SIZE;
+            let writable = segment.flags & PF_W != 0;
+            let executable = segment.flags & PF_X != 0;
+            if first == last {
+                continue;
+            }
+            if let Some((page, page_writable, page_executable)) = last_mapped {
+                if page == first {
+                    let writable = writable || page_writable;
+                    let executable = executable || page_executable;
+                    process.protect(page * PAGE_SIZE, page_flags(writable, executable))?;
+                    last_mapped = Some((page, writable, executable));
+                    first += 1;
+                }
+            }
+            if first < last {
+                let flags = page_flags(writable, executable);
+                process.map_zeroed(first * PAGE_SIZE, last - first, flags)?;
+                last_mapped = Some((last - 1, writable, executable));
+            }
+        }
+        for segment in self.segments.iter() {
+            let start = segment.offset as usize;
+            let end = (segment.offset + segment.filesz) as usize;
+            process.copy_to_user(segment.vaddr, &self.image[start..end])?;
+        }
+        Ok(())
+    }
+
+    /// Lays out argc, argv, envp and auxv at the top of the initial stack, as the
+    /// System V ABI expects them at process entry, and returns the stack pointer
+    fn build_stack(
+        &self,
+        process: &Process,
+        stack_top: u64,
+        argv: &[&str],
+        envp: &[&str],
+    ) -> Result<u64, ElfError> {
+        let stack_size = process::USER_STACK_PAGES * PAGE_SIZE;
+        // The strings go at the very top
+        let mut strings: Vec<u8> = Vec::new();
+        let mut offsets: Vec<u64> = Vec::new();
+        for string in argv.iter().chain(envp.iter()) {
+            offsets.push(strings.len() as u64);
+            strings.extend_from_slice(string.as_bytes());
+            strings.push(0);
+        }
+        let strings_start = stack_top - strings.len() as u64;
+
+        let mut auxv: Vec<(u64, u64)> = Vec::new();
+        if let Some(phdr) = self.program_headers_address() {
+            auxv.push((AT_PHDR, phdr));
+        }
+        auxv.push((AT_PHENT, self.header.phentsize as u64));
+        auxv.push((AT_PHNUM, self.header.phnum as u64));
+        auxv.push((AT_PAGESZ, PAGE_SIZE));
+        auxv.push((AT_ENTRY, self.header.entry));
+        auxv.push((AT_NULL, 0));
+
+        let mut words: Vec<u64> = Vec::new();
+        words.push(argv.len() as u64);
+        for i in 0..argv.len() {
+            words.push(strings_start + offsets[i]);
+        }
+        words.push(0);
+        for i in argv.len()..offsets.len() {
+            words.push(strings_start + offsets[i]);
+        }
+        words.push(0);
+        for (key, value) in auxv.iter() {
+            words.push(*key);
+            words.push(*value);
+        }
+
+        // The stack pointer must be 16 byte aligned at entry, pointing at argc
+        let rsp = (strings_start - words.len() as u64 * 8) & !0xf;
+        if stack_top - rsp > stack_size {
+            return Err(ElfError::ArgumentsTooLarge);
+        }
+        let words_bytes =
+            unsafe { core::slice::from_raw_parts(words.as_ptr() as *const u8, words.len() * 8) };
+        process.copy_to_user(rsp, words_bytes)?;
+        process.copy_to_user(strings_start, &strings)?;
+        Ok(rsp)
+    }
+}
+
+/// Starts the executable in image as a new process.
+/// image can come from anywhere, such as a file system or a multiboot module.
+pub fn spawn(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<Arc<Process>, ElfError> {
+    let elf = Elf::parse(image)?;
+    let process = Process::new();
+    elf.load(&process)?;
+    let stack_top = process.map_stack()?;
+    let rsp = elf.build_stack(&process, stack_top, argv, envp)?;
+    info!("Starting process {} at 0x{:x}", process.pid(), elf.entry());
+    process.spawn(elf.entry(), rsp);
+    Ok(process)
+}
+
+/// Starts the executable stored as filename on an SFS volume
+pub fn spawn_from_sfs(
+    fs: &mut SFS,
+    filename: &str,
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<Arc<Process>, ElfError> {
+    let image = fs
+        .read_file_bytes(filename)
+        .map_err(|_| ElfError::NotFound)?;
+    spawn(&image, argv, envp)
+}
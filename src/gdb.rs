@@ -0,0 +1,688 @@
+use crate::apic::Apic;
+use crate::boot_options;
+use crate::config::CONFIG;
+use crate::idt::TrapFrame;
+use crate::info;
+use crate::machine;
+use crate::smp;
+use crate::u8250;
+use crate::u8250::U8250;
+use crate::vmm;
+use core::fmt;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+
+/*
+ * A GDB remote serial protocol stub on COM2, turned on with gdb=on.
+ * At the end of boot the kernel stops at a breakpoint and waits for
+ *   (gdb) target remote <COM2>
+ * See `make gdb` for running it under QEMU.
+ *
+ * The stub runs inside the debug and breakpoint exception handlers. The core
+ * that takes the exception owns the stub, and stops every other core with an
+ * NMI, so gdb sees each core as a thread (core n is thread n + 1) with the
+ * registers it was interrupted with. Breakpoints are int3 bytes patched into
+ * the code, and single steps use the trap flag.
+ * Interrupting a running kernel with Ctrl-C is not supported.
+ */
+
+const PACKET_SIZE: usize = 0x1000;
+const MAX_BREAKPOINTS: usize = 32;
+const INT3: u8 = 0xcc;
+const TRAP_FLAG: u64 = 1 << 8;
+const DEBUG_VECTOR: u64 = 1;
+const BREAKPOINT_VECTOR: u64 = 3;
+const SIGTRAP: u8 = 5;
+/// The number of general purpose registers in a g packet, including rip
+const GP_REGISTERS: usize = 17;
+/// How long the owner waits for the other cores to stop, in spins
+const STOP_TIMEOUT: usize = 10_000_000;
+const NO_CORE: usize = usize::MAX;
+
+static PORT: U8250 = U8250::new(u8250::COM2);
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// The core that is talking to gdb
+static OWNER: AtomicUsize = AtomicUsize::new(NO_CORE);
+/// Bumped every time gdb lets the machine run again
+static RESUMES: AtomicU64 = AtomicU64::new(0);
+/// NMIs sent by the owner that have not arrived yet
+static PENDING_NMIS: AtomicUsize = AtomicUsize::new(0);
+const NO_FRAME: AtomicPtr<TrapFrame> = AtomicPtr::new(core::ptr::null_mut());
+/// The registers of every stopped core
+static FRAMES: [AtomicPtr<TrapFrame>; smp::MAX_CORES] = [NO_FRAME; smp::MAX_CORES];
+
+/// Where the stub talks to gdb. Tests can script a conversation with their own.
+pub trait Link {
+    /// Waits for the next byte from gdb
+    fn get(&mut self) -> u8;
+    fn put(&mut self, byte: u8);
+}
+
+/// COM2, set up by init
+struct Serial;
+
+impl Link for Serial {
+    fn get(&mut self) -> u8 {
+        PORT.get()
+    }
+
+    fn put(&mut self, byte: u8) {
+        PORT.put(byte);
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    address: u64,
+    original: u8,
+}
+
+/// A packet being built, cut short if it outgrows PACKET_SIZE
+struct Reply {
+    data: [u8; PACKET_SIZE],
+    length: usize,
+}
+
+impl Reply {
+    fn clear(&mut self) {
+        self.length = 0;
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.length < PACKET_SIZE {
+            self.data[self.length] = byte;
+            self.length += 1;
+        }
+    }
+
+    fn push_hex(&mut self, byte: u8) {
+        self.push(hex_digit(byte >> 4));
+        self.push(hex_digit(byte & 0xf));
+    }
+
+    /// Appends the low bytes of value in target (little endian) order
+    fn push_le(&mut self, value: u64, bytes: usize) {
+        for i in 0..bytes {
+            self.push_hex((value >> (8 * i)) as u8);
+        }
+    }
+}
+
+impl fmt::Write for Reply {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.push(byte);
+        }
+        Ok(())
+    }
+}
+
+/// State that only the owner touches
+struct Stub {
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+    /// The core whose registers g and G access
+    register_core: usize,
+    /// Set while gdb waits for the machine to stop
+    running: bool,
+    reply: Reply,
+}
+
+static mut STUB: Stub = Stub {
+    breakpoints: [None; MAX_BREAKPOINTS],
+    register_core: 0,
+    running: false,
+    reply: Reply {
+        data: [0; PACKET_SIZE],
+        length: 0,
+    },
+};
+/// The last packet received. Kept apart from STUB so handlers can read it while replying.
+static mut PACKET: [u8; PACKET_SIZE] = [0; PACKET_SIZE];
+
+fn hex_digit(value: u8) -> u8 {
+    b"0123456789abcdef"[value as usize & 0xf]
+}
+
+fn hex_value(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Parses a whole field of hex digits
+fn parse_hex(field: &[u8]) -> Option<u64> {
+    if field.is_empty() || field.len() > 16 {
+        return None;
+    }
+    let mut value = 0;
+    for digit in field {
+        value = (value << 4) | hex_value(*digit)? as u64;
+    }
+    Some(value)
+}
+
+/// Parses count little endian bytes of hex starting at field
+fn parse_le(field: &[u8], bytes: usize) -> Option<u64> {
+    let mut value = 0;
+    for i in 0..bytes {
+        let high = hex_value(*field.get(2 * i)?)?;
+        let low = hex_value(*field.get(2 * i + 1)?)?;
+        value |= (((high << 4) | low) as u64) << (8 * i);
+    }
+    Some(value)
+}
+
+/// The checksum that ends a packet
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum: u8, byte| sum.wrapping_add(*byte))
+}
+
+/// Splits "addr,length" into its two numbers
+fn parse_range(args: &[u8]) -> Option<(u64, u64)> {
+    let comma = args.iter().position(|b| *b == b',')?;
+    Some((parse_hex(&args[..comma])?, parse_hex(&args[comma + 1..])?))
+}
+
+/// Turns on the stub if the command line asked for it, and waits for gdb to attach.
+/// Call once every core is up.
+pub fn init() {
+    if !boot_options::get().gdb {
+        return;
+    }
+    PORT.init(u8250::BAUD);
+    ENABLED.store(true, Ordering::SeqCst);
+    info!("waiting for gdb on COM2");
+    unsafe {
+        machine::breakpoint();
+    }
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// Reads a byte of kernel memory, if it is mapped
+pub fn read_byte(address: u64) -> Option<u8> {
+    let paddr = vmm::translate(address)?;
+    Some(unsafe { *(vmm::phys_to_virt(paddr) as *const u8) })
+}
+
+/// Writes a byte of kernel memory through the direct map, so read only code can be patched
+pub fn write_byte(address: u64, value: u8) -> bool {
+    match vmm::translate(address) {
+        Some(paddr) => {
+            unsafe { *(vmm::phys_to_virt(paddr) as *mut u8) = value };
+            true
+        }
+        None => false,
+    }
+}
+
+/// Patches an int3 in at address. Only for use while gdb is not attached, as by tests.
+pub fn insert_breakpoint(address: u64) -> bool {
+    unsafe { STUB.insert_breakpoint(address) }
+}
+
+/// Restores the code under the breakpoint at address
+pub fn remove_breakpoint(address: u64) -> bool {
+    unsafe { STUB.remove_breakpoint(address) }
+}
+
+/// Answers the packets link delivers as if core me had stopped with frame, until gdb
+/// continues, steps or detaches. Returns true unless it detached.
+/// Only for use while gdb is not attached, as by tests.
+pub fn serve(link: &mut dyn Link, me: usize, frame: &mut TrapFrame) -> bool {
+    FRAMES[me].store(frame as *mut TrapFrame, Ordering::SeqCst);
+    let stub = unsafe { &mut STUB };
+    stub.register_core = me;
+    stub.serve(link, me);
+    let resumed = stub.running;
+    stub.running = false;
+    FRAMES[me].store(core::ptr::null_mut(), Ordering::SeqCst);
+    resumed
+}
+
+/// Called by exception_handler for debug and breakpoint exceptions taken in the kernel
+/// while the stub is enabled. Returns once gdb lets the core continue.
+pub fn handle_exception(frame: &mut TrapFrame) {
+    let me = smp::me();
+    if OWNER
+        .compare_exchange(NO_CORE, me, Ordering::SeqCst, Ordering::SeqCst)
+        .is_err()
+    {
+        // Another core is talking to gdb. Run the int3 again once it is done,
+        // so gdb hears about this breakpoint too.
+        if frame.vector == BREAKPOINT_VECTOR {
+            frame.rip -= 1;
+        }
+        wait_while_stopped(me, frame);
+        return;
+    }
+    FRAMES[me].store(frame as *mut TrapFrame, Ordering::SeqCst);
+    stop_others(me);
+
+    let stub = unsafe { &mut STUB };
+    let mut reason = "";
+    if frame.vector == BREAKPOINT_VECTOR && stub.breakpoint_index(frame.rip - 1).is_some() {
+        frame.rip -= 1;
+        reason = "swbreak:;";
+    }
+    if frame.vector == DEBUG_VECTOR {
+        frame.rflags &= !TRAP_FLAG;
+    }
+    stub.register_core = me;
+    if stub.running {
+        stub.running = false;
+        stub.stop_reply(me, reason);
+        stub.send_reply(&mut Serial);
+    }
+    stub.serve(&mut Serial, me);
+
+    RESUMES.fetch_add(1, Ordering::SeqCst);
+    for core in 0..smp::MAX_CORES {
+        if core != me {
+            wait_for(|| FRAMES[core].load(Ordering::SeqCst).is_null());
+        }
+    }
+    FRAMES[me].store(core::ptr::null_mut(), Ordering::SeqCst);
+    OWNER.store(NO_CORE, Ordering::SeqCst);
+}
+
+/// Called by exception_handler for every NMI. Returns false if the stub did not send it.
+pub fn handle_nmi(frame: &mut TrapFrame) -> bool {
+    let mut pending = PENDING_NMIS.load(Ordering::SeqCst);
+    loop {
+        if pending == 0 {
+            return false;
+        }
+        match PENDING_NMIS.compare_exchange(
+            pending,
+            pending - 1,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        ) {
+            Ok(_) => break,
+            Err(current) => pending = current,
+        }
+    }
+    wait_while_stopped(smp::me(), frame);
+    true
+}
+
+/// Publishes frame for gdb and spins until the owner resumes the machine
+fn wait_while_stopped(me: usize, frame: &mut TrapFrame) {
+    let resumes = RESUMES.load(Ordering::SeqCst);
+    if OWNER.load(Ordering::SeqCst) == NO_CORE {
+        return;
+    }
+    FRAMES[me].store(frame as *mut TrapFrame, Ordering::SeqCst);
+    while RESUMES.load(Ordering::SeqCst) == resumes {
+        core::sync::atomic::spin_loop_hint();
+    }
+    FRAMES[me].store(core::ptr::null_mut(), Ordering::SeqCst);
+}
+
+/// Spins until condition holds, or gives up after STOP_TIMEOUT spins
+fn wait_for<F: Fn() -> bool>(condition: F) {
+    for _ in 0..STOP_TIMEOUT {
+        if condition() {
+            return;
+        }
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+/// Sends every other online core an NMI, and waits for them to publish their registers
+fn stop_others(me: usize) {
+    let others = (0..smp::MAX_CORES)
+        .filter(|core| *core != me && smp::is_online(*core))
+        .count();
+    if others == 0 {
+        return;
+    }
+    PENDING_NMIS.fetch_add(others, Ordering::SeqCst);
+    Apic::with_base(unsafe { CONFIG.local_apic as usize }).send_nmi_all_excluding_self();
+    for core in 0..smp::MAX_CORES {
+        if core != me && smp::is_online(core) {
+            wait_for(|| !FRAMES[core].load(Ordering::SeqCst).is_null());
+        }
+    }
+}
+
+fn frame_of(core: usize) -> Option<&'static mut TrapFrame> {
+    let frame = FRAMES.get(core)?.load(Ordering::SeqCst);
+    if frame.is_null() {
+        None
+    } else {
+        Some(unsafe { &mut *frame })
+    }
+}
+
+/// Thread ids as gdb sees them: core n is thread n + 1, as 0 means any thread
+fn thread_to_core(args: &[u8]) -> Option<usize> {
+    match parse_hex(args)? {
+        0 => None,
+        thread => Some(thread as usize - 1),
+    }
+}
+
+impl Stub {
+    fn breakpoint_index(&self, address: u64) -> Option<usize> {
+        self.breakpoints
+            .iter()
+            .position(|b| matches!(b, Some(b) if b.address == address))
+    }
+
+    fn insert_breakpoint(&mut self, address: u64) -> bool {
+        if self.breakpoint_index(address).is_some() {
+            return true;
+        }
+        let slot = match self.breakpoints.iter().position(|b| b.is_none()) {
+            Some(slot) => slot,
+            None => return false,
+        };
+        let original = match read_byte(address) {
+            Some(original) => original,
+            None => return false,
+        };
+        write_byte(address, INT3);
+        self.breakpoints[slot] = Some(Breakpoint {
+            address: address,
+            original: original,
+        });
+        true
+    }
+
+    fn remove_breakpoint(&mut self, address: u64) -> bool {
+        let index = match self.breakpoint_index(address) {
+            Some(index) => index,
+            None => return false,
+        };
+        if let Some(breakpoint) = self.breakpoints[index].take() {
+            write_byte(breakpoint.address, breakpoint.original);
+        }
+        true
+    }
+
+    /// Waits for the next packet with a good checksum
+    fn receive(&mut self, link: &mut dyn Link) -> &'static [u8] {
+        let packet = unsafe { &mut PACKET };
+        'packet: loop {
+            while link.get() != b'$' {}
+            let mut length = 0;
+            let mut sum: u8 = 0;
+            loop {
+                let byte = link.get();
+                match byte {
+                    b'#' => break,
+                    b'$' => continue 'packet,
+                    _ => {
+                        if length < PACKET_SIZE {
+                            packet[length] = byte;
+                            length += 1;
+                        }
+                        sum = sum.wrapping_add(byte);
+                    }
+                }
+            }
+            let high = hex_value(link.get());
+            let low = hex_value(link.get());
+            match (high, low) {
+                (Some(high), Some(low)) if (high << 4 | low) == sum => {
+                    link.put(b'+');
+                    return &packet[..length];
+                }
+                _ => link.put(b'-'),
+            }
+        }
+    }
+
+    /// Sends self.reply, until gdb acknowledges it
+    fn send_reply(&mut self, link: &mut dyn Link) {
+        let data = &self.reply.data[..self.reply.length];
+        let sum = checksum(data);
+        loop {
+            link.put(b'$');
+            for byte in data {
+                link.put(*byte);
+            }
+            link.put(b'#');
+            link.put(hex_digit(sum >> 4));
+            link.put(hex_digit(sum & 0xf));
+            loop {
+                match link.get() {
+                    b'+' => return,
+                    b'-' => break,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn stop_reply(&mut self, me: usize, reason: &str) {
+        self.reply.clear();
+        write!(self.reply, "T{:02x}thread:{:x};{}", SIGTRAP, me + 1, reason).ok();
+    }
+
+    /// Answers packets until gdb continues or steps the machine
+    fn serve(&mut self, link: &mut dyn Link, me: usize) {
+        loop {
+            let packet = self.receive(link);
+            self.reply.clear();
+            let (command, args) = match packet.split_first() {
+                Some((command, args)) => (*command, args),
+                None => (0, packet),
+            };
+            match command {
+                b'?' => self.stop_reply(me, ""),
+                b'g' => self.read_registers(),
+                b'G' => self.write_registers(args),
+                b'm' => self.read_memory(args),
+                b'M' => self.write_memory(args),
+                b'Z' | b'z' => self.breakpoint(command == b'Z', args),
+                b'H' => self.set_thread(args),
+                b'T' => match thread_to_core(args).and_then(frame_of) {
+                    Some(_) => self.reply.write_str("OK").unwrap(),
+                    None => self.reply.write_str("E01").unwrap(),
+                },
+                b'q' => self.query(me, args),
+                b'c' | b's' => {
+                    if let (Some(address), Some(frame)) = (parse_hex(args), frame_of(me)) {
+                        frame.rip = address;
+                    }
+                    if command == b's' {
+                        if let Some(frame) = frame_of(me) {
+                            frame.rflags |= TRAP_FLAG;
+                        }
+                    }
+                    self.running = true;
+                    return;
+                }
+                b'D' => {
+                    for i in 0..MAX_BREAKPOINTS {
+                        if let Some(breakpoint) = self.breakpoints[i] {
+                            self.remove_breakpoint(breakpoint.address);
+                        }
+                    }
+                    self.reply.write_str("OK").unwrap();
+                    self.send_reply(link);
+                    return;
+                }
+                // Killed from the debugger, so whatever was running did not finish
+                b'k' => machine::exit(machine::EXIT_QEMU_FAILURE),
+                // Anything else is unsupported, which an empty reply tells gdb
+                _ => {}
+            }
+            self.send_reply(link);
+        }
+    }
+
+    fn read_registers(&mut self) {
+        let frame = match frame_of(self.register_core) {
+            Some(frame) => frame,
+            None => return self.reply.write_str("E01").unwrap(),
+        };
+        let registers = [
+            frame.rax, frame.rbx, frame.rcx, frame.rdx, frame.rsi, frame.rdi, frame.rbp, frame.rsp,
+            frame.r8, frame.r9, frame.r10, frame.r11, frame.r12, frame.r13, frame.r14, frame.r15,
+            frame.rip,
+        ];
+        for register in registers.iter() {
+            self.reply.push_le(*register, 8);
+        }
+        // eflags, cs, ss, ds, es, fs and gs are 32 bits each
+        let segments = [frame.rflags, frame.cs, frame.ss, frame.ss, frame.ss, 0, 0];
+        for segment in segments.iter() {
+            self.reply.push_le(*segment, 4);
+        }
+    }
+
+    fn write_registers(&mut self, args: &[u8]) {
+        let frame = match frame_of(self.register_core) {
+            Some(frame) => frame,
+            None => return self.reply.write_str("E01").unwrap(),
+        };
+        let mut values = [0; GP_REGISTERS];
+        for i in 0..GP_REGISTERS {
+            match parse_le(&args[(16 * i).min(args.len())..], 8) {
+                Some(value) => values[i] = value,
+                None => return self.reply.write_str("E22").unwrap(),
+            }
+        }
+        let registers = [
+            &mut frame.rax,
+            &mut frame.rbx,
+            &mut frame.rcx,
+            &mut frame.rdx,
+            &mut frame.rsi,
+            &mut frame.rdi,
+            &mut frame.rbp,
+            &mut frame.rsp,
+            &mut frame.r8,
+            &mut frame.r9,
+            &mut frame.r10,
+            &mut frame.r11,
+            &mut frame.r12,
+            &mut frame.r13,
+            &mut frame.r14,
+            &mut frame.r15,
+            &mut frame.rip,
+        ];
+        for (register, value) in registers.iter_mut().zip(values.iter()) {
+            **register = *value;
+        }
+        // Only the low 32 bits of rflags are in the packet
+        if let Some(eflags) = parse_le(&args[(16 * GP_REGISTERS).min(args.len())..], 4) {
+            frame.rflags = (frame.rflags & !0xffff_ffff) | eflags;
+        }
+        self.reply.write_str("OK").unwrap();
+    }
+
+    fn read_memory(&mut self, args: &[u8]) {
+        let (address, length) = match parse_range(args) {
+            Some(range) => range,
+            None => return self.reply.write_str("E22").unwrap(),
+        };
+        let length = core::cmp::min(length, (PACKET_SIZE / 2) as u64);
+        for i in 0..length {
+            match read_byte(address.wrapping_add(i)) {
+                Some(byte) => self.reply.push_hex(byte),
+                // A short read is fine, but not an empty one
+                None if i == 0 => return self.reply.write_str("E14").unwrap(),
+                None => return,
+            }
+        }
+    }
+
+    fn write_memory(&mut self, args: &[u8]) {
+        let colon = match args.iter().position(|b| *b == b':') {
+            Some(colon) => colon,
+            None => return self.reply.write_str("E22").unwrap(),
+        };
+        let (address, length) = match parse_range(&args[..colon]) {
+            Some(range) => range,
+            None => return self.reply.write_str("E22").unwrap(),
+        };
+        let data = &args[colon + 1..];
+        for i in 0..length as usize {
+            let byte = match parse_le(&data[(2 * i).min(data.len())..], 1) {
+                Some(byte) => byte as u8,
+                None => return self.reply.write_str("E22").unwrap(),
+            };
+            if !write_byte(address.wrapping_add(i as u64), byte) {
+                return self.reply.write_str("E14").unwrap();
+            }
+        }
+        self.reply.write_str("OK").unwrap();
+    }
+
+    /// Z0,addr,kind and z0,addr,kind. Other kinds of breakpoint are unsupported.
+    fn breakpoint(&mut self, insert: bool, args: &[u8]) {
+        if args.len() < 2 || args[0] != b'0' || args[1] != b',' {
+            return;
+        }
+        let (address, _kind) = match parse_range(&args[2..]) {
+            Some(range) => range,
+            None => return self.reply.write_str("E22").unwrap(),
+        };
+        let done = if insert {
+            self.insert_breakpoint(address)
+        } else {
+            self.remove_breakpoint(address)
+        };
+        let reply = if done { "OK" } else { "E14" };
+        self.reply.write_str(reply).unwrap();
+    }
+
+    /// Hg picks the core g and G access. Hc is accepted, but only the owner ever steps.
+    fn set_thread(&mut self, args: &[u8]) {
+        if args.first() == Some(&b'g') {
+            // -1 and 0 mean every thread and any thread, which leaves the choice alone
+            if let Some(core) = thread_to_core(&args[1..]) {
+                if frame_of(core).is_none() {
+                    return self.reply.write_str("E01").unwrap();
+                }
+                self.register_core = core;
+            }
+        }
+        self.reply.write_str("OK").unwrap();
+    }
+
+    fn query(&mut self, me: usize, args: &[u8]) {
+        if args.starts_with(b"Supported") {
+            write!(self.reply, "PacketSize={:x};swbreak+", PACKET_SIZE).ok();
+        } else if args == b"C" {
+            write!(self.reply, "QC{:x}", me + 1).ok();
+        } else if args == b"Attached" {
+            self.reply.write_str("1").unwrap();
+        } else if args == b"fThreadInfo" {
+            self.reply.push(b'm');
+            let mut first = true;
+            for core in 0..smp::MAX_CORES {
+                if frame_of(core).is_some() {
+                    if !first {
+                        self.reply.push(b',');
+                    }
+                    write!(self.reply, "{:x}", core + 1).ok();
+                    first = false;
+                }
+            }
+        } else if args == b"sThreadInfo" {
+            self.reply.push(b'l');
+        } else if args.starts_with(b"ThreadExtraInfo,") {
+            // The description is sent as hex encoded text
+            if let Some(core) = thread_to_core(&args[16..]) {
+                for byte in b"core ".iter() {
+                    self.reply.push_hex(*byte);
+                }
+                if core >= 10 {
+                    self.reply.push_hex(b'0' + (core / 10) as u8);
+                }
+                self.reply.push_hex(b'0' + (core % 10) as u8);
+            }
+        }
+    }
+}
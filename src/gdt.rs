@@ -0,0 +1,72 @@
+use crate::debug;
+use crate::smp;
+use crate::Stack;
+use alloc::boxed::Box;
+use x86_64::instructions::segmentation::{load_ds, load_es, load_ss, set_cs};
+use x86_64::instructions::tables::load_tss;
+use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable};
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+/// IST indices as written into IDT entries. Index 0 means "no IST",
+/// so these are one greater than the slot used in the TSS.
+pub const DOUBLE_FAULT_IST: u8 = 1;
+pub const NMI_IST: u8 = 2;
+pub const MACHINE_CHECK_IST: u8 = 3;
+
+pub const KERNEL_CODE_SELECTOR: u16 = 0x08;
+pub const KERNEL_DATA_SELECTOR: u16 = 0x10;
+/// sysret derives both user selectors from STAR, which requires user data
+/// to directly precede user code. Both carry RPL 3.
+pub const USER_DATA_SELECTOR: u16 = 0x18 | 3;
+pub const USER_CODE_SELECTOR: u16 = 0x20 | 3;
+
+/// Per-core TSS, indexed by smp::me(). Each entry is written once by its own core in init.
+static mut CORE_TSS: [Option<*mut TaskStateSegment>; smp::MAX_CORES] = [None; smp::MAX_CORES];
+
+/// Allocates a stack that is never freed and returns the address of its top
+fn alloc_ist_stack() -> u64 {
+    let stack = Box::into_raw(Stack::boxed_new());
+    stack as u64 + core::mem::size_of::<Stack>() as u64
+}
+
+/// Builds and loads a GDT and TSS for the calling core.
+/// The kernel code and data selectors match the ones used by the bootstrap GDT,
+/// so IDT entries remain valid across the switch.
+pub fn init() {
+    let me = smp::me();
+    let tss = Box::into_raw(box TaskStateSegment::new());
+    unsafe {
+        let ist = &mut (*tss).interrupt_stack_table;
+        ist[(DOUBLE_FAULT_IST - 1) as usize] = VirtAddr::new(alloc_ist_stack());
+        ist[(NMI_IST - 1) as usize] = VirtAddr::new(alloc_ist_stack());
+        ist[(MACHINE_CHECK_IST - 1) as usize] = VirtAddr::new(alloc_ist_stack());
+    }
+
+    let gdt: &'static mut GlobalDescriptorTable = Box::leak(box GlobalDescriptorTable::new());
+    let code = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code = gdt.add_entry(Descriptor::user_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*tss }));
+    assert_eq!(code.0, KERNEL_CODE_SELECTOR);
+    assert_eq!(data.0, KERNEL_DATA_SELECTOR);
+    assert_eq!(user_data.0, USER_DATA_SELECTOR);
+    assert_eq!(user_code.0, USER_CODE_SELECTOR);
+
+    gdt.load();
+    unsafe {
+        set_cs(code);
+        load_ss(data);
+        load_ds(data);
+        load_es(data);
+        load_tss(tss_selector);
+        CORE_TSS[me] = Some(tss);
+    }
+    debug!("Core {}: loaded GDT with TSS at 0x{:x}", me, tss as usize);
+}
+
+/// Returns the TSS of the calling core, if init has been called on it
+pub fn tss() -> Option<&'static mut TaskStateSegment> {
+    unsafe { CORE_TSS[smp::me()].map(|tss| &mut *tss) }
+}
@@ -0,0 +1,124 @@
+use crate::acpi;
+use crate::acpi::{ACPIHeader, GenericAddress};
+use crate::vmm;
+use crate::{debug, warn};
+
+/*
+ * The High Precision Event Timer. Its main counter ticks at a fixed rate,
+ * given in femtoseconds per tick, from the moment it is enabled, and every
+ * core reads the same counter, which makes it a good reference for timing the
+ * other clocks. Only the main counter is used; the comparators are left off.
+ *
+ * The ACPI HPET table gives the address of the registers.
+ */
+
+/// Register offsets
+const CAPABILITIES: u64 = 0x00;
+const CONFIGURATION: u64 = 0x10;
+const MAIN_COUNTER: u64 = 0xf0;
+/// Capabilities bit set if the main counter is 64 bits wide
+const COUNT_SIZE_CAP: u64 = 1 << 13;
+/// Configuration bit that starts the main counter
+const ENABLE_CNF: u64 = 1 << 0;
+/// The spec caps the tick period at 100 ns
+const MAX_PERIOD_FS: u64 = 100_000_000;
+const FS_PER_NS: u64 = 1_000_000;
+
+/// The ACPI table that describes an HPET
+#[repr(C, packed)]
+pub struct HpetTable {
+    pub header: ACPIHeader,
+    pub event_timer_block_id: u32,
+    pub base_address: GenericAddress,
+    pub hpet_number: u8,
+    pub minimum_tick: u16,
+    pub page_protection: u8,
+}
+
+unsafe impl acpi::Table for HpetTable {
+    const SIGNATURE: &'static [u8; 4] = b"HPET";
+}
+
+pub struct Hpet {
+    base: u64,
+    /// Femtoseconds per tick
+    period: u64,
+}
+
+/// Only written by init, before other cores run
+static mut HPET: Option<Hpet> = None;
+
+impl Hpet {
+    fn read(&self, register: u64) -> u64 {
+        unsafe { core::ptr::read_volatile((self.base + register) as *const u64) }
+    }
+
+    fn write(&self, register: u64, value: u64) {
+        unsafe { core::ptr::write_volatile((self.base + register) as *mut u64, value) }
+    }
+
+    /// The main counter
+    pub fn counter(&self) -> u64 {
+        self.read(MAIN_COUNTER)
+    }
+
+    /// Femtoseconds per tick of the main counter
+    pub fn period(&self) -> u64 {
+        self.period
+    }
+
+    /// Converts ticks of the main counter to nanoseconds
+    pub fn to_nanos(&self, ticks: u64) -> u64 {
+        (ticks as u128 * self.period as u128 / FS_PER_NS as u128) as u64
+    }
+}
+
+/// Finds the HPET and starts its main counter from 0.
+/// Requires acpi::init and vmm::init.
+pub fn init() {
+    let table = match acpi::find::<HpetTable>() {
+        Some(table) => table,
+        None => {
+            warn!("no HPET");
+            return;
+        }
+    };
+    let address = table.base_address;
+    if address.address_space != 0 {
+        warn!("the HPET is not memory mapped");
+        return;
+    }
+    let hpet = Hpet {
+        base: vmm::map_mmio(address.address),
+        period: 0,
+    };
+    let capabilities = hpet.read(CAPABILITIES);
+    let period = capabilities >> 32;
+    if period == 0 || period > MAX_PERIOD_FS {
+        warn!("the HPET has a bad period of {} fs", period);
+        return;
+    }
+    // A 32 bit counter wraps every few minutes, which now() cannot tell apart from time
+    // going backwards
+    if capabilities & COUNT_SIZE_CAP == 0 {
+        warn!("the HPET's counter is only 32 bits, not using it");
+        return;
+    }
+    let configuration = hpet.read(CONFIGURATION);
+    hpet.write(CONFIGURATION, configuration & !ENABLE_CNF);
+    hpet.write(MAIN_COUNTER, 0);
+    hpet.write(CONFIGURATION, configuration | ENABLE_CNF);
+    debug!(
+        "HPET at 0x{:x} ticks every {} fs",
+        { address.address },
+        period
+    );
+    unsafe {
+        HPET = Some(Hpet { period, ..hpet });
+    }
+}
+
+/// The HPET, if init found a usable one
+pub fn get() -> Option<&'static Hpet> {
+    unsafe { HPET.as_ref() }
+}
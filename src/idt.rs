@@ -1,5 +1,12 @@
+use crate::backtrace;
+use crate::gdb;
+use crate::gdt;
 use crate::machine;
 use crate::println;
+use crate::process;
+use crate::smp;
+use crate::thread;
+use crate::vmm;
 
 pub static mut IDT: IDT = IDT::new();
 pub static mut IDTRECORD: IDTRecord = IDTRecord {
@@ -12,6 +19,159 @@ pub extern "C" fn interrupt_test() {
     println!("got interrupted!");
 }
 
+static EXCEPTION_NAMES: [&str; 32] = [
+    "Divide Error",
+    "Debug",
+    "Non-Maskable Interrupt",
+    "Breakpoint",
+    "Overflow",
+    "Bound Range Exceeded",
+    "Invalid Opcode",
+    "Device Not Available",
+    "Double Fault",
+    "Coprocessor Segment Overrun",
+    "Invalid TSS",
+    "Segment Not Present",
+    "Stack-Segment Fault",
+    "General Protection Fault",
+    "Page Fault",
+    "Reserved",
+    "x87 Floating-Point Exception",
+    "Alignment Check",
+    "Machine Check",
+    "SIMD Floating-Point Exception",
+    "Virtualization Exception",
+    "Control Protection Exception",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Hypervisor Injection Exception",
+    "VMM Communication Exception",
+    "Security Exception",
+    "Reserved",
+];
+
+/// The state saved by the exception stubs in machine.S.
+/// The layout must match the order in which exception_common pushes registers.
+#[repr(C)]
+pub struct TrapFrame {
+    pub cr2: u64,
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub vector: u64,
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl TrapFrame {
+    pub fn print(&self) {
+        println!(
+            "rip 0x{:016x} cs 0x{:x} rflags 0x{:x} rsp 0x{:016x} ss 0x{:x}",
+            self.rip, self.cs, self.rflags, self.rsp, self.ss
+        );
+        println!(
+            "rax 0x{:016x} rbx 0x{:016x} rcx 0x{:016x} rdx 0x{:016x}",
+            self.rax, self.rbx, self.rcx, self.rdx
+        );
+        println!(
+            "rsi 0x{:016x} rdi 0x{:016x} rbp 0x{:016x} cr2 0x{:016x}",
+            self.rsi, self.rdi, self.rbp, self.cr2
+        );
+        println!(
+            "r8  0x{:016x} r9  0x{:016x} r10 0x{:016x} r11 0x{:016x}",
+            self.r8, self.r9, self.r10, self.r11
+        );
+        println!(
+            "r12 0x{:016x} r13 0x{:016x} r14 0x{:016x} r15 0x{:016x}",
+            self.r12, self.r13, self.r14, self.r15
+        );
+    }
+}
+
+/// Called by exception_common for every architectural exception.
+/// Dumps the faulting state and stops the machine.
+#[no_mangle]
+pub extern "C" fn exception_handler(frame: &mut TrapFrame) {
+    // The gdb stub stops the other cores with NMIs, wherever they are
+    if frame.vector == 2 && gdb::handle_nmi(frame) {
+        return;
+    }
+    // A fault in user mode only takes down the process
+    if frame.cs & 3 == 3 {
+        println!(
+            "Core {}: {} in user mode at rip 0x{:x}, address 0x{:x}",
+            smp::me(),
+            EXCEPTION_NAMES[(frame.vector & 0x1f) as usize],
+            frame.rip,
+            frame.cr2
+        );
+        process::exit_current(-1);
+    }
+    if (frame.vector == 1 || frame.vector == 3) && gdb::enabled() {
+        gdb::handle_exception(frame);
+        return;
+    }
+    // A stack overflow faults on the guard page, and then faults again while pushing
+    // the page fault frame, so it usually arrives here as a double fault.
+    if frame.vector == 14 || frame.vector == 8 {
+        if let Some(stack) = vmm::stack_guard_hit(frame.cr2) {
+            match thread::find_by_stack(stack) {
+                Some(thread) => println!(
+                    "Core {}: stack overflow in {} (guard page hit at 0x{:x})",
+                    smp::me(),
+                    thread,
+                    frame.cr2
+                ),
+                None => println!(
+                    "Core {}: stack overflow on stack {} (guard page hit at 0x{:x})",
+                    smp::me(),
+                    stack,
+                    frame.cr2
+                ),
+            }
+        }
+    }
+    let name = EXCEPTION_NAMES[(frame.vector & 0x1f) as usize];
+    println!(
+        "Core {}: {} (vector {}), error code 0x{:x}",
+        smp::me(),
+        name,
+        frame.vector,
+        frame.error_code
+    );
+    frame.print();
+    backtrace::print_from(frame.rip, frame.rbp);
+    match thread::try_running_on(smp::me()) {
+        Some(thread) => println!("Current thread: {}", thread),
+        None => println!("Current thread: unavailable"),
+    }
+    match thread::try_current_info() {
+        Some(info) => println!("Current TCB: TCBInfo at 0x{:x}", info as usize),
+        None => println!("Current TCB: unavailable"),
+    }
+    machine::exit(machine::EXIT_QEMU_FAILURE);
+}
+
 #[repr(C, align(4096))]
 pub struct IDT {
     entries: [IDTEntryWrapper; 256],
@@ -32,6 +192,15 @@ pub struct IDTRecord {
 }
 
 pub fn init() {
+    for vector in 0..32 {
+        let ist = match vector {
+            2 => gdt::NMI_IST,
+            8 => gdt::DOUBLE_FAULT_IST,
+            18 => gdt::MACHINE_CHECK_IST,
+            _ => 0,
+        };
+        interrupt_with_ist(vector, unsafe { machine::exception_stubs[vector] }, ist);
+    }
     let limit: u16 = core::mem::size_of::<IDT>() as u16 - 1;
     let idt_addr: u64 = unsafe { &IDT as *const IDT as u64 };
     let idt_record_ptr: u64 = unsafe { &IDTRECORD as *const IDTRecord as u64 };
@@ -50,13 +219,22 @@ pub fn init_ap() {
 }
 
 pub fn interrupt(index: usize, handler: unsafe extern "C" fn()) {
+    interrupt_with_ist(index, handler, 0);
+}
+
+/// Registers handler at index, running it on the given interrupt stack table entry of
+/// the current core's TSS. An ist of 0 keeps the current stack.
+pub fn interrupt_with_ist(index: usize, handler: unsafe extern "C" fn(), ist: u8) {
     let mut idt_entry = IDTEntryWrapper::new();
     let ptr = handler as *const () as u64;
     let handler_canonical = InterruptHandlerCanonicalForm { 0: ptr };
     idt_entry
         .entry
         .set_offset_low_bits(handler_canonical.low_bits());
-    idt_entry.entry.set_selector(8);
+    idt_entry
+        .entry
+        .set_selector(gdt::KERNEL_CODE_SELECTOR as u64);
+    idt_entry.entry.set_ist(ist as u64);
     idt_entry.entry.set_type_and_attributes(0x8E);
     idt_entry
         .entry
@@ -99,7 +277,8 @@ bitfield! {
     u64;
     offset_low_bits, set_offset_low_bits: 15, 0;
     selector, set_selector: 31, 16;
-    zero, _: 39, 32;
+    ist, set_ist: 34, 32;
+    zero, _: 39, 35;
     type_and_attributes, set_type_and_attributes: 47, 40;
     offset_middle_bits, set_offset_middle_bits: 63, 48;
     offset_high_bits, set_offset_high_bits: 95, 64;
@@ -0,0 +1,161 @@
+use crate::config;
+use crate::config::CONFIG;
+use crate::vmm;
+use crate::{debug, warn};
+
+/*
+ * The I/O APICs turn device interrupt lines into messages for the local APICs.
+ * Each one owns a range of global system interrupts (GSIs), starting at the
+ * gsi_base the MADT gives it, one per input.
+ *
+ * The registers are reached through a window: write the register number to
+ * IOREGSEL, then read or write IOWIN. Each input has a 64 bit redirection entry,
+ * at registers 0x10 + 2 * input, that says which vector to raise on which core.
+ */
+
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+const IOAPICVER: u32 = 0x01;
+const IOREDTBL: u32 = 0x10;
+/// Redirection entry bits
+const ACTIVE_LOW: u64 = 1 << 13;
+const LEVEL_TRIGGERED: u64 = 1 << 15;
+/// Set to ignore the input
+const MASKED: u64 = 1 << 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Polarity {
+    High,
+    Low,
+}
+
+pub struct IoApic {
+    base: u64,
+    gsi_base: u32,
+    inputs: u32,
+}
+
+const NO_IO_APIC: Option<IoApic> = None;
+/// Only written by init, before other cores run
+static mut IO_APICS: [Option<IoApic>; config::MAX_IO_APICS] = [NO_IO_APIC; config::MAX_IO_APICS];
+
+impl IoApic {
+    /// Requires vmm::init, since the registers are mapped on first use
+    pub fn new(phys_base: u32, gsi_base: u32) -> IoApic {
+        let mut io_apic = IoApic {
+            base: vmm::map_mmio(phys_base as u64),
+            gsi_base: gsi_base,
+            inputs: 0,
+        };
+        io_apic.inputs = ((io_apic.read(IOAPICVER) >> 16) & 0xff) + 1;
+        io_apic
+    }
+
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            core::ptr::write_volatile((self.base + IOREGSEL) as *mut u32, register);
+            core::ptr::read_volatile((self.base + IOWIN) as *const u32)
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        unsafe {
+            core::ptr::write_volatile((self.base + IOREGSEL) as *mut u32, register);
+            core::ptr::write_volatile((self.base + IOWIN) as *mut u32, value);
+        }
+    }
+
+    /// The number of inputs
+    pub fn inputs(&self) -> u32 {
+        self.inputs
+    }
+
+    /// Returns true if gsi is one of this I/O APIC's inputs
+    pub fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi - self.gsi_base < self.inputs
+    }
+
+    fn set_entry(&self, gsi: u32, entry: u64) {
+        let register = IOREDTBL + 2 * (gsi - self.gsi_base);
+        // Write the high half first, so the entry never fires with a stale destination
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+
+    /// Raises vector on the core with LAPIC id apic_id when gsi fires
+    pub fn route(&self, gsi: u32, vector: u8, apic_id: u32, trigger: Trigger, polarity: Polarity) {
+        let mut entry = (apic_id as u64) << 56 | vector as u64;
+        if trigger == Trigger::Level {
+            entry |= LEVEL_TRIGGERED;
+        }
+        if polarity == Polarity::Low {
+            entry |= ACTIVE_LOW;
+        }
+        self.set_entry(gsi, entry);
+    }
+
+    pub fn mask(&self, gsi: u32) {
+        self.set_entry(gsi, MASKED);
+    }
+}
+
+/// Finds every I/O APIC the MADT lists and masks all of their inputs.
+/// Requires config::init and vmm::init.
+pub fn init() {
+    for (index, info) in unsafe { CONFIG.io_apics.iter().enumerate() } {
+        if let Some(info) = info {
+            let io_apic = IoApic::new(info.address, info.gsi_base);
+            for gsi in info.gsi_base..info.gsi_base + io_apic.inputs() {
+                io_apic.mask(gsi);
+            }
+            debug!(
+                "I/O APIC {} has GSIs {}-{}",
+                info.id,
+                info.gsi_base,
+                info.gsi_base + io_apic.inputs() - 1
+            );
+            unsafe {
+                IO_APICS[index] = Some(io_apic);
+            }
+        }
+    }
+    if unsafe { IO_APICS.iter().all(|io_apic| io_apic.is_none()) } {
+        warn!("no I/O APIC, device interrupts will not arrive");
+    }
+}
+
+/// The I/O APIC that gsi is an input of
+fn find(gsi: u32) -> Option<&'static IoApic> {
+    unsafe { IO_APICS.iter() }
+        .filter_map(|io_apic| io_apic.as_ref())
+        .find(|io_apic| io_apic.handles(gsi))
+}
+
+/// Raises vector on the core with LAPIC id apic_id when gsi fires.
+/// Returns false if no I/O APIC has gsi. Requires init.
+pub fn route(gsi: u32, vector: u8, apic_id: u32, trigger: Trigger, polarity: Polarity) -> bool {
+    match find(gsi) {
+        Some(io_apic) => {
+            io_apic.route(gsi, vector, apic_id, trigger, polarity);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Stops gsi from raising anything. Returns false if no I/O APIC has it.
+pub fn mask(gsi: u32) -> bool {
+    match find(gsi) {
+        Some(io_apic) => {
+            io_apic.mask(gsi);
+            true
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,146 @@
+use crate::config::CONFIG;
+use crate::idt;
+use crate::ioapic;
+use crate::ioapic::{Polarity, Trigger};
+use crate::ismutex::ISMutex;
+use crate::machine;
+use crate::smp;
+use crate::thread;
+use crate::{debug, trace};
+
+/*
+ * Device interrupts, delivered through the I/O APICs.
+ *
+ * IRQs 0-15 are the legacy ISA IRQs: the serial ports, the PS/2 keyboard, the
+ * IDE channels, and PCI INTx lines as the interrupt line register reports them.
+ * Each arrives on the global system interrupt of the same number, edge
+ * triggered and active high, unless the MADT has an override for it. Higher
+ * IRQs are global system interrupts, which PCI uses level triggered and
+ * active low.
+ *
+ * IRQ n is delivered on vector IRQ_BASE_VECTOR + n. Its stub in machine.S calls
+ * irq_dispatch, which runs the registered handler and acknowledges the
+ * interrupt, so handlers only deal with their device.
+ */
+
+/// IRQs that can have a handler, matching the stubs in machine.S
+pub const MAX_IRQS: usize = 24;
+/// The vector of IRQ 0. Clear of the exceptions and the local APIC timer.
+pub const IRQ_BASE_VECTOR: usize = 0x30;
+const ISA_IRQS: u32 = 16;
+
+/// Bits of an override's flags. Each field is 0 for the bus default, which for ISA
+/// is edge triggered and active high.
+const OVERRIDE_ACTIVE_LOW: u16 = 0b11;
+const OVERRIDE_LEVEL: u16 = 0b11 << 2;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum IrqError {
+    /// Beyond MAX_IRQS, or not an input of any I/O APIC
+    NoSuchIrq,
+    /// The target core is not running
+    NoSuchCore,
+    /// Another handler is registered
+    InUse,
+}
+
+pub type Handler = fn();
+
+static HANDLERS: ISMutex<[Option<Handler>; MAX_IRQS]> = ISMutex::new([None; MAX_IRQS]);
+
+/// Installs the IRQ vectors and masks every I/O APIC input.
+/// Requires config::init, vmm::init and idt::init.
+pub fn init() {
+    ioapic::init();
+    for irq in 0..MAX_IRQS {
+        idt::interrupt(IRQ_BASE_VECTOR + irq, unsafe { machine::irq_stubs[irq] });
+    }
+}
+
+/// The global system interrupt irq arrives on, and how it is signaled
+fn resolve(irq: u32) -> (u32, Trigger, Polarity) {
+    if irq >= ISA_IRQS {
+        return (irq, Trigger::Level, Polarity::Low);
+    }
+    match unsafe { CONFIG.irq_overrides[irq as usize] } {
+        Some(irq_override) => {
+            let flags = irq_override.flags;
+            let trigger = if flags & OVERRIDE_LEVEL == OVERRIDE_LEVEL {
+                Trigger::Level
+            } else {
+                Trigger::Edge
+            };
+            let polarity = if flags & OVERRIDE_ACTIVE_LOW == OVERRIDE_ACTIVE_LOW {
+                Polarity::Low
+            } else {
+                Polarity::High
+            };
+            (irq_override.gsi, trigger, polarity)
+        }
+        None => (irq, Trigger::Edge, Polarity::High),
+    }
+}
+
+/// Runs handler on target_core every time irq fires. Requires init.
+pub fn register(irq: usize, handler: Handler, target_core: usize) -> Result<(), IrqError> {
+    if irq >= MAX_IRQS {
+        return Err(IrqError::NoSuchIrq);
+    }
+    let apic_id = match unsafe { CONFIG.topology.apic_id(target_core) } {
+        Some(apic_id) if smp::is_online(target_core) => apic_id,
+        _ => return Err(IrqError::NoSuchCore),
+    };
+    let mut handlers = HANDLERS.lock();
+    if handlers[irq].is_some() {
+        return Err(IrqError::InUse);
+    }
+    let (gsi, trigger, polarity) = resolve(irq as u32);
+    let vector = (IRQ_BASE_VECTOR + irq) as u8;
+    // The handler is in place before the first interrupt can arrive
+    handlers[irq] = Some(handler);
+    if !ioapic::route(gsi, vector, apic_id, trigger, polarity) {
+        handlers[irq] = None;
+        return Err(IrqError::NoSuchIrq);
+    }
+    debug!(
+        "irq {} on gsi {} ({:?}, active {:?}) -> vector {} on core {}",
+        irq, gsi, trigger, polarity, vector, target_core
+    );
+    Ok(())
+}
+
+/// Masks irq and removes its handler
+pub fn unregister(irq: usize) -> Result<(), IrqError> {
+    if irq >= MAX_IRQS {
+        return Err(IrqError::NoSuchIrq);
+    }
+    let mut handlers = HANDLERS.lock();
+    if handlers[irq].is_none() {
+        return Err(IrqError::NoSuchIrq);
+    }
+    let (gsi, _, _) = resolve(irq as u32);
+    ioapic::mask(gsi);
+    handlers[irq] = None;
+    Ok(())
+}
+
+/// Called by the IRQ stubs in machine.S
+#[no_mangle]
+pub extern "C" fn irq_dispatch(irq: u64) {
+    let handler = HANDLERS.lock()[irq as usize];
+    match handler {
+        Some(handler) => handler(),
+        None => trace!("irq {} with no handler", irq),
+    }
+    let lapic = unsafe {
+        match &smp::LAPIC {
+            Some(lapic) => lapic,
+            None => panic!("No LAPIC available"),
+        }
+    };
+    unsafe {
+        core::ptr::write_volatile(lapic.eoi_reg, 0);
+    }
+    // A thread the handler woke may outrank the one it interrupted
+    thread::preempt();
+}
@@ -1,5 +1,5 @@
 #[macro_use]
-use crate::println;
+use crate::debug;
 
 use crate::ismutex::ISMutex;
 use core::alloc::{GlobalAlloc, Layout};
@@ -26,7 +26,7 @@ impl ISHeap {
 
     pub unsafe fn init(&self, heap_bottom: usize, heap_size: usize) {
         self.0.lock().init(heap_bottom, heap_size);
-        println!("initialized the ISHeap");
+        debug!("initialized the ISHeap");
     }
 }
 
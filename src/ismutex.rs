@@ -50,6 +50,17 @@ impl<T> ISMutex<T> {
             was: was,
         }
     }
+
+    /// Locks the mutex only if it is not currently held.
+    /// Useful in contexts that must not deadlock, such as exception handlers.
+    pub fn try_lock(&self) -> Option<ISMutexGuard<T>> {
+        let was = self.lock.try_lock()?;
+        Some(ISMutexGuard {
+            lock: &self.lock,
+            data: unsafe { &mut *self.data.get() },
+            was: was,
+        })
+    }
 }
 
 unsafe impl<T: Send> Send for ISMutex<T> {}
@@ -10,18 +10,35 @@
 #![reexport_test_harness_main = "test_main"]
 #![test_runner(crate::test_runner)]
 
+pub mod acpi;
+pub mod backtrace;
+pub mod boot_options;
+pub mod clock;
 pub mod config;
+pub mod dmesg;
+pub mod elf;
+pub mod gdb;
+pub mod gdt;
 pub mod heap;
+pub mod hpet;
 pub mod ide;
 pub mod idt;
+pub mod ioapic;
+pub mod irq;
 pub mod isheap;
 pub mod ismutex;
+pub mod log;
 pub mod machine;
 pub mod pci;
+pub mod pmm;
+pub mod power;
+pub mod process;
+pub mod scheduler;
 pub mod semaphore;
 pub mod sfs;
 pub mod smp;
 pub mod spinlock;
+pub mod syscall;
 pub mod thread;
 pub mod timer;
 pub mod u8250;
@@ -40,7 +57,7 @@ use alloc::{boxed::Box, vec, vec::Vec};
 
 use core::fmt::Write;
 use core::panic::PanicInfo;
-use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
 
 use alloc::sync::Arc;
 use config::mb_info;
@@ -65,9 +82,16 @@ static ALLOCATOR: LockedHeap = LockedHeap::empty();
 #[global_allocator]
 static ALLOCATOR: ISHeap = ISHeap::empty();
 
+/// Size of the kernel heap. config::place_heap picks where it goes in physical memory,
+/// and it is used through the direct map.
+pub const HEAP_SIZE: usize = 0x800000;
+/// How many log entries the panic handler prints
+const PANIC_DUMP_ENTRIES: usize = 32;
+
 static mut STACK: Stack = Stack::new();
 static APSTACK: AtomicUsize = AtomicUsize::new(0);
 static CORES_ACTIVE: AtomicU32 = AtomicU32::new(0);
+static PANICKING: AtomicBool = AtomicBool::new(false);
 
 #[repr(C, align(4096))]
 #[derive(Copy, Clone)]
@@ -113,18 +137,22 @@ pub extern "C" fn _start(mb_config: &mb_info, end: u64) -> ! {
 #[no_mangle]
 pub extern "C" fn _ap_start() -> ! {
     unsafe {
-        println!("rsp is {:x}", machine::get_rsp());
+        debug!("rsp is {:x}", machine::get_rsp());
     }
     vmm::init_ap();
+    gdt::init();
+    syscall::init();
     idt::init_ap();
     let apic = apic::Apic::with_base(unsafe {CONFIG.local_apic as usize});
     apic.initialize();
+    smp::mark_online();
     // smp::init_ap();
+    thread::init_ap();
     timer::init();
     let me = smp::me();
-    println!("AP {} reached _ap_start", me);
+    info!("AP {} reached _ap_start", me);
     CORES_ACTIVE.fetch_add(1, Ordering::SeqCst);
-    let num_cores = unsafe { CONFIG.total_procs };
+    let num_cores = cores_to_start();
 
     while CORES_ACTIVE.load(Ordering::SeqCst) < num_cores {}
     loop {
@@ -134,67 +162,92 @@ pub extern "C" fn _ap_start() -> ! {
     let mut x = 10;
 }
 
+/// The number of cores to bring up, including the bootstrap core
+fn cores_to_start() -> u32 {
+    let total = unsafe { CONFIG.total_procs };
+    match boot_options::get().smp {
+        Some(limit) if limit < total => limit,
+        _ => total,
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn pick_stack() -> usize {
     let stack = unsafe { (&STACK as *const Stack as usize) + ((2048 * 8) - 8) };
-    println!("called pick_stack {:x}", stack);
+    trace!("called pick_stack {:x}", stack);
     stack
 }
 
 #[no_mangle]
 pub extern "C" fn ap_pick_stack() -> usize {
     let stack = APSTACK.load(Ordering::SeqCst) + (4096 - 8);
-    println!("picked rsp 0x{:x}", stack);
+    trace!("picked rsp 0x{:x}", stack);
     stack
 }
 
 #[no_mangle]
 pub extern "C" fn kernel_init(mb_config: &mb_info, end: u64) {
     CORES_ACTIVE.fetch_add(1, Ordering::SeqCst);
-    println!("the kernel stack is at {:x}", unsafe {
+    debug!("the kernel stack is at {:x}", unsafe {
         &STACK as *const Stack as usize
     });
-    println!("mb_config at {:x}", mb_config as *const mb_info as usize);
+    debug!("mb_config at {:x}", mb_config as *const mb_info as usize);
     //let rsp = unsafe{machine::get_rsp()};
     //println!("rsp at {:x}", rsp);
-    let mut uart = U8250 {};
+    u8250::init();
+    let mut uart = U8250::new(u8250::COM1);
     let hi = "Hello there!\n";
     uart.write_string(hi);
     write!(uart, "The numbers are {} and {}, {}\n", 42, 1.0 / 3.0, hi).unwrap();
     //println!("ooooweee, we're using println, {} {} {}", 42, 1.0 / 3.0, hi);
-    println!("Kernel End Address {:x}", end);
+    debug!("Kernel End Address {:x}", end);
     config::init(mb_config);
+    boot_options::init();
+    log::init();
+    backtrace::init();
+    power::init();
     config::memory_map_init();
+    unsafe {
+        let heap_start = config::place_heap(end - vmm::KERNEL_BASE, HEAP_SIZE as u64);
+        ALLOCATOR.init(vmm::phys_to_virt(heap_start) as usize, HEAP_SIZE);
+    }
+    pmm::init(end - vmm::KERNEL_BASE);
     vmm::init();
+    hpet::init();
+    clock::init();
+    gdt::init();
+    syscall::init();
     idt::init();
     idt::interrupt(0xff, machine::spurious_handler);
+    irq::init();
     smp::init_bsp();
     let apic = apic::Apic::with_base(unsafe {CONFIG.local_apic as usize});
     apic.initialize();
-    println!("smp::me(): {}", smp::me());
-    pci::check_all_buses();
-    unsafe {
-        //ALLOCATOR.init(0x200000, 0x800000);
-        ALLOCATOR.init(0x200000, 0x800000);
-    }
+    smp::mark_online();
+    debug!("smp::me(): {}", smp::me());
+    u8250::init_interrupts();
+    pci::check_all_buses(boot_options::get().pci);
     thread::init();
-    timer::calibrate(1000);
+    timer::calibrate(boot_options::get().hz);
     timer::init();
 
     let reset_eip = machine::ap_entry as *const () as u32;
-    println!("reset eip 0x{:x}", reset_eip);
-    println!("Booting up other cores...");
-    let num_cores = unsafe { CONFIG.total_procs };
+    debug!("reset eip 0x{:x}", reset_eip);
+    info!("Booting up other cores...");
+    let num_cores = cores_to_start();
 
     for i in 1..num_cores {
+        // Cores are started in logical order, so core i is the i-th to come up
+        let apic_id = unsafe { CONFIG.topology.apic_id(i as usize).unwrap() };
         // First allocate a kernel stack
         // TODO: Put info about bootstrap stacks in a Bootstrap TCB
-        APSTACK.store(vmm::alloc() as usize, Ordering::SeqCst);
-        apic.init_ipi(i);
-        apic.startup_ipi(i, machine::ap_entry);
+        APSTACK.store(vmm::phys_to_virt(vmm::alloc()) as usize, Ordering::SeqCst);
+        apic.init_ipi(apic_id);
+        apic.startup_ipi(apic_id, machine::ap_entry);
         while (CORES_ACTIVE.load(Ordering::SeqCst) <= i) {}
     }
-    println!("done with ipis");
+    debug!("done with ipis");
+    gdb::init();
     unsafe {
         machine::sti();
     }
@@ -202,9 +255,22 @@ pub extern "C" fn kernel_init(mb_config: &mb_info, end: u64) {
 
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
-    print!("Panic: ");
-    if let Some(s) = _info.message() {
-        u8250::_print(*s);
+    // One line, so it is one entry in the dump
+    match _info.message() {
+        Some(message) => println!("Panic: {}", message),
+        None => println!("Panic"),
+    }
+    // A panic while printing the backtrace must not recurse forever
+    if !PANICKING.swap(true, Ordering::SeqCst) {
+        let me = smp::me();
+        match thread::try_running_on(me) {
+            Some(thread) => println!("Core {} was running {}", me, thread),
+            None => println!("Core {} was running an unknown thread", me),
+        }
+        // The log first, as the backtraces go into it too
+        dmesg::dump(PANIC_DUMP_ENTRIES);
+        backtrace::print_current();
+        thread::print_backtraces();
     }
     machine::exit(machine::EXIT_QEMU_FAILURE);
     loop {}
@@ -0,0 +1,229 @@
+use crate::boot_options;
+use crate::clock;
+use crate::dmesg;
+use crate::machine;
+use crate::smp;
+use crate::u8250;
+use crate::vga_buffer;
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
+use spin::RwLock;
+
+/*
+ * Leveled kernel logging. The macros tag each record with the module it came
+ * from, so `info!("...")` in vmm.rs is logged with the target "vmm". A record
+ * is written to a sink when it passes both the filter and that sink's level:
+ *
+ *   [   1.250] [core 0] INFO  vmm: initialized the kernel address space
+ *
+ * The filter is a global level, set with log=<level> on the command line,
+ * plus per-target overrides such as log.pci=warn or log.thread=trace.
+ * An override applies to its target and every module below it.
+ *
+ * dmesg keeps every record down to RECORD_LEVEL in memory, whether or not
+ * it passes the filter, along with everything written with print!.
+ */
+
+/// Records at this level or above go to dmesg even when the filter drops them
+pub const RECORD_LEVEL: LogLevel = LogLevel::Debug;
+/// Most targets that can have their own level
+pub const MAX_TARGETS: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum LogLevel {
+    /// Only as a filter: nothing is logged
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    pub fn parse(name: &str) -> Option<LogLevel> {
+        match name {
+            "off" => Some(LogLevel::Off),
+            "error" => Some(LogLevel::Error),
+            "warn" => Some(LogLevel::Warn),
+            "info" => Some(LogLevel::Info),
+            "debug" => Some(LogLevel::Debug),
+            "trace" => Some(LogLevel::Trace),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LogLevel::Off => "OFF",
+            LogLevel::Error => "ERROR",
+            LogLevel::Warn => "WARN",
+            LogLevel::Info => "INFO",
+            LogLevel::Debug => "DEBUG",
+            LogLevel::Trace => "TRACE",
+        }
+    }
+
+    fn from_u8(value: u8) -> LogLevel {
+        match value {
+            0 => LogLevel::Off,
+            1 => LogLevel::Error,
+            2 => LogLevel::Warn,
+            3 => LogLevel::Info,
+            4 => LogLevel::Debug,
+            _ => LogLevel::Trace,
+        }
+    }
+}
+
+/// Where records are written
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Sink {
+    Serial,
+    Vga,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+static SERIAL_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Trace as u8);
+/// The screen is small, so it only gets problems by default
+static VGA_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Warn as u8);
+
+/// Per-target overrides
+static TARGETS: RwLock<[Option<(&'static str, LogLevel)>; MAX_TARGETS]> =
+    RwLock::new([None; MAX_TARGETS]);
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => (
+        $crate::log::_log($level, module_path!(), format_args!($($arg)*))
+    );
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => ($crate::log!($crate::log::LogLevel::Error, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::log!($crate::log::LogLevel::Warn, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::log!($crate::log::LogLevel::Info, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::log!($crate::log::LogLevel::Debug, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => ($crate::log!($crate::log::LogLevel::Trace, $($arg)*));
+}
+
+/// Applies the log options from the command line. Requires boot_options::init.
+pub fn init() {
+    let options = boot_options::get();
+    set_level(options.log);
+    set_targets(options.log_targets);
+}
+
+/// Replaces every per-target override
+pub fn set_targets(targets: [Option<(&'static str, LogLevel)>; MAX_TARGETS]) {
+    // An interrupt handler that logs must not find the lock held on its own core
+    let was = machine::disable();
+    *TARGETS.write() = targets;
+    machine::enable(was);
+}
+
+/// Sets the level of every target without an override
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::SeqCst);
+}
+
+pub fn level() -> LogLevel {
+    LogLevel::from_u8(LEVEL.load(Ordering::Relaxed))
+}
+
+/// Sets the most verbose level written to sink
+pub fn set_sink_level(sink: Sink, level: LogLevel) {
+    match sink {
+        Sink::Serial => SERIAL_LEVEL.store(level as u8, Ordering::SeqCst),
+        Sink::Vga => VGA_LEVEL.store(level as u8, Ordering::SeqCst),
+    }
+}
+
+/// The target of a module path: the path without the crate name
+fn target(module: &'static str) -> &'static str {
+    match module.find("::") {
+        Some(index) => &module[index + 2..],
+        None => module,
+    }
+}
+
+/// The level in effect for target. The longest matching override wins.
+fn level_for(target: &str) -> LogLevel {
+    let mut level = level();
+    let mut matched = 0;
+    for entry in TARGETS.read().iter() {
+        if let Some((name, target_level)) = entry {
+            let matches = target == *name
+                || (target.starts_with(name) && target[name.len()..].starts_with("::"));
+            if matches && name.len() >= matched {
+                level = *target_level;
+                matched = name.len();
+            }
+        }
+    }
+    level
+}
+
+/// Returns true if a record at level from module would be written anywhere
+pub fn enabled(level: LogLevel, module: &'static str) -> bool {
+    level != LogLevel::Off && level <= level_for(target(module))
+}
+
+/// Time since boot, as records are stamped with it
+fn millis() -> u64 {
+    clock::now() / clock::NANOS_PER_MILLI
+}
+
+/// Keeps console output in dmesg, so it can be read back next to the log records
+pub fn record_output(args: fmt::Arguments) {
+    dmesg::record(millis(), LogLevel::Info, args);
+}
+
+#[doc(hidden)]
+pub fn _log(level: LogLevel, module: &'static str, args: fmt::Arguments) {
+    let passes = enabled(level, module);
+    if !passes && (level == LogLevel::Off || level > RECORD_LEVEL) {
+        return;
+    }
+    let millis = millis();
+    dmesg::record(millis, level, format_args!("{}: {}", target(module), args));
+    if !passes {
+        return;
+    }
+    // Each sink gets the whole line in one call, so lines from different cores never mix
+    let write = |print: fn(fmt::Arguments)| {
+        print(format_args!(
+            "[{:4}.{:03}] [core {}] {:<5} {}: {}\n",
+            millis / 1000,
+            millis % 1000,
+            smp::me(),
+            level.name(),
+            target(module),
+            args
+        ))
+    };
+    if level as u8 <= SERIAL_LEVEL.load(Ordering::Relaxed) {
+        write(u8250::write_fmt);
+    }
+    if level as u8 <= VGA_LEVEL.load(Ordering::Relaxed) {
+        write(vga_buffer::_print);
+    }
+}
@@ -1,5 +1,6 @@
 use crate::println;
 use crate::thread::TCBInfo;
+use crate::u8250;
 
 pub static EXIT_QEMU_SUCCESS: u32 = 5;
 pub static EXIT_QEMU_FAILURE: u32 = 3;
@@ -12,12 +13,22 @@ extern "C" {
     pub fn inw(port: u32) -> u16;
     pub fn inl(port: u32) -> u32;
     pub fn hlt();
+    /// Enables interrupts and halts until the next one, with nothing able to arrive in between
+    pub fn sti_hlt();
     pub fn load_cr3(pml4: u64);
+    pub fn get_cr3() -> u64;
+    pub fn invlpg(addr: u64);
     pub fn rdmsr(msr: u32) -> u64;
     pub fn wrmsr(val: u64, msr: u32);
     pub fn lidt(idt: u64);
     pub fn spurious_handler();
+    pub static exception_stubs: [unsafe extern "C" fn(); 32];
+    pub static irq_stubs: [unsafe extern "C" fn(); 24];
     pub fn _apit_handler();
+    pub fn _tlb_shootdown_handler();
+    pub fn _wake_handler();
+    pub fn syscall_entry();
+    pub fn enter_user(rip: u64, rsp: u64) -> !;
     pub fn software_int();
     pub fn ap_entry() -> !;
     pub fn context_switch(current: *mut TCBInfo, next: *mut TCBInfo);
@@ -25,6 +36,11 @@ extern "C" {
     pub fn sti();
     pub fn get_flags() -> u64;
     pub fn get_rsp() -> u64;
+    pub fn get_rbp() -> u64;
+    /// Reads the time stamp counter
+    pub fn rdtsc() -> u64;
+    /// Raises a breakpoint exception
+    pub fn breakpoint();
 }
 
 /// Disables interrupts, and returns whether or not interrupts were enabled
@@ -53,6 +69,8 @@ pub fn are_interrupts_enabled() -> bool {
 }
 
 pub fn exit(exit_code: u32) -> ! {
+    // Queued output would be lost with the machine
+    u8250::flush();
     unsafe {
         outl(0xf4, exit_code);
     }
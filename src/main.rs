@@ -10,15 +10,22 @@ extern crate alloc;
 use core::slice;
 use core::str;
 
+use oxos::boot_options;
 use oxos::config::mb_info;
+use oxos::config::CONFIG;
+use oxos::elf;
 use oxos::ide;
 use oxos::ide::{IDEImpl, IDE};
 use oxos::kernel_init;
 use oxos::machine;
+use oxos::semaphore::Semaphore;
 use oxos::sfs;
+use oxos::thread;
+use oxos::thread::TCBImpl;
 use oxos::{print, println, println_vga};
 
-use alloc::{boxed::Box, vec, vec::Vec};
+use alloc::{boxed::Box, sync::Arc, vec, vec::Vec};
+use core::sync::atomic::{AtomicU32, Ordering};
 
 #[cfg(test)]
 #[no_mangle]
@@ -43,10 +50,91 @@ fn trivial_assertion() {
     println!("[ok]");
 }
 
+/// What test= can run. Without it, the kernel runs the sfs demo.
+#[cfg(not(test))]
+const TESTS: [(&str, fn() -> !); 4] = [
+    ("sfs", sfs_demo),
+    ("initrd", initrd_test),
+    ("semaphore", semaphore_test),
+    ("adder", adder_test),
+];
+
 #[cfg(not(test))]
 #[no_mangle]
 pub extern "C" fn _start(mb_config: &mb_info, end: u64) -> ! {
     kernel_init(mb_config, end);
+    let name = boot_options::get().test.unwrap_or("sfs");
+    match TESTS.iter().find(|(test, _)| *test == name) {
+        Some((_, run)) => run(),
+        None => {
+            print!("Unknown test {}, expected one of:", name);
+            for (test, _) in TESTS.iter() {
+                print!(" {}", test);
+            }
+            println!();
+            machine::exit(machine::EXIT_QEMU_FAILURE);
+        }
+    }
+}
+
+/// A semaphore counts its ups, and down only waits once they are used up
+#[cfg(not(test))]
+fn semaphore_test() -> ! {
+    let sem = Semaphore::new(1);
+    sem.up();
+    sem.down();
+    sem.down();
+    // Another thread's up wakes a waiting down
+    let waker_sem = Arc::clone(&sem);
+    thread::schedule(box TCBImpl::new(box move || waker_sem.up()));
+    sem.down();
+    println!("Semaphore Test PASSED");
+    machine::exit(machine::EXIT_QEMU_SUCCESS);
+}
+
+/// Many threads add to one counter
+#[cfg(not(test))]
+fn adder_test() -> ! {
+    let counter = Arc::new(AtomicU32::new(0));
+    let done = Semaphore::new(0);
+    for _ in 0..100 {
+        let counter = Arc::clone(&counter);
+        let done = Arc::clone(&done);
+        thread::schedule(box TCBImpl::new(box move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            done.up();
+        }));
+    }
+    for _ in 0..100 {
+        done.down();
+    }
+    assert_eq!(counter.load(Ordering::SeqCst), 100);
+    println!("Adder Test PASSED");
+    machine::exit(machine::EXIT_QEMU_SUCCESS);
+}
+
+/// Runs the initrd module as an ELF executable. A negative exit code is a failure.
+#[cfg(not(test))]
+fn initrd_test() -> ! {
+    let module = match unsafe { CONFIG.find_module("initrd") } {
+        Some(module) => module,
+        None => panic!("No initrd module"),
+    };
+    let process = match elf::spawn(module.data(), &["initrd", "hello from the initrd"], &[]) {
+        Ok(process) => process,
+        Err(error) => panic!("Failed to load the initrd: {:?}", error),
+    };
+    let exit_code = process.wait();
+    println!("initrd exited with {}", exit_code);
+    if exit_code < 0 {
+        machine::exit(machine::EXIT_QEMU_FAILURE);
+    }
+    machine::exit(machine::EXIT_QEMU_SUCCESS);
+}
+
+/// Reads a file from the IDE disk and an SFS volume on it
+#[cfg(not(test))]
+fn sfs_demo() -> ! {
     let ide = ide::IDEImpl::new(1);
     let mut buf: Box<[u32]> = box [0; 512 / 4];
     println_vga!("Reading from file...");
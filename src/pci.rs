@@ -1,5 +1,6 @@
+use crate::boot_options::PciDump;
 use crate::machine;
-use crate::println;
+use crate::{debug, info};
 
 /*** FOR HELP UNDERSTANDING THE PCI GO TO wiki.osdev.org/PCI ***/
 
@@ -57,23 +58,23 @@ impl PCIDeviceHeader {
     }
 
     fn print_header(&self) {
-        println!("At bus {} and slot {} we have device:", self.bus, self.slot);
-        println!("Device ID      : 0x{:x}", self.device_id);
-        println!("Vendor ID      : 0x{:x}", self.vendor_id);
-        println!("Command        : 0x{:x}", self.command);
-        println!("Status         : 0x{:x}", self.status);
-        println!("Revision ID    : 0x{:x}", self.revision_id);
-        println!("Prog IF        : 0x{:x}", self.prog_if);
-        println!("Class Code     : 0x{:x}", self.class_code);
-        println!("Subclass       : 0x{:x}", self.subclass);
-        println!("Cache Line Size: 0x{:x}", self.cache_line_sz);
-        println!("Latency Timer  :   {}", self.latency_timer);
-        println!("Header Type    : 0x{:x}", self.header_type);
-        println!("BIST           : 0x{:x}", self.bist);
+        debug!("At bus {} and slot {} we have device:", self.bus, self.slot);
+        debug!("Device ID      : 0x{:x}", self.device_id);
+        debug!("Vendor ID      : 0x{:x}", self.vendor_id);
+        debug!("Command        : 0x{:x}", self.command);
+        debug!("Status         : 0x{:x}", self.status);
+        debug!("Revision ID    : 0x{:x}", self.revision_id);
+        debug!("Prog IF        : 0x{:x}", self.prog_if);
+        debug!("Class Code     : 0x{:x}", self.class_code);
+        debug!("Subclass       : 0x{:x}", self.subclass);
+        debug!("Cache Line Size: 0x{:x}", self.cache_line_sz);
+        debug!("Latency Timer  :   {}", self.latency_timer);
+        debug!("Header Type    : 0x{:x}", self.header_type);
+        debug!("BIST           : 0x{:x}", self.bist);
         if self.has_multiple_funcs() {
-            println!("This device has multiple functions.");
+            debug!("This device has multiple functions.");
         } else {
-            println!("This device has only one function.");
+            debug!("This device has only one function.");
         }
     }
 
@@ -187,30 +188,30 @@ impl PCI00DeviceInfo {
 
     fn print_header(&self) {
         //self.header.print_header();
-        println!("Specific info for header 00:");
+        debug!("Specific info for header 00:");
         for i in 0..6 {
-            println!("Base Address {}         : 0x{:x}", i, self.base_addr[i]);
+            debug!("Base Address {}         : 0x{:x}", i, self.base_addr[i]);
         }
-        println!("Cardbus CIS Ptr        : 0x{:x}", self.card_bus_cis_ptr);
-        println!("Subsystem ID           : 0x{:x}", self.subsystem_id);
-        println!("Subsystem Vendor ID    : 0x{:x}", self.subsystem_vendor_id);
-        println!(
+        debug!("Cardbus CIS Ptr        : 0x{:x}", self.card_bus_cis_ptr);
+        debug!("Subsystem ID           : 0x{:x}", self.subsystem_id);
+        debug!("Subsystem Vendor ID    : 0x{:x}", self.subsystem_vendor_id);
+        debug!(
             "Expansion ROM Base Addr: 0x{:x}",
             self.expansion_rom_base_addr
         );
-        println!("Capabilities PTR       : 0x{:x}", self.capabilities_ptr);
-        println!("Reserved  8bits        : 0x{:x}", self.reserved1);
-        println!("Reserved 16bits        : 0x{:x}", self.reserved2);
-        println!("Reserved 32bits        : 0x{:x}", self.reserved3);
-        println!("Interrupt PIN          : 0x{:x}", self.interrupt_pin);
-        println!("Interrupt Line         : 0x{:x}", self.interrupt_line);
-        println!("Interrupt PIN by MP    : 0x{:x}", self.acpi_pin_assignment);
-        println!(
+        debug!("Capabilities PTR       : 0x{:x}", self.capabilities_ptr);
+        debug!("Reserved  8bits        : 0x{:x}", self.reserved1);
+        debug!("Reserved 16bits        : 0x{:x}", self.reserved2);
+        debug!("Reserved 32bits        : 0x{:x}", self.reserved3);
+        debug!("Interrupt PIN          : 0x{:x}", self.interrupt_pin);
+        debug!("Interrupt Line         : 0x{:x}", self.interrupt_line);
+        debug!("Interrupt PIN by MP    : 0x{:x}", self.acpi_pin_assignment);
+        debug!(
             "Intin by MP            : 0x{:x}",
             self.acpi_intin_assignment
         );
-        println!("Min Grant              :   {}", self.min_grant);
-        println!("Max Latency            :   {}", self.max_latency);
+        debug!("Min Grant              :   {}", self.min_grant);
+        debug!("Max Latency            :   {}", self.max_latency);
     }
 
     /*
@@ -219,29 +220,29 @@ impl PCI00DeviceInfo {
         for i in 0..6 {
             // if this BAR is 0, print it does not exist
             if self.base_addr[i] == 0 {
-                println!("Base Address Register {} is null", i);
+                debug!("Base Address Register {} is null", i);
             } else {
-                println!("Base Address Register {} is a ", i);
+                debug!("Base Address Register {} is a ", i);
                 if ((self.base_addr[i] & 1) > 0) {
-                    println!("I/O Space BAR: 0x{}",
+                    debug!("I/O Space BAR: 0x{}",
                            (self.base_addr[i] & 0xFFFFFFFC));
-                    println!("    Its address space is size: 0x{} bytes",
+                    debug!("    Its address space is size: 0x{} bytes",
                            getAddressSpaceSize(i));
                 } else {
-                    println!("Memory Space BAR ");
+                    debug!("Memory Space BAR ");
                     if (((self.base_addr[i] >> 3) & 1) > 1 {
-                        println!("that is prefetchable with register size ");
+                        debug!("that is prefetchable with register size ");
                     else
-                        println!("that is not prefetchable with register size ");
+                        debug!("that is not prefetchable with register size ");
 
                     if (((self.base_addr[i] >> 1) & 3) == 0) {
-                        println!("32 bits: 0x{}", (self.base_addr[i] & 0xFFFFFFF0));
-                        println!("    Its address space is size: 0x{} bytes",
+                        debug!("32 bits: 0x{}", (self.base_addr[i] & 0xFFFFFFF0));
+                        debug!("    Its address space is size: 0x{} bytes",
                                getAddressSpaceSize(i));
                     } else if (((self.base_addr[i] >> 1) & 3) == 3)
-                        println!("64 bits");
+                        debug!("64 bits");
                     else
-                        println!("unsupported");
+                        debug!("unsupported");
                 }
             }
         }
@@ -293,7 +294,7 @@ fn config_read8(bus: u8, slot: u8, func: u8, offset: u8) -> u8 {
 
     tmp
 }
-fn config_write8(bus: u8, slot: u8, func: u8, offset: u8, data: u8) {
+pub fn config_write8(bus: u8, slot: u8, func: u8, offset: u8, data: u8) {
     let lbus: u32 = bus as u32;
     let lslot: u32 = slot as u32;
     let lfunc: u32 = func as u32;
@@ -399,7 +400,7 @@ fn config_write32(bus: u8, slot: u8, func: u8, offset: u8, data: u32) {
     }
 }
 
-fn check_vendor(bus: u8, slot: u8) -> u16 {
+fn check_vendor(bus: u8, slot: u8, dump: PciDump) -> u16 {
     // try and read first configuration register
     // if we get 0xFFFF then we know that the slot has no device since no device has vendor id 0xFFFF
     let vendor: u16 = config_read16(bus, slot, 0, 0);
@@ -407,24 +408,28 @@ fn check_vendor(bus: u8, slot: u8) -> u16 {
         // the slot has a device that exists
         let device: u16 = config_read16(bus, slot, 0, 2);
 
-        println!(
+        info!(
             "The bus {} has slot {} with device {:x} and vendor {:x}",
             bus, slot, device, vendor
         );
-        let tmp: Option<PCI00DeviceInfo> = get_00device_info(bus, slot);
-        if let Some(dev) = tmp {
-            dev.print_header();
-            println!("\n");
+        if dump == PciDump::Full {
+            let tmp: Option<PCI00DeviceInfo> = get_00device_info(bus, slot);
+            if let Some(dev) = tmp {
+                dev.print_header();
+            }
         }
     }
 
     vendor
 }
 
-pub fn check_all_buses() {
+pub fn check_all_buses(dump: PciDump) {
+    if dump == PciDump::Off {
+        return;
+    }
     for bus in 0..256 {
         for slot in 0..32 {
-            check_vendor(bus as u8, slot as u8);
+            check_vendor(bus as u8, slot as u8, dump);
         }
     }
 }
@@ -0,0 +1,267 @@
+use crate::acpi;
+use crate::acpi::ACPIHeader;
+use crate::config;
+use crate::config::CONFIG;
+use crate::info;
+use crate::ismutex::ISMutex;
+use crate::vmm;
+use crate::HEAP_SIZE;
+use alloc::vec::Vec;
+
+pub const FRAME_SIZE: u64 = 0x1000;
+
+/*
+ * A bitmap of every physical frame below CONFIG.high_phys_mem.
+ * A set bit means the frame is in use or unusable. The bitmap lives in
+ * physical memory it reserves for itself, so it can be built before the
+ * rest of the kernel needs frames.
+ */
+struct FrameAllocator {
+    bitmap: *mut u64,
+    words: usize,
+    /// Index of a word that may contain a free frame; every word below it is full.
+    hint: usize,
+    total_frames: u64,
+    free_frames: u64,
+}
+
+unsafe impl Send for FrameAllocator {}
+
+static FRAME_ALLOCATOR: ISMutex<FrameAllocator> = ISMutex::new(FrameAllocator {
+    bitmap: 0 as *mut u64,
+    words: 0,
+    hint: 0,
+    total_frames: 0,
+    free_frames: 0,
+});
+
+/// A snapshot of frame usage
+#[derive(Clone, Copy, Debug)]
+pub struct FrameStats {
+    /// Frames the memory map reports as available, minus those reserved at boot
+    pub total_frames: u64,
+    pub free_frames: u64,
+    pub used_frames: u64,
+}
+
+impl FrameAllocator {
+    fn words(&mut self) -> &mut [u64] {
+        unsafe { core::slice::from_raw_parts_mut(self.bitmap, self.words) }
+    }
+
+    fn is_used(&mut self, frame: usize) -> bool {
+        self.words()[frame / 64] & (1 << (frame % 64)) != 0
+    }
+
+    fn set_used(&mut self, frame: usize) {
+        self.words()[frame / 64] |= 1 << (frame % 64);
+    }
+
+    fn set_free(&mut self, frame: usize) {
+        self.words()[frame / 64] &= !(1 << (frame % 64));
+    }
+
+    /// Marks frames overlapping [start, end) as used
+    fn reserve(&mut self, start: u64, end: u64) {
+        let first = start / FRAME_SIZE;
+        let last = (end + FRAME_SIZE - 1) / FRAME_SIZE;
+        for frame in first..last {
+            if frame as usize >= self.words * 64 {
+                break;
+            }
+            if !self.is_used(frame as usize) {
+                self.set_used(frame as usize);
+                self.free_frames -= 1;
+                self.total_frames -= 1;
+            }
+        }
+    }
+
+    /// Marks frames entirely inside [start, end) as free
+    fn release(&mut self, start: u64, end: u64) {
+        let first = (start + FRAME_SIZE - 1) / FRAME_SIZE;
+        let last = end / FRAME_SIZE;
+        for frame in first..last {
+            if frame as usize >= self.words * 64 {
+                break;
+            }
+            if self.is_used(frame as usize) {
+                self.set_free(frame as usize);
+                self.free_frames += 1;
+                self.total_frames += 1;
+            }
+        }
+    }
+
+    fn alloc(&mut self) -> Option<u64> {
+        for word in self.hint..self.words {
+            let bits = self.words()[word];
+            if bits != u64::MAX {
+                let frame = word * 64 + (!bits).trailing_zeros() as usize;
+                self.set_used(frame);
+                self.free_frames -= 1;
+                self.hint = word;
+                return Some(frame as u64 * FRAME_SIZE);
+            }
+        }
+        None
+    }
+
+    fn alloc_contiguous(&mut self, count: usize) -> Option<u64> {
+        let mut run_start = self.hint * 64;
+        let mut run_length = 0;
+        for frame in self.hint * 64..self.words * 64 {
+            if self.is_used(frame) {
+                run_start = frame + 1;
+                run_length = 0;
+                continue;
+            }
+            run_length += 1;
+            if run_length == count {
+                for f in run_start..run_start + count {
+                    self.set_used(f);
+                }
+                self.free_frames -= count as u64;
+                return Some(run_start as u64 * FRAME_SIZE);
+            }
+        }
+        None
+    }
+
+    fn free(&mut self, addr: u64) {
+        let frame = (addr / FRAME_SIZE) as usize;
+        if addr % FRAME_SIZE != 0 || frame >= self.words * 64 {
+            panic!("Freeing invalid frame 0x{:x}", addr);
+        }
+        if !self.is_used(frame) {
+            panic!("Double free of frame 0x{:x}", addr);
+        }
+        self.set_free(frame);
+        self.free_frames += 1;
+        if frame / 64 < self.hint {
+            self.hint = frame / 64;
+        }
+    }
+}
+
+/// Physical ranges that must never be handed out, as [start, end) pairs
+fn reserved_ranges(kernel_end: u64) -> Vec<(u64, u64)> {
+    let mut reserved = Vec::new();
+    // Everything below the kernel image end, including the AP trampoline and the BIOS areas
+    reserved.push((0, kernel_end));
+    unsafe {
+        reserved.push((CONFIG.heap_start, CONFIG.heap_start + HEAP_SIZE as u64));
+        reserved.push((CONFIG.mb_info_start, CONFIG.mb_info_end));
+        for module in CONFIG.modules() {
+            reserved.push((module.start, module.end));
+        }
+        for table in acpi::tables() {
+            let table_addr = vmm::virt_to_phys(table as *const ACPIHeader as u64);
+            reserved.push((table_addr, table_addr + table.length() as u64));
+        }
+    }
+    reserved
+}
+
+/// Finds a page aligned range of size bytes in usable memory that avoids every reserved range
+fn find_bitmap_location(size: u64, reserved: &Vec<(u64, u64)>) -> u64 {
+    let memory_map = unsafe {
+        match config::MB_MEMORY_MAP {
+            Some(memory_map) => memory_map,
+            None => panic!("No memory map structure!"),
+        }
+    };
+    let mut entry = unsafe { memory_map.first_entry() };
+    for i in 0..memory_map.num_entries() {
+        if entry.mem_type == 1 {
+            let end = entry.base_addr + entry.length;
+            let mut candidate = align_up(entry.base_addr);
+            while candidate + size <= end {
+                match reserved
+                    .iter()
+                    .find(|(start, stop)| candidate < *stop && *start < candidate + size)
+                {
+                    Some((_, stop)) => candidate = align_up(*stop),
+                    None => return candidate,
+                }
+            }
+        }
+        if i != memory_map.num_entries() - 1 {
+            entry = entry.get_next(memory_map.entry_size as usize);
+        }
+    }
+    panic!("No room for the frame allocator bitmap");
+}
+
+fn align_up(addr: u64) -> u64 {
+    (addr + FRAME_SIZE - 1) / FRAME_SIZE * FRAME_SIZE
+}
+
+/// Builds the frame bitmap from the multiboot memory map.
+/// kernel_end is the physical end of the kernel image.
+/// Requires config::init and the kernel heap.
+pub fn init(kernel_end: u64) {
+    let total_frames = unsafe { CONFIG.high_phys_mem } / FRAME_SIZE;
+    let words = ((total_frames + 63) / 64) as usize;
+    let bitmap_size = (words * core::mem::size_of::<u64>()) as u64;
+    let mut reserved = reserved_ranges(kernel_end);
+    let bitmap_start = find_bitmap_location(bitmap_size, &reserved);
+    reserved.push((bitmap_start, bitmap_start + bitmap_size));
+
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    allocator.bitmap = vmm::phys_to_virt(bitmap_start) as *mut u64;
+    allocator.words = words;
+    for word in allocator.words().iter_mut() {
+        *word = u64::MAX;
+    }
+
+    let memory_map = unsafe { config::MB_MEMORY_MAP.unwrap() };
+    let mut entry = unsafe { memory_map.first_entry() };
+    for i in 0..memory_map.num_entries() {
+        if entry.mem_type == 1 {
+            allocator.release(entry.base_addr, entry.base_addr + entry.length);
+        }
+        if i != memory_map.num_entries() - 1 {
+            entry = entry.get_next(memory_map.entry_size as usize);
+        }
+    }
+    for (start, end) in reserved.iter() {
+        allocator.reserve(*start, *end);
+    }
+    info!(
+        "Frame allocator: bitmap at 0x{:x}, {} of {} frames free",
+        bitmap_start, allocator.free_frames, allocator.total_frames
+    );
+}
+
+/// Allocates a single frame and returns its physical address
+pub fn alloc_frame() -> Option<u64> {
+    FRAME_ALLOCATOR.lock().alloc()
+}
+
+/// Allocates count physically contiguous frames and returns the address of the first
+pub fn alloc_contiguous(count: usize) -> Option<u64> {
+    FRAME_ALLOCATOR.lock().alloc_contiguous(count)
+}
+
+/// Returns a frame obtained from alloc_frame or alloc_contiguous
+pub fn free_frame(addr: u64) {
+    FRAME_ALLOCATOR.lock().free(addr);
+}
+
+/// Returns count frames obtained from alloc_contiguous
+pub fn free_contiguous(addr: u64, count: usize) {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    for i in 0..count as u64 {
+        allocator.free(addr + i * FRAME_SIZE);
+    }
+}
+
+pub fn stats() -> FrameStats {
+    let allocator = FRAME_ALLOCATOR.lock();
+    FrameStats {
+        total_frames: allocator.total_frames,
+        free_frames: allocator.free_frames,
+        used_frames: allocator.total_frames - allocator.free_frames,
+    }
+}
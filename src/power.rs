@@ -0,0 +1,253 @@
+use crate::acpi;
+use crate::acpi::{Fadt, GenericAddress};
+use crate::machine;
+use crate::pci;
+use crate::u8250;
+use crate::vmm;
+use crate::{debug, error, info, warn};
+
+/*
+ * Turning the machine off and restarting it, through ACPI.
+ *
+ * Soft off is sleep state S5. The values to write to the PM1 control registers
+ * for it are in the \_S5 package of the DSDT, which is AML. A full interpreter
+ * is not needed: firmware declares it as a plain Name holding a Package of
+ * integers, so the bytes after the name are matched directly.
+ *
+ * Reboot writes the FADT's reset value to its reset register, which ACPI 2.0
+ * added. Without one, the keyboard controller can still pulse the reset line.
+ */
+
+/// Bits of the PM1 control registers
+const SCI_EN: u16 = 1 << 0;
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_TYP_MASK: u16 = 0b111 << SLP_TYP_SHIFT;
+const SLP_EN: u16 = 1 << 13;
+/// FADT flag set if the reset register is usable
+const RESET_REG_SUP: u32 = 1 << 10;
+/// reset_value is the last byte of the FADT field, at offset 128
+const RESET_VALUE_END: usize = 129;
+
+/// AML opcodes found in \_S5
+const NAME_OP: u8 = 0x08;
+const ROOT_PREFIX: u8 = b'\\';
+const PACKAGE_OP: u8 = 0x12;
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const BYTE_PREFIX: u8 = 0x0a;
+const WORD_PREFIX: u8 = 0x0b;
+
+/// The keyboard controller's status and command port
+const KBC_PORT: u32 = 0x64;
+const KBC_INPUT_FULL: u8 = 1 << 1;
+const KBC_PULSE_RESET: u32 = 0xfe;
+
+/// How long to wait for hardware to act before trying something else
+const SPIN_ITERATIONS: u64 = 10_000_000;
+
+/// The SLP_TYP values that select a sleep state, for PM1a and PM1b
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SleepType {
+    pub pm1a: u16,
+    pub pm1b: u16,
+}
+
+/// Only written by init, before other cores run
+static mut S5: Option<SleepType> = None;
+
+/// Reads an AML integer constant, returning it and the bytes after it
+fn aml_integer(aml: &[u8]) -> Option<(u16, &[u8])> {
+    match *aml.first()? {
+        ZERO_OP => Some((0, &aml[1..])),
+        ONE_OP => Some((1, &aml[1..])),
+        BYTE_PREFIX => Some((*aml.get(1)? as u16, aml.get(2..)?)),
+        WORD_PREFIX => {
+            let value = u16::from_le_bytes([*aml.get(1)?, *aml.get(2)?]);
+            Some((value, aml.get(3..)?))
+        }
+        _ => None,
+    }
+}
+
+/// Parses the package that follows the name \_S5 at offset at
+fn s5_package(aml: &[u8], at: usize) -> Option<SleepType> {
+    let named = match at {
+        0 => false,
+        1 => aml[0] == NAME_OP,
+        _ => aml[at - 1] == NAME_OP || (aml[at - 1] == ROOT_PREFIX && aml[at - 2] == NAME_OP),
+    };
+    let package = aml.get(at + 4..)?;
+    if !named || *package.first()? != PACKAGE_OP {
+        return None;
+    }
+    // The top two bits of PkgLength's lead byte count the bytes that follow it
+    let length_bytes = (*package.get(1)? >> 6) as usize + 1;
+    // Skip the opcode, PkgLength and NumElements
+    let elements = package.get(1 + length_bytes + 1..)?;
+    let (pm1a, rest) = aml_integer(elements)?;
+    let (pm1b, _) = aml_integer(rest)?;
+    Some(SleepType { pm1a, pm1b })
+}
+
+/// Finds the SLP_TYP values for S5 in a DSDT or SSDT's AML
+pub fn parse_s5(aml: &[u8]) -> Option<SleepType> {
+    aml.windows(4)
+        .enumerate()
+        .filter(|(_, name)| *name == b"_S5_")
+        .find_map(|(at, _)| s5_package(aml, at))
+}
+
+/// Finds how to enter S5. Requires acpi::init.
+pub fn init() {
+    let s5 = acpi::find_signature(b"DSDT").and_then(|dsdt| {
+        let body = &dsdt.bytes()[core::mem::size_of::<acpi::ACPIHeader>()..];
+        parse_s5(body)
+    });
+    match s5 {
+        Some(s5) => debug!("S5 is SLP_TYPa {} SLP_TYPb {}", s5.pm1a, s5.pm1b),
+        None => warn!("no \\_S5 in the DSDT, power::shutdown will only halt"),
+    }
+    unsafe {
+        S5 = s5;
+    }
+}
+
+/// The SLP_TYP values for soft off, if the firmware has them
+pub fn s5() -> Option<SleepType> {
+    unsafe { S5 }
+}
+
+fn spin() {
+    for _ in 0..SPIN_ITERATIONS {
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+/// Stops this core for good
+fn halt() -> ! {
+    loop {
+        unsafe {
+            machine::cli();
+            machine::hlt();
+        }
+    }
+}
+
+/// Switches the chipset from legacy to ACPI mode, if firmware left it in legacy mode
+fn enable_acpi(fadt: &Fadt) {
+    let pm1a = fadt.pm1a_control_block;
+    let smi_command_port = fadt.smi_command_port;
+    if unsafe { machine::inw(pm1a) } & SCI_EN != 0 {
+        return;
+    }
+    if smi_command_port == 0 || fadt.acpi_enable == 0 {
+        return;
+    }
+    unsafe {
+        machine::outb(smi_command_port, fadt.acpi_enable as u32);
+    }
+    for _ in 0..SPIN_ITERATIONS {
+        if unsafe { machine::inw(pm1a) } & SCI_EN != 0 {
+            return;
+        }
+        core::sync::atomic::spin_loop_hint();
+    }
+    warn!("the chipset did not enter ACPI mode");
+}
+
+/// Asks the PM1 control register at port to enter the sleep state slp_typ
+fn sleep(port: u32, slp_typ: u16) {
+    unsafe {
+        let control = machine::inw(port) & !(SLP_TYP_MASK | SLP_EN);
+        let value = control | ((slp_typ << SLP_TYP_SHIFT) & SLP_TYP_MASK) | SLP_EN;
+        machine::outw(port, value as u32);
+    }
+}
+
+/// Turns the machine off. Halts if ACPI cannot.
+pub fn shutdown() -> ! {
+    info!("shutting down");
+    // Queued output would be lost with the machine
+    u8250::flush();
+    machine::disable();
+    match (acpi::find::<Fadt>(), s5()) {
+        (Some(fadt), Some(s5)) if fadt.pm1a_control_block != 0 => {
+            enable_acpi(fadt);
+            let pm1b = fadt.pm1b_control_block;
+            if pm1b != 0 {
+                sleep(pm1b, s5.pm1b);
+            }
+            sleep(fadt.pm1a_control_block, s5.pm1a);
+            spin();
+            error!("the machine did not enter S5");
+        }
+        _ => error!("no ACPI soft off"),
+    }
+    u8250::flush();
+    halt();
+}
+
+/// Writes value to the register address describes.
+/// Returns false for address spaces that are not supported.
+fn write_register(address: &GenericAddress, value: u8) -> bool {
+    let paddr = address.address;
+    match address.address_space {
+        0 => unsafe {
+            core::ptr::write_volatile(vmm::map_mmio(paddr) as *mut u8, value);
+        },
+        1 => unsafe {
+            machine::outb(paddr as u32, value as u32);
+        },
+        // PCI configuration space of a device on bus 0
+        2 => pci::config_write8(
+            0,
+            (paddr >> 32) as u8,
+            (paddr >> 16) as u8,
+            paddr as u8,
+            value,
+        ),
+        _ => return false,
+    }
+    true
+}
+
+/// Resets through the FADT's reset register, if it has a usable one
+fn acpi_reset() {
+    let fadt = match acpi::find::<Fadt>() {
+        Some(fadt) => fadt,
+        None => return,
+    };
+    if !fadt.has(RESET_VALUE_END) || fadt.flags & RESET_REG_SUP == 0 {
+        return;
+    }
+    let reset_register = fadt.reset_register;
+    if write_register(&reset_register, fadt.reset_value) {
+        spin();
+        warn!("the ACPI reset register did not reset the machine");
+    }
+}
+
+/// Pulses the CPU reset line through the keyboard controller
+fn keyboard_controller_reset() {
+    unsafe {
+        for _ in 0..SPIN_ITERATIONS {
+            if machine::inb(KBC_PORT) & KBC_INPUT_FULL == 0 {
+                break;
+            }
+        }
+        machine::outb(KBC_PORT, KBC_PULSE_RESET);
+    }
+    spin();
+}
+
+/// Restarts the machine. Halts if nothing resets it.
+pub fn reboot() -> ! {
+    info!("rebooting");
+    u8250::flush();
+    machine::disable();
+    acpi_reset();
+    keyboard_controller_reset();
+    error!("the machine did not reset");
+    u8250::flush();
+    halt();
+}
@@ -0,0 +1,231 @@
+use crate::info;
+use crate::ismutex::ISMutex;
+use crate::machine;
+use crate::semaphore::Semaphore;
+use crate::thread;
+use crate::thread::TCBImpl;
+use crate::vmm;
+use crate::vmm::{AddressSpace, PageFlags, VmmError, PAGE_SIZE};
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicI64, AtomicU64, AtomicUsize, Ordering};
+
+/// Flat user images are linked to run at this address
+pub const USER_CODE_BASE: u64 = 0x40_0000;
+/// The first thread's stack ends here
+pub const USER_STACK_TOP: u64 = 0x7FFF_FFFF_F000;
+pub const USER_STACK_PAGES: u64 = 4;
+/// mmap hands out addresses upward from here
+pub const MMAP_BASE: u64 = 0x1000_0000_0000;
+
+static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+
+struct Memory {
+    address_space: &'static mut AddressSpace,
+    mmap_next: u64,
+}
+
+/// A user program: an address space, and the threads running in it
+pub struct Process {
+    pid: u64,
+    /// The same address space as memory holds, kept outside the lock so it can be loaded on every switch
+    root: *const AddressSpace,
+    memory: ISMutex<Memory>,
+    threads: AtomicUsize,
+    exit_code: AtomicI64,
+    exited: Arc<Semaphore>,
+}
+
+unsafe impl Send for Process {}
+unsafe impl Sync for Process {}
+
+impl Process {
+    pub fn new() -> Arc<Process> {
+        let address_space = AddressSpace::new_user();
+        Arc::new(Process {
+            pid: NEXT_PID.fetch_add(1, Ordering::SeqCst),
+            root: address_space as *const AddressSpace,
+            memory: ISMutex::new(Memory {
+                address_space: address_space,
+                mmap_next: MMAP_BASE,
+            }),
+            threads: AtomicUsize::new(0),
+            exit_code: AtomicI64::new(0),
+            exited: Semaphore::new(0),
+        })
+    }
+
+    pub fn pid(&self) -> u64 {
+        self.pid
+    }
+
+    /// Loads this process's address space on the calling core
+    pub fn load_address_space(&self) {
+        unsafe { (*self.root).load() }
+    }
+
+    /// Maps pages fresh zeroed frames starting at start
+    fn map_pages(
+        memory: &mut Memory,
+        start: u64,
+        pages: u64,
+        flags: PageFlags,
+    ) -> Result<(), VmmError> {
+        for i in 0..pages {
+            let frame = vmm::try_alloc().ok_or(VmmError::OutOfMemory)?;
+            if let Err(error) = memory
+                .address_space
+                .map(start + i * PAGE_SIZE, frame, flags)
+            {
+                vmm::free(frame);
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    /// Maps pages of zeroed memory starting at the page aligned address start
+    pub fn map_zeroed(&self, start: u64, pages: u64, flags: PageFlags) -> Result<(), VmmError> {
+        Process::map_pages(&mut self.memory.lock(), start, pages, flags)
+    }
+
+    /// Replaces the flags of the page containing vaddr
+    pub fn protect(&self, vaddr: u64, flags: PageFlags) -> Result<(), VmmError> {
+        self.memory.lock().address_space.protect(vaddr, flags)
+    }
+
+    /// Copies bytes to vaddr in this process, which does not need to be the running one.
+    /// The destination must already be mapped.
+    pub fn copy_to_user(&self, vaddr: u64, bytes: &[u8]) -> Result<(), VmmError> {
+        let mut memory = self.memory.lock();
+        let mut copied = 0;
+        while copied < bytes.len() {
+            let address = vaddr + copied as u64;
+            let frame = match memory.address_space.translate(address) {
+                Some(frame) => frame,
+                None => return Err(VmmError::NotMapped),
+            };
+            let length = core::cmp::min(
+                (PAGE_SIZE - address % PAGE_SIZE) as usize,
+                bytes.len() - copied,
+            );
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    bytes[copied..].as_ptr(),
+                    vmm::phys_to_virt(frame) as *mut u8,
+                    length,
+                );
+            }
+            copied += length;
+        }
+        Ok(())
+    }
+
+    /// Copies a flat binary to USER_CODE_BASE and returns its entry point
+    pub fn load_flat(&self, image: &[u8]) -> Result<u64, VmmError> {
+        let pages = (image.len() as u64 + PAGE_SIZE - 1) / PAGE_SIZE;
+        // Never writable, so it can be executable
+        self.map_zeroed(USER_CODE_BASE, pages, PageFlags::USER)?;
+        self.copy_to_user(USER_CODE_BASE, image)?;
+        Ok(USER_CODE_BASE)
+    }
+
+    /// Maps the first thread's stack and returns its top
+    pub fn map_stack(&self) -> Result<u64, VmmError> {
+        let mut memory = self.memory.lock();
+        Process::map_pages(
+            &mut memory,
+            USER_STACK_TOP - USER_STACK_PAGES * PAGE_SIZE,
+            USER_STACK_PAGES,
+            PageFlags::USER | PageFlags::WRITABLE | PageFlags::NO_EXECUTE,
+        )?;
+        Ok(USER_STACK_TOP)
+    }
+
+    /// Maps at least length bytes of zeroed memory and returns its address
+    pub fn mmap(&self, length: u64) -> Result<u64, VmmError> {
+        let pages = (length + PAGE_SIZE - 1) / PAGE_SIZE;
+        let mut memory = self.memory.lock();
+        let start = memory.mmap_next;
+        Process::map_pages(
+            &mut memory,
+            start,
+            pages,
+            PageFlags::USER | PageFlags::WRITABLE | PageFlags::NO_EXECUTE,
+        )?;
+        memory.mmap_next = start + pages * PAGE_SIZE;
+        Ok(start)
+    }
+
+    /// Returns true if every byte of [start, start + length) is in the user half, on pages
+    /// mapped with at least flags. Buffers from user mode need PageFlags::USER, and
+    /// PageFlags::WRITABLE too if the kernel will write them.
+    pub fn can_access(&self, start: u64, length: u64, flags: PageFlags) -> bool {
+        let end = match start.checked_add(length) {
+            Some(end) if end <= vmm::USER_END => end,
+            _ => return false,
+        };
+        let mut memory = self.memory.lock();
+        let mut page = start / PAGE_SIZE * PAGE_SIZE;
+        while page < end {
+            match memory.address_space.flags(page) {
+                Some(page_flags) if page_flags.contains(flags) => {}
+                _ => return false,
+            }
+            page += PAGE_SIZE;
+        }
+        true
+    }
+
+    /// Starts a thread that enters user mode at entry with the given stack
+    pub fn spawn(self: &Arc<Process>, entry: u64, stack_top: u64) {
+        self.threads.fetch_add(1, Ordering::SeqCst);
+        let thread = TCBImpl::with_process(
+            box move || unsafe { machine::enter_user(entry, stack_top) },
+            Arc::clone(self),
+        );
+        thread::schedule(box thread);
+    }
+
+    /// Records the exit of one of this process's threads.
+    /// The exit code of the process is the one passed by its last thread.
+    pub fn exit(&self, code: i64) {
+        self.exit_code.store(code, Ordering::SeqCst);
+        if self.threads.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.exited.up();
+        }
+    }
+
+    /// Blocks until every thread of the process has exited, and returns the exit code
+    pub fn wait(&self) -> i64 {
+        self.exited.down();
+        self.exit_code.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let mut memory = self.memory.lock();
+        memory.address_space.free_user_mappings();
+        vmm::free(vmm::virt_to_phys(self.root as u64));
+    }
+}
+
+/// Creates a process running a flat binary linked at USER_CODE_BASE
+pub fn spawn_flat(image: &[u8]) -> Result<Arc<Process>, VmmError> {
+    let process = Process::new();
+    let entry = process.load_flat(image)?;
+    let stack_top = process.map_stack()?;
+    info!("Starting process {}", process.pid());
+    process.spawn(entry, stack_top);
+    Ok(process)
+}
+
+/// Exits the calling thread on behalf of its process
+pub fn exit_current(code: i64) -> ! {
+    match thread::current_process() {
+        Some(process) => process.exit(code),
+        None => panic!("exit_current called from a kernel thread"),
+    }
+    thread::stop();
+    loop {}
+}
@@ -0,0 +1,211 @@
+use crate::boot_options::SchedPolicy;
+use crate::thread::{ThreadId, ThreadInfo, TCB};
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+
+/*
+ * Scheduling policies. A Scheduler holds the threads that are ready to run,
+ * picks which one a core runs next, and decides when the running thread
+ * should give its core to one of them.
+ *
+ * All cores share one Scheduler, which thread keeps behind a lock, so the
+ * policies need no locking of their own. sched= on the command line picks one:
+ *   rr        round robin, in the order threads became ready
+ *   priority  the highest priority first, round robin within a priority
+ *   fair      the least CPU time first, scaled by priority
+ */
+
+/// Priorities run from 0 to PRIORITY_LEVELS - 1. Higher runs first.
+pub const PRIORITY_LEVELS: usize = 8;
+pub const DEFAULT_PRIORITY: u8 = 3;
+pub const MAX_PRIORITY: u8 = PRIORITY_LEVELS as u8 - 1;
+
+pub trait Scheduler: Send {
+    fn name(&self) -> &'static str;
+
+    /// Adds a thread that is ready to run
+    fn push(&mut self, tcb: Box<dyn TCB>);
+
+    /// Removes the thread to run next
+    fn pop(&mut self) -> Option<Box<dyn TCB>>;
+
+    /// Removes the ready thread with id, e.g. to push it again after its priority changes
+    fn remove(&mut self, id: ThreadId) -> Option<Box<dyn TCB>>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether running should give its core to a ready thread.
+    /// slice_over is set when its time slice has ended, or it offered to yield.
+    fn should_preempt(&self, _running: &ThreadInfo, slice_over: bool) -> bool {
+        slice_over && !self.is_empty()
+    }
+}
+
+/// Creates an empty scheduler that follows policy
+pub fn new(policy: SchedPolicy) -> Box<dyn Scheduler> {
+    match policy {
+        SchedPolicy::RoundRobin => Box::new(RoundRobin::new()),
+        SchedPolicy::Priority => Box::new(Priority::new()),
+        SchedPolicy::FairShare => Box::new(FairShare::new()),
+    }
+}
+
+/// Runs threads in the order they became ready, each for a time slice
+pub struct RoundRobin {
+    ready: VecDeque<Box<dyn TCB>>,
+}
+
+impl RoundRobin {
+    pub fn new() -> RoundRobin {
+        RoundRobin {
+            ready: VecDeque::new(),
+        }
+    }
+}
+
+impl Scheduler for RoundRobin {
+    fn name(&self) -> &'static str {
+        "round robin"
+    }
+
+    fn push(&mut self, tcb: Box<dyn TCB>) {
+        self.ready.push_back(tcb);
+    }
+
+    fn pop(&mut self) -> Option<Box<dyn TCB>> {
+        self.ready.pop_front()
+    }
+
+    fn remove(&mut self, id: ThreadId) -> Option<Box<dyn TCB>> {
+        let index = self.ready.iter().position(|tcb| tcb.thread().id() == id)?;
+        self.ready.remove(index)
+    }
+
+    fn len(&self) -> usize {
+        self.ready.len()
+    }
+}
+
+/// Static priorities, with a run queue for each. A thread only runs when no
+/// thread of a higher priority is ready, and takes the core from a lower one
+/// as soon as it is ready. Threads of the same priority take turns.
+pub struct Priority {
+    queues: [VecDeque<Box<dyn TCB>>; PRIORITY_LEVELS],
+}
+
+impl Priority {
+    pub fn new() -> Priority {
+        Priority {
+            queues: Default::default(),
+        }
+    }
+
+    /// The highest priority with a ready thread
+    fn highest(&self) -> Option<u8> {
+        self.queues
+            .iter()
+            .rposition(|queue| !queue.is_empty())
+            .map(|priority| priority as u8)
+    }
+}
+
+impl Scheduler for Priority {
+    fn name(&self) -> &'static str {
+        "priority"
+    }
+
+    fn push(&mut self, tcb: Box<dyn TCB>) {
+        let priority = tcb.thread().priority() as usize;
+        self.queues[priority].push_back(tcb);
+    }
+
+    fn pop(&mut self) -> Option<Box<dyn TCB>> {
+        let priority = self.highest()? as usize;
+        self.queues[priority].pop_front()
+    }
+
+    fn remove(&mut self, id: ThreadId) -> Option<Box<dyn TCB>> {
+        for queue in self.queues.iter_mut() {
+            if let Some(index) = queue.iter().position(|tcb| tcb.thread().id() == id) {
+                return queue.remove(index);
+            }
+        }
+        None
+    }
+
+    fn len(&self) -> usize {
+        self.queues.iter().map(|queue| queue.len()).sum()
+    }
+
+    fn should_preempt(&self, running: &ThreadInfo, slice_over: bool) -> bool {
+        match self.highest() {
+            Some(highest) if slice_over => highest >= running.priority(),
+            Some(highest) => highest > running.priority(),
+            None => false,
+        }
+    }
+}
+
+/// Runs the ready thread that has had the least CPU time, so busy threads
+/// share cores evenly. Priority scales how fast a thread's time counts:
+/// a thread one level up gets a bigger share, but never all of it.
+pub struct FairShare {
+    /// Keyed by virtual time, then by arrival so ties run in order
+    ready: BTreeMap<(u64, u64), Box<dyn TCB>>,
+    arrivals: u64,
+    /// The virtual time of the last thread picked. A thread that slept for
+    /// a long time starts from here, rather than owning the cores until it
+    /// catches up.
+    floor: u64,
+}
+
+impl FairShare {
+    pub fn new() -> FairShare {
+        FairShare {
+            ready: BTreeMap::new(),
+            arrivals: 0,
+            floor: 0,
+        }
+    }
+
+    /// CPU time, scaled so that higher priorities age more slowly
+    fn virtual_time(thread: &ThreadInfo) -> u64 {
+        let weight = thread.priority() as u64 + 1;
+        thread.cpu_time() * (DEFAULT_PRIORITY as u64 + 1) / weight
+    }
+}
+
+impl Scheduler for FairShare {
+    fn name(&self) -> &'static str {
+        "fair share"
+    }
+
+    fn push(&mut self, tcb: Box<dyn TCB>) {
+        let virtual_time = FairShare::virtual_time(tcb.thread()).max(self.floor);
+        self.arrivals += 1;
+        self.ready.insert((virtual_time, self.arrivals), tcb);
+    }
+
+    fn pop(&mut self) -> Option<Box<dyn TCB>> {
+        let key = *self.ready.keys().next()?;
+        self.floor = self.floor.max(key.0);
+        self.ready.remove(&key)
+    }
+
+    fn remove(&mut self, id: ThreadId) -> Option<Box<dyn TCB>> {
+        let key = *self
+            .ready
+            .iter()
+            .find(|(_, tcb)| tcb.thread().id() == id)?
+            .0;
+        self.ready.remove(&key)
+    }
+
+    fn len(&self) -> usize {
+        self.ready.len()
+    }
+}
@@ -1,16 +1,22 @@
+use crate::clock;
 use crate::machine;
-use crate::println;
 use crate::smp;
 use crate::spinlock::SpinLock;
 use crate::thread;
-use crate::thread::{CLEANUP, READY, TCB};
+use crate::thread::{ThreadState, CLEANUP, TCB};
+use crate::timer;
+use crate::timer::TimerId;
+use crate::trace;
 use alloc::boxed::Box;
 use alloc::collections::VecDeque;
 use alloc::sync::{Arc, Weak};
 use core::cell::UnsafeCell;
-use core::sync::atomic::{AtomicU64, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
 use spin::{Mutex, MutexGuard};
 
+static NEXT_WAITER: AtomicU64 = AtomicU64::new(0);
+
 /// A universal synchronization primitive. Blocks if count == 0.
 pub struct Semaphore {
     control: SpinLock,
@@ -32,32 +38,64 @@ impl Semaphore {
     }
 
     pub fn up(&self) {
-        println!("acquiring lock");
+        trace!("acquiring lock");
         let was = self.control.lock();
-        println!("acquired lock");
+        trace!("acquired lock");
         let internals = self.internals.data.get();
-        unsafe {
+        let woke = unsafe {
             match (*internals).blocked.pop_front() {
-                Some(tcb) => {
-                    READY.lock().push_back(tcb);
+                Some(waiter) => {
+                    // Its timeout would otherwise keep the semaphore alive until the deadline
+                    if let Some(timeout) = waiter.timeout {
+                        timer::cancel(timeout);
+                    }
+                    waiter.woken.store(true, Ordering::SeqCst);
+                    thread::schedule(waiter.tcb);
+                    true
+                }
+                None => {
+                    (*internals).count += 1;
+                    false
                 }
-                None => (*internals).count += 1,
             }
-        }
+        };
         self.control.unlock(was);
+        // The thread it woke may outrank the one running
+        if woke {
+            thread::maybe_preempt();
+        }
     }
 
     pub fn down(&self) {
+        self.down_until(None);
+    }
+
+    /// Like down, but gives up once timeout has passed.
+    /// Returns true if it took one from the count, and false if it timed out.
+    pub fn down_timeout(&self, timeout: Duration) -> bool {
+        self.down_until(Some(clock::now() + timeout.as_nanos() as u64))
+    }
+
+    /// Blocks until the count is above 0 or the clock passes deadline
+    fn down_until(&self, deadline: Option<u64>) -> bool {
         let was = self.control.lock();
         let mut internals = unsafe { Box::from_raw(self.internals.data.get()) };
         let count = unsafe { ((*internals).count) };
         if (count == 0) {
+            if let Some(deadline) = deadline {
+                if clock::now() >= deadline {
+                    let ptr = Box::into_raw(internals);
+                    self.control.unlock(was);
+                    return false;
+                }
+            }
             // Block
             let mut active = match thread::swap_active(None) {
                 Some(tcb) => tcb,
                 None => panic!("Called down on semaphore with no active thread."),
             };
             let current_state = active.get_info();
+            active.thread().set_state(ThreadState::Blocked);
             let me: Arc<Semaphore> = match internals.weak_self {
                 Some(ref ptr) => match ptr.upgrade() {
                     Some(ptr) => ptr,
@@ -65,23 +103,75 @@ impl Semaphore {
                 },
                 None => panic!("No weak pointer"),
             };
+            let id = NEXT_WAITER.fetch_add(1, Ordering::SeqCst);
+            let woken = Arc::new(AtomicBool::new(false));
+            let mut waiter = Waiter {
+                id: id,
+                tcb: active,
+                woken: woken.clone(),
+                timeout: None,
+            };
             let add_to_blocked_queue = move || {
+                // Set while control is held, so the waiter is queued before the timeout
+                // can look for it, and up always finds the timeout to cancel
+                if let Some(deadline) = deadline {
+                    let sem = Arc::clone(&me);
+                    waiter.timeout = Some(timer::at(deadline, move || sem.time_out(id)));
+                }
                 // Move internals ownership to lambda and release lock
-                internals.blocked.push_back(active);
+                internals.blocked.push_back(waiter);
                 let ptr = Box::into_raw(internals);
                 me.control.unlock(true);
             };
             CLEANUP[smp::me()].lock().add_task(box add_to_blocked_queue);
-            machine::enable(was);
+            // Interrupts stay disabled while control is held, so a timeout on this core
+            // cannot spin on it
             thread::block(current_state);
+            machine::enable(was);
+            woken.load(Ordering::SeqCst)
         } else {
             unsafe {
                 internals.count -= 1;
                 let ptr = Box::into_raw(internals);
             }
             self.control.unlock(was);
+            true
         }
     }
+
+    /// Readies the waiter with id if up has not already
+    fn time_out(&self, id: u64) {
+        let was = self.control.lock();
+        let internals = self.internals.data.get();
+        let waiter = unsafe {
+            let blocked = &mut (*internals).blocked;
+            match blocked.iter().position(|waiter| waiter.id == id) {
+                Some(index) => blocked.remove(index),
+                None => None,
+            }
+        };
+        if let Some(waiter) = waiter {
+            thread::schedule(waiter.tcb);
+        }
+        self.control.unlock(was);
+    }
+
+    /// Takes one from the count if it is not 0, without blocking.
+    /// Returns whether it did.
+    pub fn try_down(&self) -> bool {
+        let was = self.control.lock();
+        let internals = self.internals.data.get();
+        let taken = unsafe {
+            if (*internals).count > 0 {
+                (*internals).count -= 1;
+                true
+            } else {
+                false
+            }
+        };
+        self.control.unlock(was);
+        taken
+    }
 }
 
 /// Thread-safe as mutual exclusion is provided through holding the control semaphore
@@ -100,9 +190,20 @@ impl SemaphoreInternalWrapper {
 unsafe impl core::marker::Sync for SemaphoreInternalWrapper {}
 unsafe impl core::marker::Send for SemaphoreInternalWrapper {}
 
+/// A thread blocked in down
+struct Waiter {
+    /// Identifies the waiter to its timeout
+    id: u64,
+    tcb: Box<dyn TCB>,
+    /// Set by up when it hands this waiter the count
+    woken: Arc<AtomicBool>,
+    /// The timer that gives up on the wait, cancelled by up
+    timeout: Option<TimerId>,
+}
+
 struct SemaphoreInternals {
     count: u64,
-    blocked: VecDeque<Box<dyn TCB>>,
+    blocked: VecDeque<Waiter>,
     weak_self: Option<Weak<Semaphore>>,
 }
 
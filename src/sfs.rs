@@ -1,5 +1,5 @@
 use crate::ide::{IDEImpl, IDE};
-use crate::{panic, println};
+use crate::{debug, info, panic, warn};
 
 use alloc::{boxed::Box, vec, vec::Vec};
 
@@ -20,18 +20,18 @@ struct SuperBlock {
 
 impl SuperBlock {
     fn print(&self) {
-        println!("Timestamp: 0x{:x}", self.timestamp);
-        println!("Date area size: {} blocks", self.data_area_size);
-        println!("Index area size: {} bytes", self.index_area_size);
-        println!(
+        info!("Timestamp: 0x{:x}", self.timestamp);
+        info!("Date area size: {} blocks", self.data_area_size);
+        info!("Index area size: {} bytes", self.index_area_size);
+        info!(
             "Magic number: 0x{:x}",
             self.magicnum_and_sfs_version & 0x00FFFFFF
         );
-        println!("SFS version: {}", self.magicnum_and_sfs_version >> 24);
-        println!("Total blocks: {}", self.total_blocks);
-        println!("Reserved blocks: {}", self.reserved_block);
-        println!("Block size: {} bytes", self.block_size_bytes());
-        println!("Checksum: 0x{:x}", self.checksum);
+        info!("SFS version: {}", self.magicnum_and_sfs_version >> 24);
+        info!("Total blocks: {}", self.total_blocks);
+        info!("Reserved blocks: {}", self.reserved_block);
+        info!("Block size: {} bytes", self.block_size_bytes());
+        info!("Checksum: 0x{:x}", self.checksum);
     }
 
     fn block_size_bytes(&self) -> u64 {
@@ -85,7 +85,7 @@ impl SFS {
         if filename_u8.len() <= 30 {
             match self.get_file_entry(filename) {
                 Ok((file_entry, pos)) => {
-                    println!("File already exists");
+                    warn!("File already exists");
                 }
                 Err(e) => {
                     let mut filename_padded: [u8; 30] = [0; 30];
@@ -132,7 +132,7 @@ impl SFS {
                         let appendLocation = self.super_block.data_start_location()
                             + (file_entry.starting_block * self.super_block.block_size_bytes())
                             + file_entry.length;
-                        println!("Append Location: {}", appendLocation);
+                        debug!("Append Location: {}", appendLocation);
                         self.ide
                             .write(appendLocation as u32, content, content.len() as u32 * 4);
                         self.update_file_length(
@@ -144,7 +144,7 @@ impl SFS {
                     }
                 }
                 Err(e) => {
-                    println!("File doesnt exist");
+                    warn!("File doesnt exist");
                 }
             };
         } else {
@@ -177,7 +177,7 @@ impl SFS {
                         );
                         file_data.append(&mut buf);
                     }
-                    file_data.resize(file_entry.length as usize / 4, 0);
+                    file_data.resize((file_entry.length as usize + 3) / 4, 0);
                     Ok(file_data)
                 }
                 Err(e) => Err("File doesnt exist"),
@@ -187,9 +187,21 @@ impl SFS {
         }
     }
 
+    /// Reads a whole file, without the padding read_file adds to fill the last word
+    pub fn read_file_bytes(&mut self, filename: &str) -> Result<Vec<u8>, &str> {
+        let length = match self.get_file_entry(filename) {
+            Ok((file_entry, _)) => file_entry.length as usize,
+            Err(_) => return Err("File doesnt exist"),
+        };
+        let mut words = self.read_file(filename)?;
+        let mut bytes = u32_as_u8_mut(&mut words).to_vec();
+        bytes.truncate(length);
+        Ok(bytes)
+    }
+
     pub fn print_super_block(&self) {
         self.super_block.print();
-        println!("{}", self.super_block.index_start_location());
+        debug!("{}", self.super_block.index_start_location());
     }
 
     fn get_file_entry(&self, filename: &str) -> Result<(FileEntry, u64), &str> {
@@ -212,7 +224,7 @@ impl SFS {
                         );
                     }
                     if file_entry_slice[0].filename == filename_padded {
-                        println!("File Found!");
+                        debug!("File Found!");
                         return Ok((
                             FileEntry::new(
                                 file_entry_slice[0].filename,
@@ -1,7 +1,9 @@
 use crate::{apic::Apic, config::CONFIG};
 use crate::machine;
 use crate::println;
-use core::sync::atomic::Ordering;
+use crate::vmm;
+use crate::warn;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 use core::{ops::Range, sync::atomic::AtomicPtr};
 use x86_64::instructions::port::{self, Port};
 
@@ -9,6 +11,20 @@ use x86_64::instructions::port::{self, Port};
 pub static mut LAPIC: Option<SMP> = None;
 pub static mut APIC: Option<Apic> = None;
 
+const OFFLINE: AtomicBool = AtomicBool::new(false);
+/// ONLINE[i] is set once core i can receive IPIs
+static ONLINE: [AtomicBool; MAX_CORES] = [OFFLINE; MAX_CORES];
+
+/// Where the local APIC is until the MADT says otherwise
+const LAPIC_BASE_DEFAULT: u64 = 0xfee00000;
+const LAPIC_ID_REGISTER: u64 = 0x20;
+/// The highest ID a local APIC can have in xAPIC mode, as 0xff is the broadcast ID
+pub const MAX_XAPIC_ID: u32 = 0xfe;
+const NO_CORE: u8 = 0xff;
+const UNKNOWN_APIC_ID: AtomicU8 = AtomicU8::new(NO_CORE);
+/// The logical core number of each xAPIC ID, from the topology
+static CORE_OF_APIC_ID: [AtomicU8; 256] = [UNKNOWN_APIC_ID; 256];
+
 pub struct SMP {
     id: *mut u32,
     spurious: *mut u32,
@@ -26,7 +42,8 @@ impl SMP {
     const ISBSP: u32 = 1 << 8;
     const MSR: u32 = 0x1B;
 
-    pub fn new(lapic_base: u32) -> SMP {
+    pub fn new(lapic_base: u64) -> SMP {
+        let lapic_base = vmm::phys_to_virt(lapic_base);
         SMP {
             id: (lapic_base + 0x20) as *mut u32,
             eoi_reg: (lapic_base + 0xb0) as *mut u32,
@@ -41,16 +58,52 @@ impl SMP {
     }
 }
 
+/// Requires config::init, which finds the topology
 pub fn init_bsp() {
     unsafe {
         LAPIC = Some(SMP::new(CONFIG.local_apic));
+        for (core, cpu) in CONFIG.topology.cpus().iter().enumerate() {
+            // config leaves out IDs above MAX_XAPIC_ID, but a bad one must not stop the boot
+            match CORE_OF_APIC_ID.get(cpu.apic_id as usize) {
+                Some(slot) => slot.store(core as u8, Ordering::SeqCst),
+                None => warn!("core {} has APIC ID {}, out of range", core, cpu.apic_id),
+            }
+        }
     }
 }
 
 
-pub fn me() -> usize {
+/// Marks the calling core as able to receive IPIs.
+/// Call once its LAPIC is enabled and the IDT is loaded.
+pub fn mark_online() {
+    ONLINE[me()].store(true, Ordering::SeqCst);
+}
+
+pub fn is_online(core: usize) -> bool {
+    ONLINE[core].load(Ordering::SeqCst)
+}
+
+/// The number of entries in per-core arrays
+pub const MAX_CORES: usize = 16;
+
+/// The ID of the calling core's local APIC
+pub fn apic_id() -> u32 {
+    let base = match unsafe { CONFIG.local_apic } {
+        0 => LAPIC_BASE_DEFAULT,
+        base => base,
+    };
     unsafe {
-        let result = core::ptr::read_volatile(0xfee00020 as *const u32);
-        (result >> 24) as usize
+        let result =
+            core::ptr::read_volatile(vmm::phys_to_virt(base + LAPIC_ID_REGISTER) as *const u32);
+        result >> 24
+    }
+}
+
+/// The logical number of the calling core, which indexes every per-core array
+pub fn me() -> usize {
+    match CORE_OF_APIC_ID[apic_id() as usize].load(Ordering::Relaxed) {
+        // Only the bootstrap core runs before init_bsp, and it is core 0
+        NO_CORE => 0,
+        core => core as usize,
     }
 }
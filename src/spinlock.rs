@@ -20,6 +20,17 @@ impl SpinLock {
         }
         was
     }
+    /// Attempts to take the lock without spinning.
+    /// Returns the previous interrupt state if the lock was acquired.
+    pub fn try_lock(&self) -> Option<bool> {
+        let was = machine::disable();
+        if self.taken.swap(true, Ordering::SeqCst) {
+            machine::enable(was);
+            None
+        } else {
+            Some(was)
+        }
+    }
     pub fn unlock(&self, was: bool) {
         self.taken.swap(false, Ordering::SeqCst);
         machine::enable(was);
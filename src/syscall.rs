@@ -0,0 +1,200 @@
+use crate::debug;
+use crate::gdt;
+use crate::machine;
+use crate::pmm;
+use crate::process;
+use crate::process::Process;
+use crate::smp;
+use crate::thread;
+use crate::timer;
+use crate::u8250;
+use crate::vmm::{PageFlags, PAGE_SIZE};
+use alloc::sync::Arc;
+use x86_64::VirtAddr;
+
+const EFER_MSR: u32 = 0xC0000080;
+const EFER_SCE: u64 = 1;
+const STAR_MSR: u32 = 0xC0000081;
+const LSTAR_MSR: u32 = 0xC0000082;
+const FMASK_MSR: u32 = 0xC0000084;
+const KERNEL_GS_BASE_MSR: u32 = 0xC0000102;
+/// RFLAGS bits cleared on entry: TF, IF and DF
+const FMASK: u64 = (1 << 8) | (1 << 9) | (1 << 10);
+
+pub const SYS_EXIT: u64 = 0;
+pub const SYS_WRITE: u64 = 1;
+pub const SYS_READ: u64 = 2;
+pub const SYS_YIELD: u64 = 3;
+pub const SYS_SLEEP: u64 = 4;
+pub const SYS_MMAP: u64 = 5;
+pub const SYS_GETPID: u64 = 6;
+
+pub const STDIN: u64 = 0;
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
+
+/// Errors are returned to user mode as their negated value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallError {
+    BadFileDescriptor = 9,
+    OutOfMemory = 12,
+    BadAddress = 14,
+    InvalidArgument = 22,
+    NoSuchSyscall = 38,
+}
+
+/// Per-core state reached through KERNEL_GS_BASE by syscall_entry.
+/// The field offsets are hard coded there.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct CoreArea {
+    kernel_rsp: u64,
+    user_rsp: u64,
+}
+
+static mut CORE_AREA: [CoreArea; smp::MAX_CORES] = [CoreArea {
+    kernel_rsp: 0,
+    user_rsp: 0,
+}; smp::MAX_CORES];
+
+/// The registers syscall_entry passes to syscall_handler, in the System V syscall convention
+#[repr(C)]
+pub struct SyscallFrame {
+    pub number: u64,
+    pub arg0: u64,
+    pub arg1: u64,
+    pub arg2: u64,
+    pub arg3: u64,
+    pub arg4: u64,
+    pub arg5: u64,
+}
+
+type Syscall = fn(&SyscallFrame) -> Result<u64, SyscallError>;
+
+/// Indexed by syscall number
+static SYSCALLS: [Syscall; 7] = [
+    sys_exit, sys_write, sys_read, sys_yield, sys_sleep, sys_mmap, sys_getpid,
+];
+
+/// Enables the syscall instruction on the calling core. Requires gdt::init.
+pub fn init() {
+    let me = smp::me();
+    // sysret loads SS from STAR[63:48] + 8 and CS from STAR[63:48] + 16
+    let sysret_base = ((gdt::USER_DATA_SELECTOR & !3) - 8) as u64;
+    let star = (sysret_base << 48) | ((gdt::KERNEL_CODE_SELECTOR as u64) << 32);
+    unsafe {
+        machine::wrmsr(machine::rdmsr(EFER_MSR) | EFER_SCE, EFER_MSR);
+        machine::wrmsr(star, STAR_MSR);
+        machine::wrmsr(machine::syscall_entry as *const () as u64, LSTAR_MSR);
+        machine::wrmsr(FMASK, FMASK_MSR);
+        machine::wrmsr(&CORE_AREA[me] as *const CoreArea as u64, KERNEL_GS_BASE_MSR);
+    }
+    debug!("Core {}: syscalls enabled", me);
+}
+
+/// Sets the stack the calling core switches to when entering the kernel from user mode
+pub fn set_kernel_stack(top: u64) {
+    unsafe {
+        CORE_AREA[smp::me()].kernel_rsp = top;
+    }
+    if let Some(tss) = gdt::tss() {
+        tss.privilege_stack_table[0] = VirtAddr::new(top);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn syscall_handler(frame: &SyscallFrame) -> u64 {
+    let result = match SYSCALLS.get(frame.number as usize) {
+        Some(syscall) => syscall(frame),
+        None => Err(SyscallError::NoSuchSyscall),
+    };
+    match result {
+        Ok(value) => value,
+        Err(error) => -(error as i64) as u64,
+    }
+}
+
+fn current() -> Arc<Process> {
+    match thread::current_process() {
+        Some(process) => process,
+        None => panic!("syscall from a thread without a process"),
+    }
+}
+
+/// exit(code)
+fn sys_exit(frame: &SyscallFrame) -> Result<u64, SyscallError> {
+    process::exit_current(frame.arg0 as i64);
+}
+
+/// write(fd, buffer, length) returns the number of bytes written
+fn sys_write(frame: &SyscallFrame) -> Result<u64, SyscallError> {
+    if frame.arg0 != STDOUT && frame.arg0 != STDERR {
+        return Err(SyscallError::BadFileDescriptor);
+    }
+    if !current().can_access(frame.arg1, frame.arg2, PageFlags::USER) {
+        return Err(SyscallError::BadAddress);
+    }
+    let bytes =
+        unsafe { core::slice::from_raw_parts(frame.arg1 as *const u8, frame.arg2 as usize) };
+    u8250::write_bytes(bytes);
+    Ok(frame.arg2)
+}
+
+/// read(fd, buffer, length) waits for at least one byte, and returns the number of bytes read
+fn sys_read(frame: &SyscallFrame) -> Result<u64, SyscallError> {
+    if frame.arg0 != STDIN {
+        return Err(SyscallError::BadFileDescriptor);
+    }
+    let flags = PageFlags::USER | PageFlags::WRITABLE;
+    if !current().can_access(frame.arg1, frame.arg2, flags) {
+        return Err(SyscallError::BadAddress);
+    }
+    let buffer =
+        unsafe { core::slice::from_raw_parts_mut(frame.arg1 as *mut u8, frame.arg2 as usize) };
+    if buffer.is_empty() {
+        return Ok(0);
+    }
+    buffer[0] = u8250::get();
+    let mut count = 1;
+    while count < buffer.len() {
+        match u8250::try_get() {
+            Some(byte) => {
+                buffer[count] = byte;
+                count += 1;
+            }
+            None => break,
+        }
+    }
+    Ok(count as u64)
+}
+
+/// yield()
+fn sys_yield(_frame: &SyscallFrame) -> Result<u64, SyscallError> {
+    thread::surrender();
+    Ok(0)
+}
+
+/// sleep(ticks)
+fn sys_sleep(frame: &SyscallFrame) -> Result<u64, SyscallError> {
+    let until = timer::ticks().saturating_add(frame.arg0);
+    thread::sleep_until(until.saturating_mul(timer::quantum()));
+    Ok(0)
+}
+
+/// mmap(length) returns the address of length bytes of zeroed memory
+fn sys_mmap(frame: &SyscallFrame) -> Result<u64, SyscallError> {
+    if frame.arg0 == 0 {
+        return Err(SyscallError::InvalidArgument);
+    }
+    if frame.arg0 / PAGE_SIZE >= pmm::stats().free_frames {
+        return Err(SyscallError::OutOfMemory);
+    }
+    current()
+        .mmap(frame.arg0)
+        .map_err(|_| SyscallError::OutOfMemory)
+}
+
+/// getpid()
+fn sys_getpid(_frame: &SyscallFrame) -> Result<u64, SyscallError> {
+    Ok(current().pid())
+}
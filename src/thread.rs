@@ -1,42 +1,284 @@
+use crate::apic::Apic;
+use crate::backtrace;
+use crate::boot_options;
+use crate::clock;
+use crate::idt;
 use crate::machine;
-use crate::println;
 use crate::BoxedStack;
 use crate::Stack;
+use crate::{debug, info};
 use alloc::boxed::Box;
 
 use crate::config::CONFIG;
 use crate::ismutex::ISMutex;
+use crate::println;
+use crate::process::Process;
+use crate::scheduler;
+use crate::scheduler::{RoundRobin, Scheduler};
+use crate::semaphore::Semaphore;
 use crate::smp;
-use alloc::collections::VecDeque;
+use crate::syscall;
+use crate::timer;
+use crate::vmm;
+use crate::vmm::{KernelStack, PAGE_SIZE};
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::borrow::BorrowMut;
-use core::marker::{Send, Sync};
+use core::fmt;
+use core::marker::Send;
 use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use core::time::Duration;
 use spin::Mutex;
 
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
 lazy_static! {
-    pub static ref READY: ISMutex<VecDeque<Box<dyn TCB>>> = ISMutex::new(VecDeque::new());
+    /// Threads ready to run. Round robin until init reads the boot options.
+    static ref SCHEDULER: ISMutex<Box<dyn Scheduler>> = ISMutex::new(box RoundRobin::new());
 }
 
 lazy_static! {
     /// Invariant: When Active[i] == None, core i is guaranteed not to context switch due to a timer interrupt
-    pub static ref ACTIVE: [ISMutex<Option<Box<dyn TCB>>>; 16] = {
-        let mut active: [MaybeUninit<ISMutex<Option<Box<dyn TCB>>>>; 16] =
+    pub static ref ACTIVE: [ISMutex<Option<Box<dyn TCB>>>; smp::MAX_CORES] = {
+        let mut active: [MaybeUninit<ISMutex<Option<Box<dyn TCB>>>>; smp::MAX_CORES] =
             unsafe { MaybeUninit::uninit().assume_init() };
-        for i in 0..16 {
+        for i in 0..smp::MAX_CORES {
             active[i] = MaybeUninit::new(ISMutex::new(Some(BootstrapTCB::new_box())));
         }
-        unsafe { core::mem::transmute::<_, [ISMutex<Option<Box<dyn TCB>>>; 16]>(active) }
+        unsafe { core::mem::transmute::<_, [ISMutex<Option<Box<dyn TCB>>>; smp::MAX_CORES]>(active) }
     };
 }
 
 lazy_static! {
-    pub static ref CLEANUP: [ISMutex<Box<TaskHolder>>; 16] = {
-        let mut cleanup: [MaybeUninit<ISMutex<Box<TaskHolder>>>; 16] =
+    /// Threads waiting for a timer to wake them, by the token their timer carries
+    static ref SLEEPING: ISMutex<BTreeMap<u64, Box<dyn TCB>>> = ISMutex::new(BTreeMap::new());
+}
+
+static NEXT_SLEEPER: AtomicU64 = AtomicU64::new(0);
+
+static NEXT_THREAD_ID: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    /// Every thread that has not exited, by id
+    static ref REGISTRY: ISMutex<BTreeMap<ThreadId, Arc<ThreadInfo>>> = ISMutex::new(BTreeMap::new());
+}
+
+const NOT_RUNNING: ISMutex<Option<Arc<ThreadInfo>>> = ISMutex::new(None);
+/// The thread each core is running. Unlike ACTIVE, never empty while a core switches.
+static RUNNING: [ISMutex<Option<Arc<ThreadInfo>>>; smp::MAX_CORES] = [NOT_RUNNING; smp::MAX_CORES];
+
+const NO_TCB: ISMutex<Option<Box<dyn TCB>>> = ISMutex::new(None);
+/// Each core's idle thread, while it is not running. It runs when nothing else is ready,
+/// and never goes on the ready queue, so it stays on its core.
+static IDLE: [ISMutex<Option<Box<dyn TCB>>>; smp::MAX_CORES] = [NO_TCB; smp::MAX_CORES];
+const NO_IDLE: AtomicU64 = AtomicU64::new(u64::MAX);
+/// The id of each core's idle thread, once it has one
+static IDLE_IDS: [AtomicU64; smp::MAX_CORES] = [NO_IDLE; smp::MAX_CORES];
+const AWAKE: AtomicBool = AtomicBool::new(false);
+/// Set while a core's idle thread halts, or is about to, until schedule wakes it
+static HALTED: [AtomicBool; smp::MAX_CORES] = [AWAKE; smp::MAX_CORES];
+/// Sent to a halted core when there is a thread for it to run
+pub const WAKE_VECTOR: usize = 0xF1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(pub u64);
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ThreadState {
+    /// Waiting for a core
+    Ready,
+    Running,
+    /// Waiting on a semaphore
+    Blocked,
+    /// Waiting for a timer
+    Sleeping,
+    Exited,
+}
+
+impl ThreadState {
+    fn from_u8(state: u8) -> ThreadState {
+        match state {
+            0 => ThreadState::Ready,
+            1 => ThreadState::Running,
+            2 => ThreadState::Blocked,
+            3 => ThreadState::Sleeping,
+            _ => ThreadState::Exited,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ThreadState::Ready => "ready",
+            ThreadState::Running => "running",
+            ThreadState::Blocked => "blocked",
+            ThreadState::Sleeping => "sleeping",
+            ThreadState::Exited => "exited",
+        }
+    }
+}
+
+/// What can be known about a thread without owning its TCB.
+/// Shared between the TCB, the registry, and anyone listing threads.
+pub struct ThreadInfo {
+    id: ThreadId,
+    name: Option<String>,
+    state: AtomicU8,
+    /// The core it last ran on, or usize::MAX if it never ran
+    core: AtomicUsize,
+    /// Nanoseconds spent running, not counting the current run
+    cpu_time: AtomicU64,
+    /// When the current run started, on the clock
+    running_since: AtomicU64,
+    /// How many times a core switched to it
+    switches: AtomicU64,
+    /// The id of its kernel stack, for overflow reports
+    stack_id: Option<usize>,
+    priority: AtomicU8,
+    /// Where context_switch saved its registers while it is switched out, or 0
+    stack_pointer: AtomicUsize,
+}
+
+impl ThreadInfo {
+    fn new(name: Option<String>, stack_id: Option<usize>) -> Arc<ThreadInfo> {
+        Arc::new(ThreadInfo {
+            id: ThreadId(NEXT_THREAD_ID.fetch_add(1, Ordering::SeqCst)),
+            name: name,
+            state: AtomicU8::new(ThreadState::Ready as u8),
+            core: AtomicUsize::new(usize::MAX),
+            cpu_time: AtomicU64::new(0),
+            running_since: AtomicU64::new(0),
+            switches: AtomicU64::new(0),
+            stack_id: stack_id,
+            priority: AtomicU8::new(scheduler::DEFAULT_PRIORITY),
+            stack_pointer: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_ref().map(|name| name.as_str())
+    }
+
+    /// The stack pointer context_switch saved for the thread, if it is switched out.
+    /// Only stable while the thread stays off every core.
+    pub fn saved_stack_pointer(&self) -> Option<usize> {
+        match self.stack_pointer.load(Ordering::SeqCst) {
+            0 => None,
+            stack_pointer => Some(stack_pointer),
+        }
+    }
+
+    pub fn state(&self) -> ThreadState {
+        ThreadState::from_u8(self.state.load(Ordering::SeqCst))
+    }
+
+    fn set_state(&self, state: ThreadState) {
+        self.state.store(state as u8, Ordering::SeqCst);
+    }
+
+    /// The core the thread is running on, or last ran on
+    pub fn core(&self) -> Option<usize> {
+        match self.core.load(Ordering::SeqCst) {
+            usize::MAX => None,
+            core => Some(core),
+        }
+    }
+
+    /// Nanoseconds the thread has spent running, including the current run
+    pub fn cpu_time(&self) -> u64 {
+        let cpu_time = self.cpu_time.load(Ordering::SeqCst);
+        match self.state() {
+            ThreadState::Running => {
+                let since = self.running_since.load(Ordering::SeqCst);
+                cpu_time + clock::now().saturating_sub(since)
+            }
+            _ => cpu_time,
+        }
+    }
+
+    /// How many times the thread has been switched to
+    pub fn switches(&self) -> u64 {
+        self.switches.load(Ordering::SeqCst)
+    }
+
+    pub fn stack_id(&self) -> Option<usize> {
+        self.stack_id
+    }
+
+    pub fn priority(&self) -> u8 {
+        self.priority.load(Ordering::SeqCst)
+    }
+
+    /// Sets the priority, up to scheduler::MAX_PRIORITY. A ready thread moves to its
+    /// new place in the queue, and takes this core if it now outranks the running thread.
+    pub fn set_priority(&self, priority: u8) {
+        let priority = priority.min(scheduler::MAX_PRIORITY);
+        self.priority.store(priority, Ordering::SeqCst);
+        {
+            let mut scheduler = SCHEDULER.lock();
+            if let Some(tcb) = scheduler.remove(self.id) {
+                scheduler.push(tcb);
+            }
+        }
+        maybe_preempt();
+    }
+
+    fn start_running(&self, core: usize) {
+        self.core.store(core, Ordering::SeqCst);
+        self.running_since.store(clock::now(), Ordering::SeqCst);
+        self.switches.fetch_add(1, Ordering::SeqCst);
+        self.set_state(ThreadState::Running);
+    }
+
+    /// Called as the thread leaves its core. Its new state is set by whoever takes it off.
+    fn stop_running(&self) {
+        let since = self.running_since.load(Ordering::SeqCst);
+        self.cpu_time
+            .fetch_add(clock::now().saturating_sub(since), Ordering::SeqCst);
+    }
+
+    /// Marks the thread exited and removes it from the registry
+    fn exit(&self) {
+        self.set_state(ThreadState::Exited);
+        REGISTRY.lock().remove(&self.id);
+    }
+}
+
+impl fmt::Display for ThreadInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name() {
+            Some(name) => write!(f, "thread {} \"{}\"", self.id, name),
+            None => write!(f, "thread {}", self.id),
+        }
+    }
+}
+
+fn register(thread: &Arc<ThreadInfo>) {
+    REGISTRY.lock().insert(thread.id(), Arc::clone(thread));
+}
+
+lazy_static! {
+    pub static ref CLEANUP: [ISMutex<Box<TaskHolder>>; smp::MAX_CORES] = {
+        let mut cleanup: [MaybeUninit<ISMutex<Box<TaskHolder>>>; smp::MAX_CORES] =
             unsafe { MaybeUninit::uninit().assume_init() };
-        for i in 0..16 {
+        for i in 0..smp::MAX_CORES {
             cleanup[i] = MaybeUninit::new(ISMutex::new(box TaskHolder::new()));
         }
-        unsafe { core::mem::transmute::<_, [ISMutex<Box<TaskHolder>>; 16]>(cleanup) }
+        unsafe { core::mem::transmute::<_, [ISMutex<Box<TaskHolder>>; smp::MAX_CORES]>(cleanup) }
     };
 }
 
@@ -50,15 +292,30 @@ pub fn swap_active(swap_to: Option<Box<dyn TCB>>) -> Option<Box<dyn TCB>> {
     result
 }
 
-pub trait TCB: Send + Sync {
+pub trait TCB: Send {
     fn get_info(&mut self) -> *mut TCBInfo;
-    fn get_work(&mut self) -> Box<'static + FnOnce() + Send + Sync>;
+    fn get_work(&mut self) -> Box<Task>;
+
+    /// The top of the stack used when this thread enters the kernel from user mode
+    fn kernel_stack_top(&self) -> Option<u64> {
+        None
+    }
+
+    /// The process this thread runs user code for, if any
+    fn process(&self) -> Option<&Arc<Process>> {
+        None
+    }
+
+    /// The thread's id, name, state and statistics
+    fn thread(&self) -> &Arc<ThreadInfo>;
 }
 
 #[repr(C)]
 struct BootstrapTCB {
     tcb_info: TCBInfo,
     stack_frame_start: Option<usize>,
+    /// Registered once its core adopts it
+    thread: Arc<ThreadInfo>,
 }
 
 impl BootstrapTCB {
@@ -66,13 +323,11 @@ impl BootstrapTCB {
         BootstrapTCB {
             tcb_info: TCBInfo::new(0),
             stack_frame_start: None,
+            thread: ThreadInfo::new(Some(String::from("boot")), None),
         }
     }
     pub fn new_box() -> Box<BootstrapTCB> {
-        box BootstrapTCB {
-            tcb_info: TCBInfo::new(0),
-            stack_frame_start: None,
-        }
+        box BootstrapTCB::new()
     }
 }
 
@@ -84,50 +339,134 @@ impl TCB for BootstrapTCB {
     fn get_work(&mut self) -> Box<Task> {
         panic!("BootstrapTCB has no work to do!");
     }
+
+    fn thread(&self) -> &Arc<ThreadInfo> {
+        &self.thread
+    }
+}
+
+impl Drop for BootstrapTCB {
+    fn drop(&mut self) {
+        self.thread.exit();
+    }
 }
 
-type Task = 'static + FnOnce() + Send + Sync;
+type Task = 'static + FnOnce() + Send;
 
 #[repr(C)]
 pub struct TCBImpl {
     tcb_info: TCBInfo,
-    stack: Box<[u64]>,
+    stack: KernelStack,
     work: Option<Box<Task>>,
+    process: Option<Arc<Process>>,
+    /// Run when the thread is dropped
+    exit_hook: Option<Box<Cleanup>>,
+    thread: Arc<ThreadInfo>,
 }
 
 #[repr(C)]
-#[derive(Clone, Copy)]
 pub struct TCBInfo {
     stack_pointer: usize,
+    /// Set once context_switch has saved the thread's registers at stack_pointer,
+    /// cleared when it is switched back in
+    switched_out: AtomicBool,
 }
 
 impl TCBInfo {
     pub fn new(stack_pointer: usize) -> TCBInfo {
         TCBInfo {
             stack_pointer: stack_pointer,
+            switched_out: AtomicBool::new(false),
+        }
+    }
+
+    pub fn stack_pointer(&self) -> usize {
+        self.stack_pointer
+    }
+
+    /// The stack pointer context_switch saved for the thread, if it is switched out.
+    /// None before it first runs, as the frame it starts from is not a real switch.
+    pub fn saved_stack_pointer(&self) -> Option<usize> {
+        if self.switched_out.load(Ordering::SeqCst) {
+            Some(unsafe { core::ptr::read_volatile(&self.stack_pointer) })
+        } else {
+            None
         }
     }
 }
 
 impl TCBImpl {
     const NUM_CALLEE_SAVED: usize = 6;
+    pub const DEFAULT_STACK_SIZE: usize = 0x8000;
 
     pub fn new(work: Box<Task>) -> TCBImpl {
-        let mut stack: Box<[u64]> = box [0; 4096];
-        let end_of_stack = 4095;
-        stack[end_of_stack] = thread_entry_point as *const () as u64;
-        let index: usize = end_of_stack - TCBImpl::NUM_CALLEE_SAVED - 1;
-        stack[index] = 0; // Flags
-        stack[index - 1] = 0; // CR2
-        let stack_ptr = Box::into_raw(stack);
-        let stack_ptr_as_usize = stack_ptr as *mut u64 as usize;
-        stack = unsafe { Box::from_raw(stack_ptr) };
-        let stack_ptr_start = stack_ptr_as_usize + ((index - 1) * core::mem::size_of::<usize>());
+        TCBImpl::with_stack_size(work, TCBImpl::DEFAULT_STACK_SIZE)
+    }
+
+    /// Creates a thread that is called name in thread listings and reports
+    pub fn named(name: &str, work: Box<Task>) -> TCBImpl {
+        TCBImpl::create(work, TCBImpl::DEFAULT_STACK_SIZE, Some(String::from(name)))
+    }
+
+    /// Creates a thread whose stack holds at least stack_size bytes.
+    /// The stack is rounded up to whole pages and sits above an unmapped guard page.
+    pub fn with_stack_size(work: Box<Task>, stack_size: usize) -> TCBImpl {
+        TCBImpl::create(work, stack_size, None)
+    }
+
+    fn create(work: Box<Task>, stack_size: usize, name: Option<String>) -> TCBImpl {
+        let pages = (stack_size as u64 + PAGE_SIZE - 1) / PAGE_SIZE;
+        let stack = KernelStack::new(pages);
+        // Layout popped by context_switch: flags, CR2, callee saved registers, return address.
+        // One extra zero word on top keeps the entry point's stack 16 byte aligned.
+        let num_words = TCBImpl::NUM_CALLEE_SAVED + 4;
+        let stack_ptr_start = stack.top() as usize - num_words * core::mem::size_of::<u64>();
+        let frame =
+            unsafe { core::slice::from_raw_parts_mut(stack_ptr_start as *mut u64, num_words) };
+        for word in frame.iter_mut() {
+            *word = 0;
+        }
+        frame[0] = 0; // Flags
+        frame[1] = 0; // CR2
+        frame[TCBImpl::NUM_CALLEE_SAVED + 2] = thread_entry_point as *const () as u64;
         let tcb_info = TCBInfo::new(stack_ptr_start);
+        let thread = ThreadInfo::new(name, Some(stack.id()));
+        register(&thread);
         TCBImpl {
             tcb_info: tcb_info,
             stack: stack,
             work: Some(work),
+            process: None,
+            exit_hook: None,
+            thread: thread,
+        }
+    }
+
+    /// Creates a thread that belongs to process, and runs in its address space
+    pub fn with_process(work: Box<Task>, process: Arc<Process>) -> TCBImpl {
+        let name = format!("pid {}", process.pid());
+        let mut tcb = TCBImpl::create(work, TCBImpl::DEFAULT_STACK_SIZE, Some(name));
+        tcb.process = Some(process);
+        tcb
+    }
+
+    /// Identifies this thread's stack in overflow reports
+    pub fn stack_id(&self) -> usize {
+        self.stack.id()
+    }
+
+    /// Runs hook when the thread is dropped: once it has stopped, in the cleanup of the
+    /// next thread on its core, or if it is dropped without ever running
+    pub fn on_exit(&mut self, hook: Box<Cleanup>) {
+        self.exit_hook = Some(hook);
+    }
+}
+
+impl Drop for TCBImpl {
+    fn drop(&mut self) {
+        self.thread.exit();
+        if let Some(hook) = self.exit_hook.take() {
+            hook();
         }
     }
 }
@@ -145,9 +484,21 @@ impl TCB for TCBImpl {
             None => panic!("TCBImpl had no work!"),
         }
     }
+
+    fn kernel_stack_top(&self) -> Option<u64> {
+        Some(self.stack.top())
+    }
+
+    fn process(&self) -> Option<&Arc<Process>> {
+        self.process.as_ref()
+    }
+
+    fn thread(&self) -> &Arc<ThreadInfo> {
+        &self.thread
+    }
 }
 
-type Cleanup = FnOnce() + Send + Sync;
+type Cleanup = FnOnce() + Send;
 
 /// Holds tasks to perform after context-switching.
 /// No mutual exclusion needed as this is a per-core data structure
@@ -164,6 +515,10 @@ impl TaskHolder {
     pub fn add_task(&mut self, task: Box<Cleanup>) {
         self.tasks.push_back(task);
     }
+    /// Adds a task that runs before every task already held
+    pub fn add_first(&mut self, task: Box<Cleanup>) {
+        self.tasks.push_front(task);
+    }
     pub fn get_task(&mut self) -> Option<Box<Cleanup>> {
         self.tasks.pop_front()
     }
@@ -198,15 +553,123 @@ pub fn yeet() -> [ISMutex<Option<Box<dyn TCB>>>; 16] {
     }
 }
 
+/// Returns the TCBInfo of the thread active on this core without blocking.
+/// Returns None if threads have not been initialized, or if the active slot is busy or empty.
+pub fn try_current_info() -> Option<*mut TCBInfo> {
+    if !INITIALIZED.load(Ordering::SeqCst) {
+        return None;
+    }
+    let mut active = ACTIVE[smp::me()].try_lock()?;
+    match *active {
+        Some(ref mut tcb) => Some(tcb.get_info()),
+        None => None,
+    }
+}
+
+/// Returns the process of the thread running on this core
+pub fn current_process() -> Option<Arc<Process>> {
+    let was = machine::disable();
+    let result = match *ACTIVE[smp::me()].lock() {
+        Some(ref tcb) => tcb.process().cloned(),
+        None => None,
+    };
+    machine::enable(was);
+    result
+}
+
 pub fn init() {
-    println!("initializing threads...");
-    lazy_static::initialize(&READY);
+    debug!("initializing threads...");
+    set_scheduler(scheduler::new(boot_options::get().sched));
     //println!("ready complete");
     //println!("initializing active");
     lazy_static::initialize(&ACTIVE);
     //println!("active complete");
     lazy_static::initialize(&CLEANUP);
-    println!("threads initialized");
+    lazy_static::initialize(&REGISTRY);
+    idt::interrupt(WAKE_VECTOR, machine::_wake_handler);
+    adopt_boot_thread();
+    INITIALIZED.store(true, Ordering::SeqCst);
+    debug!("threads initialized");
+}
+
+/// Registers the thread an AP booted on. Requires init.
+pub fn init_ap() {
+    adopt_boot_thread();
+}
+
+/// Makes the code running on this core since boot a thread like any other
+fn adopt_boot_thread() {
+    let was = machine::disable();
+    let me = smp::me();
+    let thread = match *ACTIVE[me].lock() {
+        Some(ref tcb) => Arc::clone(tcb.thread()),
+        None => panic!("No boot thread on core {}", me),
+    };
+    register(&thread);
+    thread.start_running(me);
+    *RUNNING[me].lock() = Some(thread);
+    machine::enable(was);
+}
+
+/// Replaces the scheduling policy, moving every ready thread over to the new one
+pub fn set_scheduler(mut new: Box<dyn Scheduler>) {
+    let mut scheduler = SCHEDULER.lock();
+    while let Some(tcb) = scheduler.pop() {
+        new.push(tcb);
+    }
+    info!("scheduling threads by {}", new.name());
+    *scheduler = new;
+}
+
+/// Every thread that has not exited, in order of id
+pub fn threads() -> Vec<Arc<ThreadInfo>> {
+    REGISTRY.lock().values().cloned().collect()
+}
+
+/// The thread running on this core
+pub fn current() -> Option<Arc<ThreadInfo>> {
+    let was = machine::disable();
+    let current = RUNNING[smp::me()].lock().clone();
+    machine::enable(was);
+    current
+}
+
+/// The thread running on core, without blocking.
+/// Returns None if there is none, or its slot is busy, as it may be in a panic.
+pub fn try_running_on(core: usize) -> Option<Arc<ThreadInfo>> {
+    RUNNING[core].try_lock()?.clone()
+}
+
+/// The thread whose kernel stack has id stack_id, without blocking
+pub fn find_by_stack(stack_id: usize) -> Option<Arc<ThreadInfo>> {
+    let registry = REGISTRY.try_lock()?;
+    registry
+        .values()
+        .find(|thread| thread.stack_id() == Some(stack_id))
+        .cloned()
+}
+
+/// Prints every thread, with its state and statistics
+pub fn print_threads() {
+    println!(
+        "{:>6} {:<16} {:<8} {:>4} {:>12} {:>10}",
+        "id", "name", "state", "core", "cpu us", "switches"
+    );
+    for thread in threads() {
+        let core = match thread.core() {
+            Some(core) => format!("{}", core),
+            None => String::from("-"),
+        };
+        println!(
+            "{:>6} {:<16} {:<8} {:>4} {:>12} {:>10}",
+            thread.id(),
+            thread.name().unwrap_or("-"),
+            thread.state().name(),
+            core,
+            thread.cpu_time() / 1000,
+            thread.switches()
+        );
+    }
 }
 
 pub fn surrender() {
@@ -219,10 +682,14 @@ pub fn stop() {
 
 /// Yield is a reserved word in Rust, so we use a synonym
 fn surrender_help(run_again: bool, back_out: bool) {
-    // No point of context switching if nothing is on the queue
+    // The idle thread gives up its core by itself, and must never be queued
+    if current().map_or(false, |running| is_idle(&running)) {
+        return;
+    }
+    // No point of context switching if the scheduler would not pick another thread
     // Not a race condition as we double check later on
     if back_out {
-        if READY.lock().is_empty() {
+        if !should_preempt(true) {
             return;
         }
     }
@@ -234,9 +701,14 @@ fn surrender_help(run_again: bool, back_out: bool) {
     // Don't need to disable interrupts, as we will run on this core until we context switch
     let me = smp::me();
     let current_thread_info = current_thread.get_info();
+    current_thread.thread().set_state(if run_again {
+        ThreadState::Ready
+    } else {
+        ThreadState::Exited
+    });
     if (run_again) {
         let add_to_ready = move || {
-            READY.lock().push_back(current_thread);
+            schedule(current_thread);
         };
         CLEANUP[me].lock().add_task(Box::new(add_to_ready));
     } else {
@@ -249,19 +721,202 @@ fn surrender_help(run_again: bool, back_out: bool) {
     block(current_thread_info);
 }
 
+/// Whether the running thread should give this core to a ready one.
+/// slice_over is set when its time slice has ended.
+fn should_preempt(slice_over: bool) -> bool {
+    let was = machine::disable();
+    let scheduler = SCHEDULER.lock();
+    let preempt = match *RUNNING[smp::me()].lock() {
+        Some(ref running) => scheduler.should_preempt(running, slice_over),
+        None => slice_over && !scheduler.is_empty(),
+    };
+    drop(scheduler);
+    machine::enable(was);
+    preempt
+}
+
+/// Gives this core to a ready thread that the scheduler ranks above the running one,
+/// before its time slice is over. Called after waking threads.
+pub fn preempt() {
+    if should_preempt(false) {
+        surrender_help(true, false);
+    }
+}
+
+/// Like preempt, for after waking a thread from code that may not be able to switch:
+/// an interrupt handler, code that disabled interrupts for a lock, or a cleanup task.
+/// There it does nothing, and interrupt handlers preempt once they are done.
+pub fn maybe_preempt() {
+    let was = machine::disable();
+    let in_cleanup = CLEANUP[smp::me()].try_lock().is_none();
+    machine::enable(was);
+    if was && !in_cleanup {
+        preempt();
+    }
+}
+
+/// Blocks the running thread until the clock reaches deadline, in nanoseconds
+pub fn sleep_until(deadline: u64) {
+    if clock::now() >= deadline {
+        return;
+    }
+    // Stays disabled until the switch, so the thread cannot be preempted half asleep
+    let was = machine::disable();
+    let mut current_thread = match swap_active(None) {
+        Some(tcb) => tcb,
+        None => panic!("Called sleep with no active thread"),
+    };
+    let current_thread_info = current_thread.get_info();
+    current_thread.thread().set_state(ThreadState::Sleeping);
+    let token = NEXT_SLEEPER.fetch_add(1, Ordering::SeqCst);
+    // The timer is set only once the thread is off its stack and in SLEEPING
+    let go_to_sleep = move || {
+        SLEEPING.lock().insert(token, current_thread);
+        timer::at(deadline, move || wake_sleeper(token));
+    };
+    CLEANUP[smp::me()].lock().add_task(Box::new(go_to_sleep));
+    block(current_thread_info);
+    machine::enable(was);
+}
+
+/// Blocks the running thread for at least duration
+pub fn sleep(duration: Duration) {
+    sleep_until(clock::now() + duration.as_nanos() as u64);
+}
+
+/// Moves a sleeping thread back onto the ready queue
+fn wake_sleeper(token: u64) {
+    let tcb = SLEEPING.lock().remove(&token);
+    if let Some(tcb) = tcb {
+        schedule(tcb);
+    }
+}
+
 pub fn block(current_thread_info: *mut TCBInfo) {
     // Find something to switch to
-    let mut next_thread: Box<dyn TCB> = match READY.lock().pop_front() {
-        Some(mut tcb) => tcb,
-        None => {
-            // Implementation Note: Potentially a trade off to switch to something that switches back,
-            // but most of the time, there should be something in the ready q
-            let work = move || return;
-            let busy_work = Box::new(TCBImpl::new(Box::new(work)));
-            busy_work
+    let next = SCHEDULER.lock().pop();
+    // With nothing ready this core is idle, and needs no time slices
+    timer::set_idle(next.is_none());
+    let next_thread = match next {
+        Some(tcb) => tcb,
+        None => take_idle(),
+    };
+    switch_to(current_thread_info, next_thread);
+}
+
+/// Whether thread is this core's idle thread
+fn is_idle(thread: &ThreadInfo) -> bool {
+    thread.id().0 == IDLE_IDS[smp::me()].load(Ordering::SeqCst)
+}
+
+/// Takes this core's idle thread to run, creating it the first time
+fn take_idle() -> Box<dyn TCB> {
+    let me = smp::me();
+    if let Some(idle) = IDLE[me].lock().take() {
+        return idle;
+    }
+    if IDLE_IDS[me].load(Ordering::SeqCst) != u64::MAX {
+        panic!("Core {} blocked its own idle thread", me);
+    }
+    let idle = TCBImpl::named("idle", Box::new(idle_loop));
+    IDLE_IDS[me].store(idle.thread().id().0, Ordering::SeqCst);
+    Box::new(idle)
+}
+
+/// What the idle thread runs: hands the core to the first thread that becomes ready,
+/// and halts until schedule wakes it while there is none
+fn idle_loop() {
+    loop {
+        let was = machine::disable();
+        let next = SCHEDULER.lock().pop();
+        match next {
+            Some(next) => {
+                let mut idle = match swap_active(None) {
+                    Some(tcb) => tcb,
+                    None => panic!("No active idle thread"),
+                };
+                let idle_info = idle.get_info();
+                idle.thread().set_state(ThreadState::Ready);
+                let park = move || {
+                    *IDLE[smp::me()].lock() = Some(idle);
+                };
+                CLEANUP[smp::me()].lock().add_task(Box::new(park));
+                timer::set_idle(false);
+                switch_to(idle_info, next);
+            }
+            None => {
+                // Set before looking again, so a thread scheduled meanwhile is either
+                // seen here or finds the flag and sends a wake-up, which waits for the sti
+                let me = smp::me();
+                HALTED[me].store(true, Ordering::SeqCst);
+                if SCHEDULER.lock().is_empty() {
+                    unsafe {
+                        machine::sti_hlt();
+                    }
+                }
+                HALTED[me].store(false, Ordering::SeqCst);
+            }
+        }
+        machine::enable(was);
+    }
+}
+
+/// Sends a wake-up to one halted core, if there is one, to run a thread just made ready
+fn wake_halted_core() {
+    let me = smp::me();
+    let halted =
+        (0..smp::MAX_CORES).find(|&core| core != me && HALTED[core].swap(false, Ordering::SeqCst));
+    let apic_id = match halted.and_then(|core| unsafe { CONFIG.topology.apic_id(core) }) {
+        Some(apic_id) => apic_id,
+        None => return,
+    };
+    Apic::with_base(unsafe { CONFIG.local_apic as usize }).send_ipi(apic_id, WAKE_VECTOR as u8);
+}
+
+#[no_mangle]
+pub extern "C" fn wake_handler() {
+    // Returning from the interrupt is the wake-up, so there is nothing to do but acknowledge it
+    let lapic = unsafe {
+        match &smp::LAPIC {
+            Some(lapic) => lapic,
+            None => panic!("No LAPIC available"),
         }
     };
+    unsafe {
+        core::ptr::write_volatile(lapic.eoi_reg, 0);
+    }
+}
+
+/// Switches from the thread whose context is saved at current_thread_info to next_thread,
+/// which becomes the active thread
+fn switch_to(current_thread_info: *mut TCBInfo, mut next_thread: Box<dyn TCB>) {
     let next_thread_info = next_thread.get_info();
+    unsafe {
+        (*next_thread_info)
+            .switched_out
+            .store(false, Ordering::SeqCst);
+    }
+    next_thread
+        .thread()
+        .stack_pointer
+        .store(0, Ordering::SeqCst);
+    // Runs before any other cleanup can hand the outgoing thread to another core, or free it
+    let saved = current_thread_info as usize;
+    let previous = current();
+    let mark_switched_out = move || {
+        let info = unsafe { &*(saved as *const TCBInfo) };
+        info.switched_out.store(true, Ordering::SeqCst);
+        if let Some(previous) = previous {
+            previous
+                .stack_pointer
+                .store(info.stack_pointer(), Ordering::SeqCst);
+        }
+    };
+    CLEANUP[smp::me()]
+        .lock()
+        .add_first(Box::new(mark_switched_out));
+    switch_running(Arc::clone(next_thread.thread()));
+    prepare_switch(&*next_thread);
     let assert_as_active = move || {
         // The next thread will now assert itself as the active thread
         swap_active(Some(next_thread));
@@ -273,7 +928,31 @@ pub fn block(current_thread_info: *mut TCBInfo) {
     cleanup();
 }
 
+/// Charges the outgoing thread for its run, and starts the clock on next
+fn switch_running(next: Arc<ThreadInfo>) {
+    let me = smp::me();
+    let mut running = RUNNING[me].lock();
+    if let Some(ref previous) = *running {
+        previous.stop_running();
+    }
+    next.start_running(me);
+    *running = Some(next);
+}
+
+/// Points kernel entry from user mode at the next thread's stack, and loads its address space
+fn prepare_switch(next: &dyn TCB) {
+    if let Some(top) = next.kernel_stack_top() {
+        syscall::set_kernel_stack(top);
+    }
+    match next.process() {
+        Some(process) => process.load_address_space(),
+        None => vmm::load_kernel_address_space(),
+    }
+}
+
 fn cleanup() {
+    // Cores that run with interrupts disabled only see TLB shootdowns here
+    vmm::handle_shootdown();
     let was = machine::disable();
     let me = smp::me();
     let mut cleanup_work = CLEANUP[me].lock();
@@ -286,22 +965,150 @@ fn cleanup() {
     }
 }
 
+/// Prints a backtrace of every thread that is switched out, from where it last switched
+pub fn print_backtraces() {
+    // Never blocks, as it runs in panics
+    let threads: Vec<Arc<ThreadInfo>> = match REGISTRY.try_lock() {
+        Some(registry) => registry.values().cloned().collect(),
+        None => {
+            println!("The thread registry is busy");
+            return;
+        }
+    };
+    for thread in threads {
+        match thread.saved_stack_pointer() {
+            Some(stack_pointer) => {
+                println!("{} ({}):", thread, thread.state().name());
+                backtrace::print_switched(stack_pointer);
+            }
+            None => println!("{} ({})", thread, thread.state().name()),
+        }
+    }
+}
+
+/// Makes tcb ready to run
 pub fn schedule(tcb: Box<dyn TCB>) {
+    tcb.thread().set_state(ThreadState::Ready);
     unsafe {
         let was = machine::disable();
-        READY.lock().push_back(tcb);
+        SCHEDULER.lock().push(tcb);
         machine::enable(was);
     }
+    wake_halted_core();
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum JoinError {
+    /// The thread was dropped before its closure returned
+    Incomplete,
+}
+
+struct JoinState<T> {
+    result: ISMutex<Option<T>>,
+    /// Goes up once, when the thread is dropped
+    exited: Arc<Semaphore>,
+}
+
+/// Waits for a thread started by spawn
+pub struct JoinHandle<T> {
+    state: Arc<JoinState<T>>,
+    thread: Arc<ThreadInfo>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Blocks until the thread is gone, and returns what its closure returned
+    pub fn join(self) -> Result<T, JoinError> {
+        self.state.exited.down();
+        self.state.result.lock().take().ok_or(JoinError::Incomplete)
+    }
+
+    /// The thread's id, name, state and statistics
+    pub fn thread(&self) -> &Arc<ThreadInfo> {
+        &self.thread
+    }
+}
+
+/// Runs work on a new thread. Dropping the handle lets the thread run on, detached.
+pub fn spawn<F, T>(work: F) -> JoinHandle<T>
+where
+    F: 'static + FnOnce() -> T + Send,
+    T: 'static + Send,
+{
+    Builder::new().spawn(work)
+}
+
+/// Like spawn, but names the thread
+pub fn spawn_named<F, T>(name: &str, work: F) -> JoinHandle<T>
+where
+    F: 'static + FnOnce() -> T + Send,
+    T: 'static + Send,
+{
+    Builder::new().name(name).spawn(work)
+}
+
+/// Sets up a thread before it is ready to run, e.g.
+///   thread::Builder::new().name("worker").priority(5).spawn(|| work())
+pub struct Builder {
+    name: Option<String>,
+    priority: u8,
+}
+
+impl Builder {
+    pub fn new() -> Builder {
+        Builder {
+            name: None,
+            priority: scheduler::DEFAULT_PRIORITY,
+        }
+    }
+
+    /// Names the thread in thread listings and reports
+    pub fn name(mut self, name: &str) -> Builder {
+        self.name = Some(String::from(name));
+        self
+    }
+
+    /// The priority it starts at, up to scheduler::MAX_PRIORITY
+    pub fn priority(mut self, priority: u8) -> Builder {
+        self.priority = priority.min(scheduler::MAX_PRIORITY);
+        self
+    }
+
+    /// Runs work on the new thread. Dropping the handle lets the thread run on, detached.
+    pub fn spawn<F, T>(self, work: F) -> JoinHandle<T>
+    where
+        F: 'static + FnOnce() -> T + Send,
+        T: 'static + Send,
+    {
+        let state = Arc::new(JoinState {
+            result: ISMutex::new(None),
+            exited: Semaphore::new(0),
+        });
+        let result_state = Arc::clone(&state);
+        let work = Box::new(move || {
+            let result = work();
+            *result_state.result.lock() = Some(result);
+        });
+        let mut tcb = TCBImpl::create(work, TCBImpl::DEFAULT_STACK_SIZE, self.name);
+        // Before it is queued, so it is queued at its own priority
+        tcb.thread()
+            .priority
+            .store(self.priority, Ordering::SeqCst);
+        let exit_state = Arc::clone(&state);
+        tcb.on_exit(Box::new(move || exit_state.exited.up()));
+        let thread = Arc::clone(tcb.thread());
+        schedule(Box::new(tcb));
+        JoinHandle { state, thread }
+    }
 }
 
 pub fn surrender_test() {
     let mut test1 = Box::new(TCBImpl::new(box || ()));
-    println!("{} in surrender after heap allocation", smp::me());
+    debug!("{} in surrender after heap allocation", smp::me());
     let mut test2 = Box::new(TCBImpl::new(box || ()));
-    println!("attempting to context switch");
+    debug!("attempting to context switch");
     let x = test2.get_info();
     unsafe {
-        println!("switching to rsp {:x}", unsafe { *(x as *mut usize) });
+        debug!("switching to rsp {:x}", unsafe { *(x as *mut usize) });
     }
     unsafe {
         machine::context_switch(test1.get_info(), test2.get_info());
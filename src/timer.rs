@@ -1,93 +1,353 @@
+use crate::boot_options;
+use crate::boot_options::TimerMode;
+use crate::clock;
 use crate::idt;
+use crate::ismutex::ISMutex;
 use crate::machine;
-use crate::println;
 use crate::smp;
 use crate::thread;
-use core::sync::atomic::Ordering;
+use crate::{debug, info};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+/*
+ * The local APIC timer, which preempts threads and runs timer callbacks.
+ *
+ * Each core keeps its own pending callbacks, ordered by deadline on the clock.
+ * In one-shot and TSC-deadline modes the timer is only armed for the next
+ * thing due: the earliest callback, or the end of the running thread's time
+ * slice. A core with nothing to run has no time slice, so unless a callback
+ * is pending it takes no timer interrupts at all.
+ *
+ * Periodic mode interrupts hz times a second, and callbacks fire on the first
+ * tick after their deadline.
+ *
+ * Callbacks run in the timer interrupt, with interrupts disabled, so they
+ * must not block. Waking a thread is the usual thing to do in one, and if the
+ * scheduler ranks the woken thread above the running one, it runs at once.
+ */
 
-pub static PIT_FREQ: u32 = 1193182;
 pub static APIT_vector: usize = 40;
+/// The initial count for one period in periodic mode
 pub static mut APIT_counter: Option<u32> = None;
+/// Divide configuration for the local APIC timer: count at the bus rate
+const APIT_DIVIDE: u32 = 0xB;
+/// LVT timer bits
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_PERIODIC: u32 = 0b01 << 17;
+const LVT_TSC_DEADLINE: u32 = 0b10 << 17;
+const IA32_TSC_DEADLINE: u32 = 0x6e0;
+/// CPUID leaf 1 ECX bit for TSC-deadline mode
+const CPUID_TSC_DEADLINE: u32 = 1 << 24;
 
-pub fn calibrate(hz: u32) {
-    println!("Calibrating APIT...");
-    let lapic = unsafe {
+/// Only written by calibrate, before other cores run
+static mut MODE: TimerMode = TimerMode::Periodic;
+/// Local APIC timer counts per second
+static APIT_HZ: AtomicU64 = AtomicU64::new(0);
+/// The length of a time slice, in nanoseconds
+static QUANTUM: AtomicU64 = AtomicU64::new(0);
+static NEXT_TIMER: AtomicU64 = AtomicU64::new(0);
+
+type Callback = Box<dyn FnOnce() + Send>;
+
+/// A pending callback, for cancel
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimerId {
+    core: usize,
+    deadline: u64,
+    id: u64,
+}
+
+impl TimerId {
+    /// When the callback is due, in clock nanoseconds
+    pub fn deadline(&self) -> u64 {
+        self.deadline
+    }
+}
+
+struct CoreTimers {
+    /// Keyed by deadline, then by id so callbacks due together run in the order they were added
+    pending: BTreeMap<(u64, u64), Callback>,
+    /// When the running thread's time slice ends, if it has one
+    slice_end: Option<u64>,
+    idle: bool,
+    /// Set once init has set up this core's timer
+    started: bool,
+}
+
+impl CoreTimers {
+    fn new() -> CoreTimers {
+        CoreTimers {
+            pending: BTreeMap::new(),
+            slice_end: None,
+            idle: false,
+            started: false,
+        }
+    }
+
+    /// The next time this core needs an interrupt
+    fn next_deadline(&self) -> Option<u64> {
+        let callback = self.pending.keys().next().map(|(deadline, _)| *deadline);
+        match (callback, self.slice_end) {
+            (Some(callback), Some(slice_end)) => Some(callback.min(slice_end)),
+            (callback, slice_end) => callback.or(slice_end),
+        }
+    }
+}
+
+lazy_static! {
+    static ref TIMERS: [ISMutex<CoreTimers>; smp::MAX_CORES] = {
+        let mut timers: [MaybeUninit<ISMutex<CoreTimers>>; smp::MAX_CORES] =
+            unsafe { MaybeUninit::uninit().assume_init() };
+        for i in 0..smp::MAX_CORES {
+            timers[i] = MaybeUninit::new(ISMutex::new(CoreTimers::new()));
+        }
+        unsafe { core::mem::transmute::<_, [ISMutex<CoreTimers>; smp::MAX_CORES]>(timers) }
+    };
+}
+
+fn lapic() -> &'static smp::SMP {
+    unsafe {
         match &smp::LAPIC {
             Some(lapic) => lapic,
             None => panic!("No LAPIC available"),
         }
-    };
-    let d = PIT_FREQ / 20;
-    let mut initial = 0xffffffff;
+    }
+}
+
+fn mode() -> TimerMode {
+    unsafe { MODE }
+}
+
+fn has_tsc_deadline() -> bool {
+    unsafe { core::arch::x86_64::__cpuid(1).ecx & CPUID_TSC_DEADLINE != 0 }
+}
+
+/// Times the local APIC timer, picks the timer mode, and sets up hz time slices a second.
+/// Requires clock::init.
+pub fn calibrate(hz: u32) {
+    debug!("Calibrating APIT...");
+    let lapic = lapic();
+    let initial = 0xffffffff;
     unsafe {
-        core::ptr::write_volatile(lapic.apit_lvt_timer, 0x00010000);
-        core::ptr::write_volatile(lapic.apit_divide, 0x00010000);
+        // Masked, counting down once at the rate init runs it at
+        core::ptr::write_volatile(lapic.apit_lvt_timer, LVT_MASKED);
+        core::ptr::write_volatile(lapic.apit_divide, APIT_DIVIDE);
         core::ptr::write_volatile(lapic.apit_initial_count, initial);
-        machine::outb(0x61, 1);
-        machine::outb(0x43, 0b10110110);
-        machine::outb(0x42, d);
-        machine::outb(0x42, d >> 8);
-    }
-    let mut last = unsafe { machine::inb(0x61) & 0x20 };
-    let mut changes = 0;
-
-    while changes < 40 {
-        let t = unsafe { machine::inb(0x61) & 0x20 };
-        if t != last {
-            changes += 1;
-            last = t;
-        }
     }
-    let current_count = unsafe { core::ptr::read_volatile(lapic.apit_current_count) };
-    println!("current count {:x}", current_count);
-    let diff = initial - current_count;
+    let apit_hz = clock::calibrate(|| {
+        let current_count = unsafe { core::ptr::read_volatile(lapic.apit_current_count) };
+        (initial - current_count) as u64
+    });
     unsafe {
-        machine::outb(0x61, 0);
+        core::ptr::write_volatile(lapic.apit_initial_count, 0);
     }
-    println!("diff {:x}", diff);
-    println!("APIT running at {} hz", diff);
-    let counter = diff / hz;
-    println!("apit counter: {}", counter);
+    debug!("APIT running at {} hz", apit_hz);
+    let counter = (apit_hz / hz as u64) as u32;
+    debug!("apit counter: {}", counter);
+    APIT_HZ.store(apit_hz, Ordering::SeqCst);
+    QUANTUM.store(clock::NANOS_PER_SEC / hz as u64, Ordering::SeqCst);
+    let mode = match boot_options::get().timer {
+        Some(TimerMode::TscDeadline) if !has_tsc_deadline() => {
+            info!("no TSC-deadline mode, using one-shot mode");
+            TimerMode::OneShot
+        }
+        Some(mode) => mode,
+        None if has_tsc_deadline() => TimerMode::TscDeadline,
+        None => TimerMode::OneShot,
+    };
+    info!("the timer is in {:?} mode", mode);
     unsafe {
         APIT_counter = Some(counter);
+        MODE = mode;
     }
     idt::interrupt(APIT_vector, machine::_apit_handler);
 }
 
-pub fn init() {
-    let lapic = unsafe {
-        match &smp::LAPIC {
-            Some(lapic) => lapic,
-            None => panic!("No LAPIC available"),
+/// Arms this core's timer for the next thing due, or disarms it if nothing is.
+/// Does nothing in periodic mode, where the timer never stops.
+fn program(timers: &CoreTimers) {
+    if !timers.started {
+        return;
+    }
+    let lapic = lapic();
+    let next = timers.next_deadline();
+    match mode() {
+        TimerMode::Periodic => {}
+        TimerMode::OneShot => {
+            let count = match next {
+                Some(deadline) => {
+                    let nanos = deadline.saturating_sub(clock::now());
+                    let count = nanos as u128 * APIT_HZ.load(Ordering::SeqCst) as u128
+                        / clock::NANOS_PER_SEC as u128;
+                    // 0 would stop the timer, and is too late anyway
+                    count.max(1).min(u32::MAX as u128) as u32
+                }
+                None => 0,
+            };
+            unsafe {
+                core::ptr::write_volatile(lapic.apit_initial_count, count);
+            }
         }
-    };
+        TimerMode::TscDeadline => {
+            let tsc_deadline = match next {
+                Some(deadline) => {
+                    let nanos = deadline.saturating_sub(clock::now());
+                    let cycles =
+                        nanos as u128 * clock::tsc_hz() as u128 / clock::NANOS_PER_SEC as u128;
+                    // 0 disarms the timer, and a deadline in the past fires at once
+                    unsafe { machine::rdtsc() + cycles as u64 }
+                }
+                None => 0,
+            };
+            unsafe {
+                machine::wrmsr(tsc_deadline, IA32_TSC_DEADLINE);
+            }
+        }
+    }
+}
+
+/// Starts the timer on this core. Requires calibrate.
+pub fn init() {
+    let lapic = lapic();
     let counter = unsafe {
         match APIT_counter {
             Some(counter) => counter,
             None => panic!("APIT not initialized"),
         }
     };
+    let vector = APIT_vector as u32;
     unsafe {
-        core::ptr::write_volatile(lapic.apit_divide, 0x0000000B);
-        core::ptr::write_volatile(
-            lapic.apit_lvt_timer,
-            (1 << 17) | (0 << 16) | (APIT_vector as u32),
-        );
-        core::ptr::write_volatile(lapic.apit_initial_count, counter);
+        match mode() {
+            TimerMode::Periodic => {
+                core::ptr::write_volatile(lapic.apit_divide, APIT_DIVIDE);
+                core::ptr::write_volatile(lapic.apit_lvt_timer, LVT_PERIODIC | vector);
+                core::ptr::write_volatile(lapic.apit_initial_count, counter);
+            }
+            TimerMode::OneShot => {
+                core::ptr::write_volatile(lapic.apit_divide, APIT_DIVIDE);
+                core::ptr::write_volatile(lapic.apit_lvt_timer, vector);
+            }
+            TimerMode::TscDeadline => {
+                core::ptr::write_volatile(lapic.apit_lvt_timer, LVT_TSC_DEADLINE | vector);
+                // The mode switch must land before the deadline MSR is written
+                core::sync::atomic::fence(Ordering::SeqCst);
+            }
+        }
+    }
+    let mut timers = TIMERS[smp::me()].lock();
+    timers.started = true;
+    timers.slice_end = Some(clock::now() + quantum());
+    program(&timers);
+}
+
+/// Called by the scheduler when this core runs out of threads, or gets one again.
+/// An idle core has no time slice to end.
+pub fn set_idle(idle: bool) {
+    if mode() == TimerMode::Periodic {
+        return;
+    }
+    let mut timers = TIMERS[smp::me()].lock();
+    if timers.idle == idle {
+        return;
+    }
+    timers.idle = idle;
+    timers.slice_end = if idle {
+        None
+    } else {
+        Some(clock::now() + quantum())
+    };
+    program(&timers);
+}
+
+/// Runs callback on this core once the clock reaches deadline, in nanoseconds.
+/// Requires clock::init.
+pub fn at<F: 'static + FnOnce() + Send>(deadline: u64, callback: F) -> TimerId {
+    let was = machine::disable();
+    let core = smp::me();
+    let id = NEXT_TIMER.fetch_add(1, Ordering::SeqCst);
+    let mut timers = TIMERS[core].lock();
+    let rearm = match timers.next_deadline() {
+        Some(next) => deadline < next,
+        None => true,
+    };
+    timers.pending.insert((deadline, id), Box::new(callback));
+    if rearm {
+        program(&timers);
+    }
+    drop(timers);
+    machine::enable(was);
+    TimerId { core, deadline, id }
+}
+
+/// Runs callback on this core once duration has passed
+pub fn after<F: 'static + FnOnce() + Send>(duration: Duration, callback: F) -> TimerId {
+    at(clock::now() + duration.as_nanos() as u64, callback)
+}
+
+/// Stops a callback from running. Returns false if it already ran or was cancelled.
+pub fn cancel(timer: TimerId) -> bool {
+    TIMERS[timer.core]
+        .lock()
+        .pending
+        .remove(&(timer.deadline, timer.id))
+        .is_some()
+}
+
+/// The length of a time slice in nanoseconds, or 0 before calibrate
+pub fn quantum() -> u64 {
+    QUANTUM.load(Ordering::SeqCst)
+}
+
+/// Returns the number of time slices since the clock started, at the rate passed to calibrate
+pub fn ticks() -> u64 {
+    match quantum() {
+        0 => 0,
+        quantum => clock::now() / quantum,
     }
 }
 
 #[no_mangle]
 pub extern "C" fn apit_handler() {
     //println!("timer interrupt");
-    let lapic = unsafe {
-        match &smp::LAPIC {
-            Some(lapic) => lapic,
-            None => panic!("No LAPIC available"),
+    unsafe {
+        core::ptr::write_volatile(lapic().eoi_reg, 0);
+    }
+    let now = clock::now();
+    let mut expired: Vec<Callback> = Vec::new();
+    let preempt = {
+        let mut timers = TIMERS[smp::me()].lock();
+        loop {
+            let next = timers.pending.keys().next().cloned();
+            match next {
+                Some(key) if key.0 <= now => expired.push(timers.pending.remove(&key).unwrap()),
+                _ => break,
+            }
+        }
+        let preempt = match (mode(), timers.slice_end) {
+            (TimerMode::Periodic, _) => true,
+            (_, Some(slice_end)) => slice_end <= now,
+            (_, None) => false,
+        };
+        if preempt && !timers.idle {
+            timers.slice_end = Some(now + quantum());
         }
+        program(&timers);
+        preempt
     };
-    unsafe {
-        core::ptr::write_volatile(lapic.eoi_reg, 0);
+    let woke = !expired.is_empty();
+    // Without the lock, so callbacks can add timers
+    for callback in expired {
+        callback();
+    }
+    if preempt {
+        thread::surrender();
+    } else if woke {
+        // A thread a callback woke may outrank the running one
+        thread::preempt();
     }
-    thread::surrender();
 }
@@ -1,31 +1,168 @@
 extern crate spin;
 
+use crate::irq;
 use crate::ismutex::ISMutex;
+use crate::log;
 use crate::machine;
+use crate::semaphore::Semaphore;
+use crate::smp;
+use crate::{info, warn};
+use alloc::sync::Arc;
 use core::fmt;
 use spin::Mutex;
 
-pub struct U8250 {}
+/*
+ * The 16550 UART driver, and the console on COM1.
+ *
+ * The console starts out polled: each byte waits for the transmitter. Once
+ * init_interrupts routes the port's IRQ, output is queued instead and the
+ * port's interrupt refills its FIFO, so printing only waits when the queue is
+ * full. Received bytes are queued by the same interrupt, and threads block on
+ * INPUT_READY until one arrives.
+ */
 
-static mut WRITER: ISMutex<U8250> = ISMutex::new(U8250 {});
+/// The I/O port base of each standard serial port
+pub const COM1: u32 = 0x3F8;
+pub const COM2: u32 = 0x2F8;
+pub const COM3: u32 = 0x3E8;
+pub const COM4: u32 = 0x2E8;
+
+/// The rate every port is set to
+pub const BAUD: u32 = 115200;
+/// The legacy IRQ of COM1
+const CONSOLE_IRQ: usize = 4;
+/// Bytes held by each of the console's queues
+pub const QUEUE_SIZE: usize = 4096;
+
+/// A serial port, identified by its I/O port base
+pub struct U8250 {
+    port: u32,
+}
 
 impl U8250 {
-    const COM_PORT: u32 = 0x3F8;
-    const COM_READY: u32 = 0x3F8 + 5;
+    const DATA: u32 = 0;
+    const INTERRUPT_ENABLE: u32 = 1;
+    /// Read as the interrupt identification register, written as the FIFO control register
+    const INTERRUPT_ID: u32 = 2;
+    const FIFO_CONTROL: u32 = 2;
+    const LINE_CONTROL: u32 = 3;
+    const MODEM_CONTROL: u32 = 4;
+    const LINE_STATUS: u32 = 5;
+    /// With DLAB set in the line control register, registers 0 and 1 hold the divisor
+    const DIVISOR_LOW: u32 = 0;
+    const DIVISOR_HIGH: u32 = 1;
+
+    const DLAB: u32 = 0x80;
+    /// 8 data bits, no parity, 1 stop bit
+    const EIGHT_N_ONE: u32 = 0x03;
+    /// Enable and clear both FIFOs, and interrupt once 14 bytes are waiting.
+    /// Fewer bytes interrupt when the line goes quiet.
+    const FIFO_ENABLE: u32 = 0xC7;
+    /// DTR and RTS, plus OUT2, which connects the port's interrupt line on PCs
+    const MODEM_READY: u32 = 0x0B;
+    /// Feeds the transmitter straight back into the receiver
+    const LOOPBACK: u32 = 0x10;
+    const DATA_READY: u8 = 0x01;
+    const TRANSMIT_EMPTY: u8 = 0x20;
+    const NO_INTERRUPT: u8 = 0x01;
+    const RECEIVE_INTERRUPT: u32 = 0x01;
+    const TRANSMIT_INTERRUPT: u32 = 0x02;
+    /// The UART clock divided by 16. The divisor for a baud rate is this over the rate.
+    const MAX_BAUD: u32 = 115200;
+    /// Bytes the transmit FIFO holds
+    const FIFO_SIZE: usize = 16;
+
+    pub const fn new(port: u32) -> U8250 {
+        U8250 { port: port }
+    }
+
+    pub fn port(&self) -> u32 {
+        self.port
+    }
+
+    /// Sets the port to baud, 8N1 with FIFOs, and turns its interrupts off
+    pub fn init(&self, baud: u32) {
+        let divisor = U8250::MAX_BAUD / baud;
+        unsafe {
+            machine::outb(self.port + U8250::INTERRUPT_ENABLE, 0);
+            machine::outb(self.port + U8250::LINE_CONTROL, U8250::DLAB);
+            machine::outb(self.port + U8250::DIVISOR_LOW, divisor & 0xff);
+            machine::outb(self.port + U8250::DIVISOR_HIGH, divisor >> 8);
+            machine::outb(self.port + U8250::LINE_CONTROL, U8250::EIGHT_N_ONE);
+            machine::outb(self.port + U8250::FIFO_CONTROL, U8250::FIFO_ENABLE);
+            machine::outb(self.port + U8250::MODEM_CONTROL, U8250::MODEM_READY);
+        }
+    }
+
+    /// While on, bytes sent come back as received bytes instead of leaving the port
+    pub fn set_loopback(&self, on: bool) {
+        let control = if on {
+            U8250::MODEM_READY | U8250::LOOPBACK
+        } else {
+            U8250::MODEM_READY
+        };
+        unsafe {
+            machine::outb(self.port + U8250::MODEM_CONTROL, control);
+        }
+    }
+
+    fn set_interrupts(&self, receive: bool, transmit: bool) {
+        let mut enable = 0;
+        if receive {
+            enable |= U8250::RECEIVE_INTERRUPT;
+        }
+        if transmit {
+            enable |= U8250::TRANSMIT_INTERRUPT;
+        }
+        unsafe {
+            machine::outb(self.port + U8250::INTERRUPT_ENABLE, enable);
+        }
+    }
+
+    /// Reading the interrupt identification also acknowledges a transmit interrupt
+    fn interrupt_pending(&self) -> bool {
+        unsafe { machine::inb(self.port + U8250::INTERRUPT_ID) & U8250::NO_INTERRUPT == 0 }
+    }
+
+    fn transmit_empty(&self) -> bool {
+        unsafe { machine::inb(self.port + U8250::LINE_STATUS) & U8250::TRANSMIT_EMPTY != 0 }
+    }
 
     pub fn put(&self, c: u8) {
+        while !self.transmit_empty() {}
         unsafe {
-            while machine::inb(U8250::COM_READY) & 0x20 == 0 {}
-            machine::outb(U8250::COM_PORT, c as u32);
+            machine::outb(self.port + U8250::DATA, c as u32);
         }
     }
 
-    pub fn write_string(&self, s: &str) {
-        for byte in s.bytes() {
-            match byte {
-                0x20..=0x7e | b'\n' => self.put(byte),
-                _ => self.put(0xfe),
+    /// Returns a received byte, if one is waiting
+    pub fn try_get(&self) -> Option<u8> {
+        unsafe {
+            if machine::inb(self.port + U8250::LINE_STATUS) & U8250::DATA_READY == 0 {
+                None
+            } else {
+                Some(machine::inb(self.port + U8250::DATA))
+            }
+        }
+    }
+
+    /// Waits for a byte to arrive
+    pub fn get(&self) -> u8 {
+        loop {
+            if let Some(byte) = self.try_get() {
+                return byte;
             }
+            core::sync::atomic::spin_loop_hint();
+        }
+    }
+
+    pub fn write_string(&self, s: &str) {
+        self.write_bytes(s.as_bytes());
+    }
+
+    pub fn write_bytes(&self, bytes: &[u8]) {
+        for byte in bytes.iter() {
+            self.put(printable(*byte));
         }
     }
 }
@@ -37,6 +174,125 @@ impl fmt::Write for U8250 {
     }
 }
 
+/// Replaces bytes the terminal could misread as control sequences
+fn printable(byte: u8) -> u8 {
+    match byte {
+        0x20..=0x7e | b'\n' => byte,
+        _ => 0xfe,
+    }
+}
+
+/// A fixed ring of bytes, so the interrupt handler never allocates
+struct Queue {
+    bytes: [u8; QUEUE_SIZE],
+    head: usize,
+    length: usize,
+}
+
+impl Queue {
+    const fn new() -> Queue {
+        Queue {
+            bytes: [0; QUEUE_SIZE],
+            head: 0,
+            length: 0,
+        }
+    }
+
+    /// Returns false, dropping byte, if the queue is full
+    fn push(&mut self, byte: u8) -> bool {
+        if self.length == QUEUE_SIZE {
+            return false;
+        }
+        self.bytes[(self.head + self.length) % QUEUE_SIZE] = byte;
+        self.length += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.length == 0 {
+            return None;
+        }
+        let byte = self.bytes[self.head];
+        self.head = (self.head + 1) % QUEUE_SIZE;
+        self.length -= 1;
+        Some(byte)
+    }
+}
+
+struct Console {
+    uart: U8250,
+    output: Queue,
+    /// Set once the port's IRQ is routed, and output goes through the queue
+    interrupt_driven: bool,
+}
+
+impl Console {
+    const fn new(port: u32) -> Console {
+        Console {
+            uart: U8250::new(port),
+            output: Queue::new(),
+            interrupt_driven: false,
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        if !self.interrupt_driven {
+            self.uart.write_bytes(bytes);
+            return;
+        }
+        for byte in bytes.iter() {
+            // Only a full queue waits for the port
+            while !self.output.push(printable(*byte)) {
+                self.transmit();
+                core::sync::atomic::spin_loop_hint();
+            }
+        }
+        self.transmit();
+    }
+
+    /// Refills the transmit FIFO if it has emptied, and asks to be
+    /// interrupted when it empties again if there is more to send
+    fn transmit(&mut self) {
+        if self.uart.transmit_empty() {
+            for _ in 0..U8250::FIFO_SIZE {
+                match self.output.pop() {
+                    Some(byte) => unsafe {
+                        machine::outb(self.uart.port + U8250::DATA, byte as u32);
+                    },
+                    None => break,
+                }
+            }
+        }
+        self.uart
+            .set_interrupts(true, self.interrupt_driven && self.output.length > 0);
+    }
+
+    /// Sends everything queued, waiting for the port
+    fn flush(&mut self) {
+        while self.output.length > 0 {
+            self.transmit();
+            core::sync::atomic::spin_loop_hint();
+        }
+    }
+}
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// The console: print! and the log go here
+static mut WRITER: ISMutex<Console> = ISMutex::new(Console::new(COM1));
+/// Bytes received on the console and not read yet
+static INPUT: ISMutex<Queue> = ISMutex::new(Queue::new());
+
+lazy_static! {
+    /// Counts the bytes in INPUT
+    static ref INPUT_READY: Arc<Semaphore> = Semaphore::new(0);
+}
+
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => ($crate::u8250::_print(format_args!($($arg)*)));
@@ -48,10 +304,135 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
-#[doc(hidden)]
-pub fn _print(args: fmt::Arguments) {
+/// Sets up the console port. Output stays polled until init_interrupts.
+pub fn init() {
+    unsafe {
+        WRITER.lock().uart.init(BAUD);
+    }
+}
+
+/// Routes the console's IRQ to the calling core and switches to queued output.
+/// Requires the heap and irq::init.
+pub fn init_interrupts() {
+    lazy_static::initialize(&INPUT_READY);
+    if let Err(error) = irq::register(CONSOLE_IRQ, serial_interrupt, smp::me()) {
+        warn!("the console stays polled, its irq failed: {:?}", error);
+        return;
+    }
+    unsafe {
+        let mut console = WRITER.lock();
+        console.interrupt_driven = true;
+        console.uart.set_interrupts(true, false);
+    }
+    info!("console input and output are interrupt driven");
+}
+
+/// Returns true once output is queued rather than polled
+pub fn interrupt_driven() -> bool {
+    unsafe { WRITER.lock().interrupt_driven }
+}
+
+/// The number of bytes waiting to be sent
+pub fn pending_output() -> usize {
+    unsafe { WRITER.lock().output.length }
+}
+
+/// Sends all queued output before returning, for when the machine is about to stop
+pub fn flush() {
+    unsafe {
+        WRITER.lock().flush();
+    }
+}
+
+/// Writes raw bytes without interleaving them with other output
+pub fn write_bytes(bytes: &[u8]) {
+    log::record_output(format_args!(
+        "{}",
+        core::str::from_utf8(bytes).unwrap_or("<not utf-8>")
+    ));
+    unsafe {
+        WRITER.lock().write_bytes(bytes);
+    }
+}
+
+/// Loops the console's output back into its input, or stops, for testing input.
+/// Output already queued is sent first.
+pub fn set_loopback(on: bool) {
+    unsafe {
+        let mut console = WRITER.lock();
+        console.flush();
+        console.uart.set_loopback(on);
+    }
+}
+
+/// Returns a byte received on the console, if one is waiting
+pub fn try_get() -> Option<u8> {
+    unsafe {
+        let console = WRITER.lock();
+        if !console.interrupt_driven {
+            return console.uart.try_get();
+        }
+    }
+    if INPUT_READY.try_down() {
+        INPUT.lock().pop()
+    } else {
+        None
+    }
+}
+
+/// Waits for a byte from the console. Blocks the calling thread once the
+/// console is interrupt driven, and spins before that.
+pub fn get() -> u8 {
+    loop {
+        unsafe {
+            let console = WRITER.lock();
+            if console.interrupt_driven {
+                break;
+            }
+            if let Some(byte) = console.uart.try_get() {
+                return byte;
+            }
+        }
+        // Spins without the console, so other cores can print meanwhile
+        core::sync::atomic::spin_loop_hint();
+    }
+    INPUT_READY.down();
+    match INPUT.lock().pop() {
+        Some(byte) => byte,
+        None => panic!("console input counted a byte that is not there"),
+    }
+}
+
+fn serial_interrupt() {
+    let mut received = 0;
+    unsafe {
+        let mut console = WRITER.lock();
+        while console.uart.interrupt_pending() {
+            while let Some(byte) = console.uart.try_get() {
+                // Input nobody reads is dropped once the queue is full
+                if INPUT.lock().push(byte) {
+                    received += 1;
+                }
+            }
+            console.transmit();
+        }
+    }
+    // Waking readers can log, which needs the console
+    for _ in 0..received {
+        INPUT_READY.up();
+    }
+}
+
+/// Writes to the serial port without keeping a copy in dmesg, for text that is already there
+pub fn write_fmt(args: fmt::Arguments) {
     use core::fmt::Write;
     unsafe {
         WRITER.lock().write_fmt(args).unwrap();
     }
 }
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    log::record_output(args);
+    write_fmt(args);
+}
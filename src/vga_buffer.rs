@@ -3,12 +3,13 @@ use lazy_static::lazy_static;
 use volatile::Volatile;
 
 use crate::ismutex::ISMutex;
+use crate::vmm;
 
 lazy_static! {
     pub static ref VGA_WRITER: ISMutex<Writer> = ISMutex::new(Writer {
         column_position: 0,
         color_code: ColorCode::new(Color::Yellow, Color::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+        buffer: unsafe { &mut *(vmm::phys_to_virt(0xb8000) as *mut Buffer) },
     });
 }
 
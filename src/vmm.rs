@@ -2,26 +2,93 @@ extern crate spin;
 
 use spin::Mutex;
 
+use crate::apic::Apic;
 use crate::config::CONFIG;
+use crate::idt;
+use crate::ismutex::ISMutex;
 use crate::machine;
-use crate::println;
+use crate::pmm;
+use crate::smp;
+use crate::spinlock::SpinLock;
+use crate::{debug, trace};
+use alloc::vec::Vec;
+use core::ops::BitOr;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 lazy_static! {
-    pub static ref IDENTITY_MAP: Mutex<&'static mut AddressSpace> = Mutex::new(
-        create_identity_mappings(unsafe { CONFIG.high_phys_mem } / PAGE_SIZE)
+    pub static ref KERNEL_MAP: Mutex<&'static mut AddressSpace> = Mutex::new(
+        create_kernel_mappings(unsafe { CONFIG.high_phys_mem } / PAGE_SIZE)
     );
 }
 
-/*
- * Not a particularly impressive allocator, but works fine in QEMU.
- * Assumes all physical pages are available from start_phys_mem to end_phys_mem
- * and allocates them in order. Then uses next as an intrusive linked list, where
- * next is a page aligned value representing the next available physical page.
- */
-pub struct VMAllocator {
-    next: u64,
-    start_phys_mem: u64,
-    end_phys_mem: u64,
+extern "C" {
+    /// Defined by link.ld, at the end of the kernel image in the higher half
+    static KERNEL_END: u8;
+}
+
+/// The kernel image is linked at KERNEL_BASE + its physical address
+pub const KERNEL_BASE: u64 = 0xFFFF_FFFF_8000_0000;
+/// All physical memory is mapped at PHYS_OFFSET + its physical address
+pub const PHYS_OFFSET: u64 = 0xFFFF_8000_0000_0000;
+/// User processes own the lower half of the address space
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+const USER_PML4_ENTRIES: usize = 256;
+/// The only page mapped in the low 2MB, so APs can find their way into long mode
+pub const AP_TRAMPOLINE: u64 = 0x7000;
+
+/// Returns the direct map address of paddr
+pub fn phys_to_virt(paddr: u64) -> u64 {
+    paddr + PHYS_OFFSET
+}
+
+/// Returns the physical address of a kernel image or direct map address
+pub fn virt_to_phys(vaddr: u64) -> u64 {
+    if vaddr >= KERNEL_BASE {
+        vaddr - KERNEL_BASE
+    } else if vaddr >= PHYS_OFFSET && vaddr < KERNEL_STACK_REGION_START {
+        vaddr - PHYS_OFFSET
+    } else {
+        panic!("0x{:x} is not a linearly mapped kernel address", vaddr);
+    }
+}
+
+/// Permission and caching attributes of a mapping. Present is implied.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PageFlags(u64);
+
+impl PageFlags {
+    pub const WRITABLE: PageFlags = PageFlags(1 << 1);
+    pub const USER: PageFlags = PageFlags(1 << 2);
+    pub const WRITE_THROUGH: PageFlags = PageFlags(1 << 3);
+    pub const CACHE_DISABLE: PageFlags = PageFlags(1 << 4);
+    pub const GLOBAL: PageFlags = PageFlags(1 << 8);
+    pub const NO_EXECUTE: PageFlags = PageFlags(1 << 63);
+
+    /// A read-only, executable, supervisor mapping
+    pub const fn empty() -> PageFlags {
+        PageFlags(0)
+    }
+
+    pub fn contains(&self, other: PageFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for PageFlags {
+    type Output = PageFlags;
+    fn bitor(self, rhs: PageFlags) -> PageFlags {
+        PageFlags(self.0 | rhs.0)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum VmmError {
+    AlreadyMapped,
+    NotMapped,
+    /// The address is covered by a huge page, which these APIs do not split
+    HugePage,
+    /// No physical frames are left
+    OutOfMemory,
 }
 
 #[repr(C, align(4096))]
@@ -32,17 +99,38 @@ pub struct AddressSpace {
 
 impl AddressSpace {
     pub fn new() -> *mut AddressSpace {
-        alloc() as *mut AddressSpace
+        phys_to_virt(alloc()) as *mut AddressSpace
     }
-    pub fn new_with_identity() -> &'static mut AddressSpace {
-        let address_space = alloc() as *mut AddressSpace;
+    pub fn new_with_kernel() -> &'static mut AddressSpace {
+        let address_space = AddressSpace::new();
         let address_space_ref = unsafe { &mut *(address_space) };
-        let identity = (*IDENTITY_MAP).lock();
+        let kernel = (*KERNEL_MAP).lock();
         for i in 0..512 {
-            address_space_ref.entries[i] = identity.entries[i];
+            address_space_ref.entries[i] = kernel.entries[i];
+        }
+        address_space_ref
+    }
+    /// Creates an address space for a user process. Only the kernel half is shared;
+    /// the lower half starts out empty and belongs to the process.
+    pub fn new_user() -> &'static mut AddressSpace {
+        let address_space = AddressSpace::new();
+        let address_space_ref = unsafe { &mut *(address_space) };
+        let kernel = (*KERNEL_MAP).lock();
+        for i in USER_PML4_ENTRIES..512 {
+            address_space_ref.entries[i] = kernel.entries[i];
         }
         address_space_ref
     }
+    /// Makes sure the PML4 entry covering vpn points to a page table, so that
+    /// address spaces copied from this one share everything mapped under it later.
+    fn reserve_pml4_entry(&mut self, vpn: u64) {
+        let entry = &mut self.entries[Address { 0: vpn }.pml4_index() as usize];
+        if entry.present() == 0 {
+            entry.set_present(1);
+            entry.set_writable(1);
+            entry.set_physical_addr(alloc() / PAGE_SIZE);
+        }
+    }
     pub fn create_mapping(&mut self, vpn: u64, ppn: u64) {
         self.create_mapping_helper(Address { 0: vpn }, ppn, 4);
     }
@@ -100,7 +188,7 @@ impl AddressSpace {
             }
             3..=4 => {
                 if entry.present() == 0 {
-                    println!("creating additional page table");
+                    trace!("creating additional page table");
                     entry.set_present(1);
                     entry.set_writable(1);
                     entry.set_physical_addr(alloc() / PAGE_SIZE);
@@ -114,42 +202,345 @@ impl AddressSpace {
             }
         }
     }
+    /// Walks to the page table entry for vaddr. Missing tables are created if create is set.
+    /// Tables on the path of a user mapping are marked user accessible.
+    fn walk(
+        &mut self,
+        vaddr: u64,
+        create: bool,
+        user: bool,
+    ) -> Result<&mut AddressSpaceEntry, VmmError> {
+        let address = Address { 0: vaddr / PAGE_SIZE };
+        let indices = [
+            address.pml4_index(),
+            address.pdpt_index(),
+            address.pd_index(),
+        ];
+        let mut table: &mut AddressSpace = self;
+        for (level, index) in indices.iter().enumerate() {
+            let entry = &mut table.entries[*index as usize];
+            if entry.present() == 0 {
+                if !create {
+                    return Err(VmmError::NotMapped);
+                }
+                let frame = try_alloc().ok_or(VmmError::OutOfMemory)?;
+                entry.set_present(1);
+                entry.set_writable(1);
+                entry.set_physical_addr(frame / PAGE_SIZE);
+            } else if entry.huge() == 1 {
+                if level == 2 && !create {
+                    // Let protect and translate operate on a 2MB page directly
+                    return Ok(entry);
+                }
+                return Err(VmmError::HugePage);
+            }
+            if user {
+                entry.set_user_supervisor(1);
+            }
+            table = entry.get_address_space();
+        }
+        Ok(&mut table.entries[address.pt_index() as usize])
+    }
+
+    /// Maps the page containing vaddr to the frame containing paddr
+    pub fn map(&mut self, vaddr: u64, paddr: u64, flags: PageFlags) -> Result<(), VmmError> {
+        let entry = self.walk(vaddr, true, flags.contains(PageFlags::USER))?;
+        if entry.present() == 1 {
+            return Err(VmmError::AlreadyMapped);
+        }
+        entry.set_physical_addr(paddr / PAGE_SIZE);
+        entry.set_flags(flags);
+        entry.set_present(1);
+        Ok(())
+    }
+
+    /// Removes the mapping of the page containing vaddr and returns the frame it mapped.
+    /// The frame is not freed.
+    pub fn unmap(&mut self, vaddr: u64) -> Result<u64, VmmError> {
+        let entry = self.walk(vaddr, false, false)?;
+        if entry.present() == 0 {
+            return Err(VmmError::NotMapped);
+        }
+        if entry.huge() == 1 {
+            return Err(VmmError::HugePage);
+        }
+        let frame = entry.physical_addr() * PAGE_SIZE;
+        *entry = AddressSpaceEntry(0);
+        flush_tlb(vaddr, 1);
+        Ok(frame)
+    }
+
+    /// Replaces the flags of the mapping containing vaddr
+    pub fn protect(&mut self, vaddr: u64, flags: PageFlags) -> Result<(), VmmError> {
+        let entry = self.walk(vaddr, false, false)?;
+        if entry.present() == 0 {
+            return Err(VmmError::NotMapped);
+        }
+        entry.set_flags(flags);
+        flush_tlb(vaddr, 1);
+        Ok(())
+    }
+
+    /// Returns the physical address vaddr maps to
+    pub fn translate(&mut self, vaddr: u64) -> Option<u64> {
+        let entry = self.walk(vaddr, false, false).ok()?;
+        if entry.present() == 0 {
+            return None;
+        }
+        let page_size = if entry.huge() == 1 {
+            HUGE_PAGE_SIZE
+        } else {
+            PAGE_SIZE
+        };
+        Some((entry.physical_addr() * PAGE_SIZE & !(page_size - 1)) + (vaddr & (page_size - 1)))
+    }
+
+    /// Returns the flags in effect for vaddr. USER and WRITABLE are only reported if
+    /// every level of the walk allows them, as that is how the processor checks them.
+    pub fn flags(&mut self, vaddr: u64) -> Option<PageFlags> {
+        let address = Address { 0: vaddr / PAGE_SIZE };
+        let indices = [
+            address.pml4_index(),
+            address.pdpt_index(),
+            address.pd_index(),
+            address.pt_index(),
+        ];
+        let mut table: &mut AddressSpace = self;
+        let mut user = true;
+        let mut writable = true;
+        for (level, index) in indices.iter().enumerate() {
+            let entry = table.entries[*index as usize];
+            if entry.present() == 0 {
+                return None;
+            }
+            user = user && entry.user_supervisor() == 1;
+            writable = writable && entry.writable() == 1;
+            if level == 3 || entry.huge() == 1 {
+                let mut flags = entry.flags().0 & !(PageFlags::USER.0 | PageFlags::WRITABLE.0);
+                if user {
+                    flags |= PageFlags::USER.0;
+                }
+                if writable {
+                    flags |= PageFlags::WRITABLE.0;
+                }
+                return Some(PageFlags(flags));
+            }
+            table = entry.get_address_space();
+        }
+        None
+    }
+
+    /// Unmaps the lower half, returning every frame and page table it used to the frame allocator
+    pub fn free_user_mappings(&mut self) {
+        for i in 0..USER_PML4_ENTRIES {
+            let entry = self.entries[i];
+            if entry.present() == 1 {
+                free_table(entry.get_address_space(), 3);
+                free(entry.physical_addr() * PAGE_SIZE);
+                self.entries[i] = AddressSpaceEntry(0);
+            }
+        }
+    }
+
+    /// Load this address space in CR3 if it is not already loaded
+    pub fn load(&self) {
+        let pml4 = virt_to_phys(self as *const AddressSpace as u64);
+        unsafe {
+            if machine::get_cr3() != pml4 {
+                machine::load_cr3(pml4);
+            }
+        }
+    }
+
     /// Load this address space in CR3
     pub fn activate(&self) {
         unsafe {
-            println!(
+            debug!(
                 "switching to address space at 0x{:x}",
                 self as *const AddressSpace as usize
             );
-            machine::load_cr3(self as *const AddressSpace as u64);
+            machine::load_cr3(virt_to_phys(self as *const AddressSpace as u64));
         }
     }
 }
 
-fn create_identity_mappings(high_page: u64) -> &'static mut AddressSpace {
+/// Frees the frames mapped by a table at the given level, and the tables below it
+fn free_table(table: &mut AddressSpace, level: u32) {
+    for entry in table.entries.iter() {
+        if entry.present() == 0 {
+            continue;
+        }
+        if level > 1 && entry.huge() == 0 {
+            free_table(entry.get_address_space(), level - 1);
+        }
+        if level == 1 || entry.huge() == 0 {
+            free(entry.physical_addr() * PAGE_SIZE);
+        }
+    }
+}
+
+fn create_kernel_mappings(high_page: u64) -> &'static mut AddressSpace {
     let address_space = AddressSpace::new();
     let mut address_space_ref = unsafe { &mut *address_space };
-    // The first 2MB
-    for i in 1..0x200 {
-        address_space_ref.create_mapping(i, i);
+    let pages_per_huge_page = HUGE_PAGE_SIZE / PAGE_SIZE;
+    // Direct map of all physical memory, using huge pages
+    let direct_map_start = PHYS_OFFSET / PAGE_SIZE;
+    for i in (0..high_page).step_by(pages_per_huge_page as usize) {
+        address_space_ref.create_huge_mapping(direct_map_start + i, i);
     }
-    // Huge mappings
-    let boundary = high_page - (high_page % 0x200);
-    for i in (0x200..boundary).step_by(0x200) {
-        address_space_ref.create_huge_mapping(i, i);
-    }
-    for i in boundary..high_page {
-        address_space_ref.create_mapping(i, i);
+    // The kernel image
+    let kernel_start = KERNEL_BASE / PAGE_SIZE;
+    let kernel_end = unsafe { virt_to_phys(&KERNEL_END as *const u8 as u64) } / PAGE_SIZE;
+    for i in (0..kernel_end).step_by(pages_per_huge_page as usize) {
+        address_space_ref.create_huge_mapping(kernel_start + i, i);
     }
+    address_space_ref
+        .map(AP_TRAMPOLINE, AP_TRAMPOLINE, PageFlags::WRITABLE)
+        .unwrap();
+    address_space_ref.reserve_pml4_entry(KERNEL_STACK_REGION_START / PAGE_SIZE);
     // Map MMIO
     unsafe {
-        let lapic: u64 = (CONFIG.local_apic as u64) / PAGE_SIZE;
-        println!("mapping {:x}", lapic);
-        address_space_ref.create_mapping(lapic, lapic);
+        let lapic = CONFIG.local_apic as u64;
+        debug!("mapping {:x}", lapic);
+        map_mmio_in(address_space_ref, lapic);
     }
     address_space_ref
 }
 
+fn map_mmio_in(address_space: &mut AddressSpace, paddr: u64) -> u64 {
+    let page = paddr & !(PAGE_SIZE - 1);
+    let vaddr = phys_to_virt(page);
+    // Memory mapped registers below the top of RAM are already covered by the direct map
+    if address_space.translate(vaddr).is_none() {
+        address_space
+            .map(
+                vaddr,
+                page,
+                PageFlags::WRITABLE | PageFlags::CACHE_DISABLE | PageFlags::NO_EXECUTE,
+            )
+            .unwrap();
+    }
+    phys_to_virt(paddr)
+}
+
+/// Maps the page of device registers containing paddr into the direct map, uncached,
+/// and returns the virtual address of paddr
+pub fn map_mmio(paddr: u64) -> u64 {
+    map_mmio_in(&mut KERNEL_MAP.lock(), paddr)
+}
+
+/// Thread stacks are mapped into their own PML4 slot, shared by every address space.
+/// Each stack sits directly above an unmapped guard page.
+pub const KERNEL_STACK_REGION_START: u64 = 0xFFFF_FE00_0000_0000;
+pub const KERNEL_STACK_REGION_END: u64 = 0xFFFF_FE80_0000_0000;
+
+struct StackSlot {
+    guard_page: u64,
+    pages: u64,
+    in_use: bool,
+}
+
+/*
+ * Stacks are never unmapped. A freed stack keeps its frames and is handed
+ * out again to the next request for the same number of pages.
+ */
+struct StackRegion {
+    next: u64,
+    slots: Vec<StackSlot>,
+}
+
+static STACK_REGION: ISMutex<StackRegion> = ISMutex::new(StackRegion {
+    next: KERNEL_STACK_REGION_START,
+    slots: Vec::new(),
+});
+
+/// A kernel stack backed by frames from alloc, with an unmapped guard page below it
+pub struct KernelStack {
+    slot: usize,
+    bottom: u64,
+    pages: u64,
+}
+
+impl KernelStack {
+    pub fn new(pages: u64) -> KernelStack {
+        let mut region = STACK_REGION.lock();
+        for (i, slot) in region.slots.iter_mut().enumerate() {
+            if !slot.in_use && slot.pages == pages {
+                slot.in_use = true;
+                return KernelStack {
+                    slot: i,
+                    bottom: slot.guard_page + PAGE_SIZE,
+                    pages: pages,
+                };
+            }
+        }
+        let guard_page = region.next;
+        let bottom = guard_page + PAGE_SIZE;
+        let end = bottom + pages * PAGE_SIZE;
+        if end > KERNEL_STACK_REGION_END {
+            panic!("Out of virtual memory for kernel stacks");
+        }
+        {
+            let mut address_space = KERNEL_MAP.lock();
+            for page in (bottom..end).step_by(PAGE_SIZE as usize) {
+                address_space.create_mapping(page / PAGE_SIZE, alloc() / PAGE_SIZE);
+            }
+        }
+        region.next = end;
+        region.slots.push(StackSlot {
+            guard_page: guard_page,
+            pages: pages,
+            in_use: true,
+        });
+        KernelStack {
+            slot: region.slots.len() - 1,
+            bottom: bottom,
+            pages: pages,
+        }
+    }
+
+    /// The address one past the highest usable word of the stack
+    pub fn top(&self) -> u64 {
+        self.bottom + self.pages * PAGE_SIZE
+    }
+
+    /// A small number that identifies this stack while it is in use
+    pub fn id(&self) -> usize {
+        self.slot
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        STACK_REGION.lock().slots[self.slot].in_use = false;
+    }
+}
+
+/// Returns the physical address vaddr maps to in the address space loaded on the calling core.
+/// Takes no locks and allocates nothing, so it is safe to call while handling a fault.
+pub fn translate(vaddr: u64) -> Option<u64> {
+    let root = phys_to_virt(unsafe { machine::get_cr3() } & !(PAGE_SIZE - 1));
+    unsafe { (*(root as *mut AddressSpace)).translate(vaddr) }
+}
+
+/// Returns true if vaddr is mapped in the address space loaded on the calling core
+pub fn is_mapped(vaddr: u64) -> bool {
+    translate(vaddr).is_some()
+}
+
+/// If addr lies in the guard page of a kernel stack, returns the id of that stack.
+/// Never blocks, so it is safe to call from the page fault path.
+pub fn stack_guard_hit(addr: u64) -> Option<usize> {
+    if addr < KERNEL_STACK_REGION_START || addr >= KERNEL_STACK_REGION_END {
+        return None;
+    }
+    let region = STACK_REGION.try_lock()?;
+    region
+        .slots
+        .iter()
+        .position(|slot| addr >= slot.guard_page && addr < slot.guard_page + PAGE_SIZE)
+}
+
 bitfield! {
     /**
     * Represents a memory address in terms of its indices into
@@ -177,67 +568,204 @@ bitfield! {
     huge, set_huge: 7, 7;
     u64;
     physical_addr, set_physical_addr: 51, 12;
-
+    write_through, set_write_through: 3, 3;
+    cache_disable, set_cache_disable: 4, 4;
+    global, set_global: 8, 8;
+    no_execute, set_no_execute: 63, 63;
 }
 
 impl AddressSpaceEntry {
+    /// Applies every attribute in flags, clearing the ones it does not contain
+    fn set_flags(&mut self, flags: PageFlags) {
+        self.set_writable(flags.contains(PageFlags::WRITABLE) as u64);
+        self.set_user_supervisor(flags.contains(PageFlags::USER) as u64);
+        self.set_write_through(flags.contains(PageFlags::WRITE_THROUGH) as u64);
+        self.set_cache_disable(flags.contains(PageFlags::CACHE_DISABLE) as u64);
+        self.set_global(flags.contains(PageFlags::GLOBAL) as u64);
+        self.set_no_execute(flags.contains(PageFlags::NO_EXECUTE) as u64);
+    }
+
+    /// The attributes set_flags applied
+    fn flags(&self) -> PageFlags {
+        let attributes = [
+            (self.writable(), PageFlags::WRITABLE),
+            (self.user_supervisor(), PageFlags::USER),
+            (self.write_through(), PageFlags::WRITE_THROUGH),
+            (self.cache_disable(), PageFlags::CACHE_DISABLE),
+            (self.global(), PageFlags::GLOBAL),
+            (self.no_execute(), PageFlags::NO_EXECUTE),
+        ];
+        attributes
+            .iter()
+            .filter(|(set, _)| *set == 1)
+            .fold(PageFlags::empty(), |flags, (_, flag)| flags | *flag)
+    }
+
     pub fn get_address_space(&self) -> &mut AddressSpace {
-        unsafe { &mut *((self.physical_addr() * PAGE_SIZE) as *mut AddressSpace) }
+        unsafe { &mut *(phys_to_virt(self.physical_addr() * PAGE_SIZE) as *mut AddressSpace) }
     }
 }
 
-static VMM_ALLOCATOR: Mutex<VMAllocator> = spin::Mutex::new(VMAllocator {
-    next: 0,
-    start_phys_mem: 0x1000000,
-    end_phys_mem: 0,
-});
-static PAGE_SIZE: u64 = 0x1000;
+pub const PAGE_SIZE: u64 = 0x1000;
+pub const HUGE_PAGE_SIZE: u64 = 0x200000;
+
+const EFER_MSR: u32 = 0xC0000080;
+const EFER_NXE: u64 = 1 << 11;
+
+/// Allows PageFlags::NO_EXECUTE to be used on the calling core
+fn enable_no_execute() {
+    unsafe {
+        machine::wrmsr(machine::rdmsr(EFER_MSR) | EFER_NXE, EFER_MSR);
+    }
+}
+
+/*
+ * TLB shootdown. The initiator publishes the range and bumps the generation,
+ * then waits until every other online core has acknowledged that generation.
+ * Cores acknowledge from the IPI handler, and also whenever they pass through the
+ * scheduler, since a core running with interrupts disabled would never take the IPI.
+ */
+pub const TLB_SHOOTDOWN_VECTOR: usize = 0xF0;
+/// Past this many pages a full flush is cheaper than invlpg on each page
+const FULL_FLUSH_THRESHOLD: u64 = 32;
+
+static SHOOTDOWN_LOCK: SpinLock = SpinLock::new();
+static SHOOTDOWN_START: AtomicU64 = AtomicU64::new(0);
+static SHOOTDOWN_PAGES: AtomicU64 = AtomicU64::new(0);
+static SHOOTDOWN_GENERATION: AtomicU64 = AtomicU64::new(0);
+const NOT_ACKED: AtomicU64 = AtomicU64::new(0);
+static SHOOTDOWN_ACKED: [AtomicU64; smp::MAX_CORES] = [NOT_ACKED; smp::MAX_CORES];
+
+fn invalidate_local(start: u64, pages: u64) {
+    unsafe {
+        if pages > FULL_FLUSH_THRESHOLD {
+            machine::load_cr3(machine::get_cr3());
+        } else {
+            for page in 0..pages {
+                machine::invlpg(start + page * PAGE_SIZE);
+            }
+        }
+    }
+}
+
+/// Performs any shootdown this core has not acknowledged yet
+pub fn handle_shootdown() {
+    let me = smp::me();
+    let generation = SHOOTDOWN_GENERATION.load(Ordering::SeqCst);
+    if SHOOTDOWN_ACKED[me].load(Ordering::SeqCst) < generation {
+        invalidate_local(
+            SHOOTDOWN_START.load(Ordering::SeqCst),
+            SHOOTDOWN_PAGES.load(Ordering::SeqCst),
+        );
+        SHOOTDOWN_ACKED[me].store(generation, Ordering::SeqCst);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn tlb_shootdown_handler() {
+    handle_shootdown();
+    let lapic = unsafe {
+        match &smp::LAPIC {
+            Some(lapic) => lapic,
+            None => panic!("No LAPIC available"),
+        }
+    };
+    unsafe {
+        core::ptr::write_volatile(lapic.eoi_reg, 0);
+    }
+}
+
+/// Invalidates pages starting at start on every online core, and returns once all have done so
+pub fn flush_tlb(start: u64, pages: u64) {
+    invalidate_local(start, pages);
+    let me = smp::me();
+    let others_online = (0..smp::MAX_CORES).any(|core| core != me && smp::is_online(core));
+    if !others_online {
+        return;
+    }
+    // Keep acknowledging other initiators while waiting, or two initiators would deadlock
+    let was = loop {
+        match SHOOTDOWN_LOCK.try_lock() {
+            Some(was) => break was,
+            None => handle_shootdown(),
+        }
+    };
+    SHOOTDOWN_START.store(start, Ordering::SeqCst);
+    SHOOTDOWN_PAGES.store(pages, Ordering::SeqCst);
+    let generation = SHOOTDOWN_GENERATION.fetch_add(1, Ordering::SeqCst) + 1;
+    SHOOTDOWN_ACKED[me].store(generation, Ordering::SeqCst);
+    Apic::with_base(unsafe { CONFIG.local_apic as usize })
+        .send_ipi_all_excluding_self(TLB_SHOOTDOWN_VECTOR as u8);
+    for core in 0..smp::MAX_CORES {
+        if core != me && smp::is_online(core) {
+            while SHOOTDOWN_ACKED[core].load(Ordering::SeqCst) < generation {
+                core::sync::atomic::spin_loop_hint();
+            }
+        }
+    }
+    SHOOTDOWN_LOCK.unlock(was);
+}
 
+/// The address space each core runs kernel threads in, set by init and init_ap
+static mut CORE_ADDRESS_SPACE: [Option<*const AddressSpace>; smp::MAX_CORES] =
+    [None; smp::MAX_CORES];
+
+/// Requires pmm::init
 pub fn init() {
-    {
-        let mut vmm_allocator = VMM_ALLOCATOR.lock();
-        vmm_allocator.end_phys_mem = unsafe { CONFIG.high_phys_mem };
-        if vmm_allocator.end_phys_mem < vmm_allocator.start_phys_mem {
-            panic!("Failed in VMM init: not enough physical memory on the system");
-        }
-        println!("end_phys_mem: {:x}", vmm_allocator.end_phys_mem);
-    }
-    lazy_static::initialize(&IDENTITY_MAP);
-    println!("Creating new address space...");
-    let new_address_space = AddressSpace::new_with_identity();
-    println!("Switching to new address space...");
+    enable_no_execute();
+    idt::interrupt(TLB_SHOOTDOWN_VECTOR, machine::_tlb_shootdown_handler);
+    lazy_static::initialize(&KERNEL_MAP);
+    debug!("Creating new address space...");
+    let new_address_space = AddressSpace::new_with_kernel();
+    debug!("Switching to new address space...");
     new_address_space.activate();
-    println!("Running with a new address space!");
+    unsafe {
+        CORE_ADDRESS_SPACE[smp::me()] = Some(new_address_space as *const AddressSpace);
+    }
+    debug!("Running with a new address space!");
 }
 
 pub fn init_ap() {
-    println!("called vmm init ap");
-    let new_address_space = AddressSpace::new_with_identity();
+    debug!("called vmm init ap");
+    enable_no_execute();
+    let new_address_space = AddressSpace::new_with_kernel();
     //new_address_space.create_mapping(0xfee00, 0xfee00);
     new_address_space.activate();
+    unsafe {
+        CORE_ADDRESS_SPACE[smp::me()] = Some(new_address_space as *const AddressSpace);
+    }
 }
 
+/// Switches the calling core back to its own kernel address space,
+/// so that no process address space stays loaded while a kernel thread runs
+pub fn load_kernel_address_space() {
+    unsafe {
+        if let Some(address_space) = CORE_ADDRESS_SPACE[smp::me()] {
+            (*address_space).load();
+        }
+    }
+}
+
+/// Allocates a zeroed physical frame
 pub fn alloc() -> u64 {
-    let mut vmm_allocator = VMM_ALLOCATOR.lock();
-    let result: u64;
-    if vmm_allocator.start_phys_mem != vmm_allocator.end_phys_mem {
-        result = vmm_allocator.start_phys_mem;
-        vmm_allocator.start_phys_mem += PAGE_SIZE;
-    } else {
-        panic!("Ran out of fresh frames to allocate");
+    match try_alloc() {
+        Some(frame) => frame,
         // TODO: Demand paging
-        if vmm_allocator.next == 0 {
-            panic!("Out of physical frames.");
-        }
-        result = vmm_allocator.next;
-        let ptr = result as *mut u64;
-        unsafe {
-            vmm_allocator.next = *ptr;
-        }
+        None => panic!("Out of physical frames."),
     }
+}
+
+/// Allocates a zeroed physical frame, or returns None if none are free
+pub fn try_alloc() -> Option<u64> {
+    let result = pmm::alloc_frame()?;
     unsafe {
-        core::ptr::write_bytes(result as *mut u8, 0, PAGE_SIZE as usize);
+        core::ptr::write_bytes(phys_to_virt(result) as *mut u8, 0, PAGE_SIZE as usize);
     }
-    println!("allocated frame 0x{:x}", result);
-    result
+    trace!("allocated frame 0x{:x}", result);
+    Some(result)
+}
+
+/// Returns a frame obtained from alloc
+pub fn free(frame: u64) {
+    pmm::free_frame(frame);
 }
@@ -0,0 +1,57 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(box_syntax)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(crate::test_runner)]
+
+extern crate alloc;
+
+use oxos::acpi;
+use oxos::acpi::Fadt;
+use oxos::config::mb_info;
+use oxos::config::CONFIG;
+use oxos::config::MADT;
+use oxos::kernel_init;
+use oxos::machine;
+use oxos::{print, println};
+
+fn test_runner(tests: &[&dyn Fn()]) {
+    unimplemented!("test_runner not used so should never be called");
+}
+
+#[no_mangle]
+pub extern "C" fn _start(mb_config: &mb_info, end: u64) -> ! {
+    kernel_init(mb_config, end);
+    acpi_test();
+}
+
+pub fn acpi_test() -> ! {
+    println!("Running acpi test");
+    assert_eq!(acpi::checksum(&[]), 0);
+    assert_eq!(acpi::checksum(&[1, 2, 3]), 250);
+    assert_eq!(acpi::checksum(&[1, 2, 3, 250]), 0);
+    assert_eq!(acpi::checksum(&[0xff, 0xff, 2]), 0);
+
+    let rsdp = unsafe { CONFIG.rsdp.unwrap() };
+    assert!(rsdp.is_valid());
+
+    // Only tables with good checksums are registered
+    assert!(acpi::tables().all(|table| table.is_valid()));
+    let root = acpi::tables().next().unwrap();
+    match rsdp.xsdt_address() {
+        Some(_) => assert_eq!(root.signature(), b"XSDT"),
+        None => assert_eq!(root.signature(), b"RSDT"),
+    }
+
+    assert!(acpi::find::<MADT>().is_some());
+    let fadt = acpi::find::<Fadt>().unwrap();
+    assert_eq!(fadt.header.signature(), b"FACP");
+    // The DSDT is registered through the FADT
+    let dsdt = acpi::find_signature(b"DSDT").unwrap();
+    assert!(dsdt.is_valid());
+    assert!(acpi::find_signature(b"NONE").is_none());
+
+    println!("Acpi Test PASSED");
+    machine::exit(machine::EXIT_QEMU_SUCCESS);
+}
@@ -11,10 +11,12 @@ use oxos::config::mb_info;
 use oxos::kernel_init;
 use oxos::machine;
 use oxos::thread;
-use oxos::thread::TCBImpl;
+use oxos::thread::JoinError;
 use oxos::{print, println};
 
 use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::Cell;
 use core::sync::atomic::{AtomicU32, Ordering};
 
 fn test_runner(tests: &[&dyn Fn()]) {
@@ -31,18 +33,42 @@ pub extern "C" fn _start(mb_config: &mb_info, end: u64) -> ! {
 pub fn adder_test() -> ! {
     println!("scheduling threads");
     let counter = Arc::new(AtomicU32::new(0));
-    for i in 0..100 {
+    let mut handles = Vec::new();
+    for i in 0..100u32 {
         let c = Arc::clone(&counter);
-        let x = TCBImpl::new(box move || {
+        handles.push(thread::spawn(move || {
             c.fetch_add(1, Ordering::SeqCst);
-        });
-        thread::schedule(box x);
+            i
+        }));
     }
     println!("scheduled all threads");
-    while counter.load(Ordering::SeqCst) < 100 {}
+    // Every thread has finished once its join returns
+    let sum: u32 = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .sum();
     println!("counter: {}", counter.load(Ordering::SeqCst));
-    let result = counter.load(Ordering::SeqCst);
-    assert_eq!(result, 100);
+    assert_eq!(counter.load(Ordering::SeqCst), 100);
+    assert_eq!(sum, (0..100).sum::<u32>());
+
+    // Threads can spawn and join threads of their own
+    let nested = thread::spawn(|| thread::spawn(|| 21).join().unwrap() * 2);
+    assert_eq!(nested.join(), Ok(42));
+
+    // The closure only has to be Send, as it never runs on two threads at once
+    let cell = Cell::new(20);
+    let unshared = thread::spawn(move || {
+        cell.set(cell.get() + 1);
+        cell.get() * 2
+    });
+    assert_eq!(unshared.join(), Ok(42));
+
+    // A thread that stops before its closure returns has no result
+    let stopped = thread::spawn(|| {
+        thread::stop();
+        42
+    });
+    assert_eq!(stopped.join(), Err(JoinError::Incomplete));
     println!("Adder Test PASSED");
     machine::exit(machine::EXIT_QEMU_SUCCESS);
 }
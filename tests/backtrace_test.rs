@@ -0,0 +1,110 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(box_syntax)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(crate::test_runner)]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use oxos::backtrace;
+use oxos::config::mb_info;
+use oxos::kernel_init;
+use oxos::machine;
+use oxos::semaphore::Semaphore;
+use oxos::thread;
+use oxos::thread::{TCBImpl, TCBInfo, TCB};
+use oxos::vmm;
+use oxos::{print, println};
+
+fn test_runner(tests: &[&dyn Fn()]) {
+    unimplemented!("test_runner not used so should never be called");
+}
+
+#[no_mangle]
+pub extern "C" fn _start(mb_config: &mb_info, end: u64) -> ! {
+    kernel_init(mb_config, end);
+    backtrace_test();
+}
+
+static TABLE: &str = "ffffffff80100000 T _start
+ffffffff80100040 t oxos::thread::block
+ffffffff80100100 d oxos::CONFIG
+ffffffff80100200 T <oxos::u8250::U8250 as core::fmt::Write>::write_str
+";
+
+#[inline(never)]
+fn outer(frames: &mut Vec<u64>) {
+    middle(frames);
+}
+
+#[inline(never)]
+fn middle(frames: &mut Vec<u64>) {
+    inner(frames);
+}
+
+#[inline(never)]
+fn inner(frames: &mut Vec<u64>) {
+    backtrace::walk(unsafe { machine::get_rbp() }, |address| {
+        frames.push(address)
+    });
+}
+
+pub fn backtrace_test() -> ! {
+    println!("Running backtrace test");
+    assert_eq!(
+        backtrace::lookup(TABLE, 0xffffffff80100000),
+        Some(("_start", 0))
+    );
+    assert_eq!(
+        backtrace::lookup(TABLE, 0xffffffff80100050),
+        Some(("oxos::thread::block", 0x10))
+    );
+    // Data symbols are skipped
+    assert_eq!(
+        backtrace::lookup(TABLE, 0xffffffff80100180),
+        Some(("oxos::thread::block", 0x140))
+    );
+    assert_eq!(
+        backtrace::lookup(TABLE, 0xffffffff80100208),
+        Some(("<oxos::u8250::U8250 as core::fmt::Write>::write_str", 8))
+    );
+    assert_eq!(backtrace::lookup(TABLE, 0x1000), None);
+
+    let mut frames = Vec::new();
+    outer(&mut frames);
+    // inner returns to middle, middle to outer, outer to backtrace_test, and so on up to _start
+    assert!(frames.len() >= 4);
+    assert!(frames.iter().all(|address| *address >= vmm::KERNEL_BASE));
+    backtrace::print_current();
+
+    // A thread blocked on a semaphore is traced from where it switched out
+    let sem = Semaphore::new(0);
+    let waiter_sem = Arc::clone(&sem);
+    let mut waiter = box TCBImpl::new(box move || waiter_sem.down());
+    let info = waiter.get_info() as usize;
+    let saved = || unsafe { (*(info as *const TCBInfo)).saved_stack_pointer() };
+    assert_eq!(saved(), None);
+    thread::schedule(waiter);
+    // Until it has switched out there is nothing to walk. If it is preempted on the way
+    // to down and runs again during the walk, the walk is retried.
+    let frames = loop {
+        if let Some(stack_pointer) = saved() {
+            let mut frames = Vec::new();
+            backtrace::walk_switched(stack_pointer, |address| frames.push(address));
+            if saved() == Some(stack_pointer) {
+                break frames;
+            }
+        }
+    };
+    // context_switch returns to block, block to down, and so on up to thread_entry_point
+    assert!(frames.len() >= 3);
+    assert!(frames.iter().all(|address| *address >= vmm::KERNEL_BASE));
+    thread::print_backtraces();
+    // The waiter is freed once it finishes, so its info is not read after this
+    sem.up();
+    println!("Backtrace Test PASSED");
+    machine::exit(machine::EXIT_QEMU_SUCCESS);
+}
@@ -0,0 +1,61 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(box_syntax)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(crate::test_runner)]
+
+extern crate alloc;
+
+use oxos::boot_options::{BootOptions, PciDump, SchedPolicy, TimerMode};
+use oxos::config::mb_info;
+use oxos::kernel_init;
+use oxos::log::LogLevel;
+use oxos::machine;
+use oxos::{print, println};
+
+fn test_runner(tests: &[&dyn Fn()]) {
+    unimplemented!("test_runner not used so should never be called");
+}
+
+#[no_mangle]
+pub extern "C" fn _start(mb_config: &mb_info, end: u64) -> ! {
+    kernel_init(mb_config, end);
+    boot_options_test();
+}
+
+pub fn boot_options_test() -> ! {
+    println!("Running boot options test");
+    assert_eq!(BootOptions::parse(""), BootOptions::new());
+
+    let options = BootOptions::parse(
+        "smp=2 hz=250 timer=oneshot sched=priority log=debug test=semaphore pci=summary",
+    );
+    assert_eq!(options.smp, Some(2));
+    assert_eq!(options.hz, 250);
+    assert_eq!(options.timer, Some(TimerMode::OneShot));
+    assert_eq!(options.sched, SchedPolicy::Priority);
+    assert_eq!(
+        BootOptions::parse("sched=fair").sched,
+        SchedPolicy::FairShare
+    );
+    assert_eq!(options.log, LogLevel::Debug);
+    assert_eq!(options.test, Some("semaphore"));
+    assert_eq!(options.pci, PciDump::Summary);
+
+    let options = BootOptions::parse("log.pci=warn log.thread=trace log.pci=off");
+    assert_eq!(options.log_targets[0], Some(("pci", LogLevel::Off)));
+    assert_eq!(options.log_targets[1], Some(("thread", LogLevel::Trace)));
+    assert_eq!(options.log_targets[2], None);
+
+    // Bad values and unknown options leave the defaults alone
+    let options = BootOptions::parse(
+        "smp=0 hz=fast timer=slow sched=lottery log=loud log.=info log.vmm=loud color=blue quiet",
+    );
+    assert_eq!(options, BootOptions::new());
+
+    // The last occurrence wins
+    assert_eq!(BootOptions::parse("hz=100 hz=200").hz, 200);
+    println!("Boot Options Test PASSED");
+    machine::exit(machine::EXIT_QEMU_SUCCESS);
+}
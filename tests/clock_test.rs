@@ -0,0 +1,77 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(box_syntax)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(crate::test_runner)]
+
+extern crate alloc;
+
+use oxos::clock;
+use oxos::config::mb_info;
+use oxos::hpet;
+use oxos::kernel_init;
+use oxos::machine;
+use oxos::timer;
+use oxos::{print, println};
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+fn test_runner(tests: &[&dyn Fn()]) {
+    unimplemented!("test_runner not used so should never be called");
+}
+
+#[no_mangle]
+pub extern "C" fn _start(mb_config: &mb_info, end: u64) -> ! {
+    kernel_init(mb_config, end);
+    clock_test();
+}
+
+pub fn clock_test() -> ! {
+    println!("Running clock test");
+    // QEMU has an HPET, so it is the clock
+    let hpet = hpet::get().unwrap();
+    assert!(hpet.period() > 0);
+    assert_eq!(hpet.to_nanos(0), 0);
+    assert!(clock::tsc_hz() > 0);
+
+    let mut last = clock::now();
+    for _ in 0..10_000 {
+        let now = clock::now();
+        assert!(now >= last);
+        last = now;
+    }
+
+    // The TSC, calibrated against the clock, agrees with it
+    let tsc_start = unsafe { machine::rdtsc() };
+    let start = clock::now();
+    while clock::now() - start < 10 * clock::NANOS_PER_MILLI {
+        core::sync::atomic::spin_loop_hint();
+    }
+    let cycles = unsafe { machine::rdtsc() } - tsc_start;
+    let expected = clock::tsc_hz() / 100;
+    assert!(cycles > expected / 2 && cycles < expected * 2);
+
+    // So does the local APIC timer: a timer counting at the wrong rate fires
+    // late by a share of how long it was set for
+    for millis in [1, 5, 20, 50].iter() {
+        let fired = Arc::new(AtomicU64::new(0));
+        let fired_at = Arc::clone(&fired);
+        let id = timer::after(Duration::from_millis(*millis), move || {
+            fired_at.store(clock::now(), Ordering::SeqCst);
+        });
+        while fired.load(Ordering::SeqCst) == 0 {
+            core::sync::atomic::spin_loop_hint();
+        }
+        let fired = fired.load(Ordering::SeqCst);
+        assert!(fired >= id.deadline());
+        let late = fired - id.deadline();
+        println!("a {} ms timer fired {} ns late", millis, late);
+        assert!(late < millis * clock::NANOS_PER_MILLI / 4 + 5 * clock::NANOS_PER_MILLI);
+    }
+
+    println!("Clock Test PASSED");
+    machine::exit(machine::EXIT_QEMU_SUCCESS);
+}
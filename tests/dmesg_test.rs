@@ -0,0 +1,89 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(box_syntax)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(crate::test_runner)]
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use oxos::config::mb_info;
+use oxos::dmesg;
+use oxos::kernel_init;
+use oxos::log;
+use oxos::log::LogLevel;
+use oxos::machine;
+use oxos::{debug, info, trace, warn};
+use oxos::{print, println};
+
+fn test_runner(tests: &[&dyn Fn()]) {
+    unimplemented!("test_runner not used so should never be called");
+}
+
+#[no_mangle]
+pub extern "C" fn _start(mb_config: &mb_info, end: u64) -> ! {
+    kernel_init(mb_config, end);
+    dmesg_test();
+}
+
+/// The entries still held whose text contains marker, oldest first.
+/// Other cores log too, so entries are found by what they say, not where they are.
+fn entries_with(marker: &str) -> Vec<dmesg::Entry> {
+    let mut entries = Vec::new();
+    dmesg::for_each_recent(usize::MAX, |entry| {
+        if entry.text().contains(marker) {
+            entries.push(*entry);
+        }
+    });
+    entries
+}
+
+pub fn dmesg_test() -> ! {
+    println!("Running dmesg test");
+    for i in 0..3 {
+        info!("dmesg-marker-entry {}", i);
+    }
+    warn!("dmesg-marker-entry last");
+
+    let entries = entries_with("dmesg-marker-entry");
+    assert_eq!(entries.len(), 4);
+    assert_eq!(entries[0].text(), "dmesg_test: dmesg-marker-entry 0");
+    assert_eq!(entries[2].text(), "dmesg_test: dmesg-marker-entry 2");
+    assert_eq!(entries[3].text(), "dmesg_test: dmesg-marker-entry last");
+    assert_eq!(entries[3].level, LogLevel::Warn);
+    assert!(entries.windows(2).all(|pair| pair[0].seq < pair[1].seq));
+
+    // Debug records are kept even when the filter drops them, trace records are not
+    log::set_level(LogLevel::Info);
+    debug!("dmesg-marker-debug");
+    trace!("dmesg-marker-trace");
+    assert_eq!(entries_with("dmesg-marker-debug").len(), 1);
+    assert_eq!(entries_with("dmesg-marker-trace").len(), 0);
+
+    // So is print! output, without its newline
+    println!("dmesg-marker-println");
+    let printed = entries_with("dmesg-marker-println");
+    assert_eq!(printed.len(), 1);
+    assert_eq!(printed[0].text(), "dmesg-marker-println");
+
+    // Messages too long for an entry are cut short
+    let long: String = core::iter::repeat('x').take(2 * dmesg::MAX_TEXT).collect();
+    info!("dmesg-marker-long {}", long);
+    let long_entries = entries_with("dmesg-marker-long");
+    assert_eq!(long_entries.len(), 1);
+    assert_eq!(long_entries[0].text().len(), dmesg::MAX_TEXT);
+
+    // More entries than a ring holds only keeps the newest
+    for i in 0..2 * dmesg::RING_ENTRIES {
+        info!("filler {}", i);
+    }
+    assert_eq!(entries_with("dmesg-marker-entry").len(), 0);
+    let mut count = 0;
+    dmesg::for_each_recent(usize::MAX, |_| count += 1);
+    assert!(count >= dmesg::RING_ENTRIES);
+    dmesg::dump(4);
+    println!("Dmesg Test PASSED");
+    machine::exit(machine::EXIT_QEMU_SUCCESS);
+}
@@ -0,0 +1,100 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(box_syntax)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(crate::test_runner)]
+
+extern crate alloc;
+
+use oxos::config::mb_info;
+use oxos::elf;
+use oxos::elf::ElfError;
+use oxos::kernel_init;
+use oxos::machine;
+use oxos::pmm;
+use oxos::{print, println};
+
+use alloc::vec::Vec;
+
+/// Built from src/user/args.S by build.rs
+static ARGS: &[u8] = include_bytes!("../BUILD_FILES/args.elf");
+const ARGUMENT: &str = "elf loader";
+
+/// Offsets into the ELF and program headers
+const PHOFF: usize = 0x20;
+const PHENTSIZE: usize = 0x36;
+const PHNUM: usize = 0x38;
+const P_MEMSZ: usize = 0x28;
+const PT_LOAD: u32 = 1;
+
+fn read_u16(image: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([image[offset], image[offset + 1]])
+}
+
+/// Returns a copy of ARGS whose last loadable segment claims memsz bytes
+fn with_memsz(memsz: u64) -> Vec<u8> {
+    let mut image = ARGS.to_vec();
+    let mut phoff = [0; 8];
+    phoff.copy_from_slice(&image[PHOFF..PHOFF + 8]);
+    let phoff = u64::from_le_bytes(phoff) as usize;
+    let phentsize = read_u16(&image, PHENTSIZE) as usize;
+    let last_load = (0..read_u16(&image, PHNUM) as usize)
+        .map(|i| phoff + i * phentsize)
+        .filter(|&header| {
+            let segment_type = u32::from_le_bytes([
+                image[header],
+                image[header + 1],
+                image[header + 2],
+                image[header + 3],
+            ]);
+            segment_type == PT_LOAD
+        })
+        .last();
+    let header = match last_load {
+        Some(header) => header,
+        None => panic!("args has no loadable segment"),
+    };
+    image[header + P_MEMSZ..header + P_MEMSZ + 8].copy_from_slice(&memsz.to_le_bytes());
+    image
+}
+
+fn test_runner(tests: &[&dyn Fn()]) {
+    unimplemented!("test_runner not used so should never be called");
+}
+
+#[no_mangle]
+pub extern "C" fn _start(mb_config: &mb_info, end: u64) -> ! {
+    kernel_init(mb_config, end);
+    elf_test();
+}
+
+pub fn elf_test() -> ! {
+    println!("Running ELF test");
+    assert_eq!(
+        elf::spawn(&[0; 64], &[], &[]).err(),
+        Some(ElfError::BadMagic)
+    );
+    assert_eq!(
+        elf::spawn(&ARGS[..32], &[], &[]).err(),
+        Some(ElfError::Truncated)
+    );
+
+    // A segment bigger than memory runs out of frames, and gives back every one it took
+    let free_frames = pmm::stats().free_frames;
+    assert_eq!(
+        elf::spawn(&with_memsz(1 << 44), &[], &[]).err(),
+        Some(ElfError::OutOfMemory)
+    );
+    assert_eq!(pmm::stats().free_frames, free_frames);
+
+    let process = match elf::spawn(ARGS, &["args", ARGUMENT], &["OXOS=1"]) {
+        Ok(process) => process,
+        Err(error) => panic!("Failed to load args: {:?}", error),
+    };
+    let exit_code = process.wait();
+    println!("process {} exited with {}", process.pid(), exit_code);
+    assert_eq!(exit_code, ARGUMENT.len() as i64);
+    println!("ELF Test PASSED");
+    machine::exit(machine::EXIT_QEMU_SUCCESS);
+}
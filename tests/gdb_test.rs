@@ -0,0 +1,281 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(box_syntax)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(crate::test_runner)]
+
+extern crate alloc;
+
+use oxos::config::mb_info;
+use oxos::gdb;
+use oxos::idt::TrapFrame;
+use oxos::kernel_init;
+use oxos::machine;
+use oxos::process;
+use oxos::{print, println};
+
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+const TRAP_FLAG: u64 = 1 << 8;
+
+static BYTES: [u8; 4] = [0xde, 0xad, 0xbe, 0xef];
+static mut SCRATCH: [u8; 4] = [0; 4];
+
+fn test_runner(tests: &[&dyn Fn()]) {
+    unimplemented!("test_runner not used so should never be called");
+}
+
+#[no_mangle]
+pub extern "C" fn _start(mb_config: &mb_info, end: u64) -> ! {
+    kernel_init(mb_config, end);
+    gdb_test();
+}
+
+#[inline(never)]
+fn target() -> u64 {
+    42
+}
+
+/// What gdb would send, and what the stub must answer
+struct Script {
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+    expected: Vec<u8>,
+}
+
+impl Script {
+    fn new() -> Script {
+        Script {
+            input: VecDeque::new(),
+            output: Vec::new(),
+            expected: Vec::new(),
+        }
+    }
+
+    /// Sends data as is, expecting the stub to answer with response
+    fn raw(&mut self, data: &str, response: &str) {
+        self.input.extend(data.bytes());
+        self.expected.extend(response.bytes());
+    }
+
+    /// Sends a packet, and acknowledges the reply
+    fn ask(&mut self, data: &str, reply: &str) {
+        self.raw(&packet(data), &format!("+{}", packet(reply)));
+        self.raw("+", "");
+    }
+
+    /// Sends a packet that continues or steps, which the stub only acknowledges
+    fn resume(&mut self, data: &str) {
+        self.raw(&packet(data), "+");
+    }
+
+    /// Lets the stub answer the script as core 0, stopped with frame
+    fn run(mut self, frame: &mut TrapFrame) -> bool {
+        let resumed = gdb::serve(&mut self, 0, frame);
+        assert!(self.input.is_empty(), "the stub stopped reading early");
+        assert_eq!(
+            core::str::from_utf8(&self.output),
+            core::str::from_utf8(&self.expected)
+        );
+        resumed
+    }
+}
+
+impl gdb::Link for Script {
+    fn get(&mut self) -> u8 {
+        self.input
+            .pop_front()
+            .expect("the stub read past the script")
+    }
+
+    fn put(&mut self, byte: u8) {
+        self.output.push(byte);
+    }
+}
+
+fn packet(data: &str) -> String {
+    format!("${}#{:02x}", data, gdb::checksum(data.as_bytes()))
+}
+
+/// The low bytes of value as hex, in target order
+fn le_hex(value: u64, bytes: usize) -> String {
+    (0..bytes)
+        .map(|i| format!("{:02x}", (value >> (8 * i)) as u8))
+        .collect()
+}
+
+fn hex_text(text: &str) -> String {
+    text.bytes().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn new_frame() -> TrapFrame {
+    TrapFrame {
+        cr2: 0,
+        rax: 0x1,
+        rbx: 0x2,
+        rcx: 0x3,
+        rdx: 0x4,
+        rsi: 0x5,
+        rdi: 0x6,
+        rbp: 0x7,
+        r8: 0x8,
+        r9: 0x9,
+        r10: 0xa,
+        r11: 0xb,
+        r12: 0xc,
+        r13: 0xd,
+        r14: 0xe,
+        r15: 0xf,
+        vector: 3,
+        error_code: 0,
+        rip: 0xffff_ffff_8010_0000,
+        cs: 0x8,
+        rflags: 0x202,
+        rsp: 0xffff_fe00_0000_1000,
+        ss: 0x10,
+    }
+}
+
+/// The general purpose registers in g packet order
+fn registers(frame: &TrapFrame) -> [u64; 17] {
+    [
+        frame.rax, frame.rbx, frame.rcx, frame.rdx, frame.rsi, frame.rdi, frame.rbp, frame.rsp,
+        frame.r8, frame.r9, frame.r10, frame.r11, frame.r12, frame.r13, frame.r14, frame.r15,
+        frame.rip,
+    ]
+}
+
+/// Packets are only taken once their checksum matches, and replies are sent until acknowledged
+fn packet_test() {
+    let mut frame = new_frame();
+    let mut script = Script::new();
+    script.raw("junk$?#00", "-");
+    script.raw(&packet("?"), &format!("+{}", packet("T05thread:1;")));
+    script.raw("-", &packet("T05thread:1;"));
+    script.raw("+", "");
+    script.ask("vMustReplyEmpty", "");
+    script.resume("c");
+    assert!(script.run(&mut frame));
+    assert_eq!(frame.rip, new_frame().rip);
+    assert_eq!(frame.rflags & TRAP_FLAG, 0);
+}
+
+fn register_test() {
+    let mut frame = new_frame();
+    let mut script = Script::new();
+    let mut g = String::new();
+    for register in registers(&frame).iter() {
+        g.push_str(&le_hex(*register, 8));
+    }
+    for segment in [frame.rflags, frame.cs, frame.ss, frame.ss, frame.ss, 0, 0].iter() {
+        g.push_str(&le_hex(*segment, 4));
+    }
+    assert_eq!(g.len(), 17 * 16 + 7 * 8);
+    script.ask("g", &g);
+
+    let mut values = String::new();
+    for i in 0..17u64 {
+        values.push_str(&le_hex(0x1122_3344_5566_0000 + i, 8));
+    }
+    script.ask(&format!("G{}{}", values, le_hex(0x246, 4)), "OK");
+    // Every register must be there
+    script.ask(&format!("G{}", &values[..16 * 16]), "E22");
+    script.ask("Gzz", "E22");
+    script.ask("D", "OK");
+    assert!(!script.run(&mut frame));
+    for (i, register) in registers(&frame).iter().enumerate() {
+        assert_eq!(*register, 0x1122_3344_5566_0000 + i as u64);
+    }
+    assert_eq!(frame.rflags, 0x246);
+}
+
+fn memory_test() {
+    let mut frame = new_frame();
+    let mut script = Script::new();
+    let bytes = BYTES.as_ptr() as u64;
+    let scratch = unsafe { SCRATCH.as_ptr() as u64 };
+    script.ask(&format!("m{:x},4", bytes), "deadbeef");
+    script.ask(&format!("m{:x},2", bytes + 1), "adbe");
+    script.ask(&format!("m{:x},4", process::MMAP_BASE), "E14");
+    script.ask(&format!("m{:x}", bytes), "E22");
+    script.ask("m,4", "E22");
+    script.ask(&format!("M{:x},3:123456", scratch), "OK");
+    // Too little data for the length
+    script.ask(&format!("M{:x},4:12", scratch), "E22");
+    script.ask(&format!("M{:x},1:12", process::MMAP_BASE), "E14");
+    script.ask(&format!("M{:x},1", scratch), "E22");
+    script.ask("D", "OK");
+    script.run(&mut frame);
+    assert_eq!(unsafe { SCRATCH[..3].to_vec() }, [0x12, 0x34, 0x56]);
+}
+
+fn thread_test() {
+    let mut frame = new_frame();
+    let mut script = Script::new();
+    // Only core 0 has stopped, and it is thread 1
+    script.ask("qfThreadInfo", "m1");
+    script.ask("qsThreadInfo", "l");
+    script.ask("qC", "QC1");
+    script.ask("qAttached", "1");
+    script.ask("qThreadExtraInfo,1", &hex_text("core 0"));
+    script.ask("T1", "OK");
+    script.ask("T2", "E01");
+    script.ask("Hg2", "E01");
+    script.ask("Hg1", "OK");
+    script.ask("Hg0", "OK");
+    script.ask("Hc-1", "OK");
+    script.ask("D", "OK");
+    script.run(&mut frame);
+}
+
+fn resume_test() {
+    // A step sets the trap flag, and either can move rip first
+    let mut frame = new_frame();
+    let mut script = Script::new();
+    script.resume("sffffffff80100010");
+    assert!(script.run(&mut frame));
+    assert_eq!(frame.rip, 0xffff_ffff_8010_0010);
+    assert_ne!(frame.rflags & TRAP_FLAG, 0);
+
+    let mut frame = new_frame();
+    let mut script = Script::new();
+    script.resume("cffffffff80100020");
+    assert!(script.run(&mut frame));
+    assert_eq!(frame.rip, 0xffff_ffff_8010_0020);
+    assert_eq!(frame.rflags & TRAP_FLAG, 0);
+}
+
+pub fn gdb_test() -> ! {
+    println!("Running gdb test");
+    assert!(!gdb::enabled());
+    assert_eq!(gdb::checksum(b"OK"), 0x9a);
+    assert_eq!(gdb::checksum(b""), 0);
+
+    // Breakpoints patch the code through the direct map, even though it is read only
+    let address = target as *const () as u64;
+    let original = gdb::read_byte(address).unwrap();
+    assert!(gdb::insert_breakpoint(address));
+    assert_eq!(gdb::read_byte(address), Some(0xcc));
+    // Inserting twice keeps the original byte
+    assert!(gdb::insert_breakpoint(address));
+    assert!(gdb::remove_breakpoint(address));
+    assert_eq!(gdb::read_byte(address), Some(original));
+    assert!(!gdb::remove_breakpoint(address));
+    assert_eq!(target(), 42);
+
+    assert_eq!(gdb::read_byte(process::MMAP_BASE), None);
+    assert!(!gdb::insert_breakpoint(process::MMAP_BASE));
+
+    // The protocol, against scripted packets
+    packet_test();
+    register_test();
+    memory_test();
+    thread_test();
+    resume_test();
+    println!("GDB Test PASSED");
+    machine::exit(machine::EXIT_QEMU_SUCCESS);
+}
@@ -0,0 +1,85 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(box_syntax)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(crate::test_runner)]
+
+extern crate alloc;
+
+use oxos::config::mb_info;
+use oxos::config::CONFIG;
+use oxos::irq;
+use oxos::irq::IrqError;
+use oxos::kernel_init;
+use oxos::machine;
+use oxos::smp;
+use oxos::{print, println};
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+static PIT_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+
+fn test_runner(tests: &[&dyn Fn()]) {
+    unimplemented!("test_runner not used so should never be called");
+}
+
+#[no_mangle]
+pub extern "C" fn _start(mb_config: &mb_info, end: u64) -> ! {
+    kernel_init(mb_config, end);
+    irq_test();
+}
+
+fn pit_interrupt() {
+    PIT_INTERRUPTS.fetch_add(1, Ordering::SeqCst);
+}
+
+fn other_handler() {}
+
+fn spin(iterations: u64) {
+    for _ in 0..iterations {
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+pub fn irq_test() -> ! {
+    println!("Running irq test");
+    unsafe {
+        assert!(CONFIG.io_apics[0].is_some());
+        assert_ne!(CONFIG.io_apic, 0);
+    }
+
+    let me = smp::me();
+    assert_eq!(
+        irq::register(irq::MAX_IRQS, other_handler, me),
+        Err(IrqError::NoSuchIrq)
+    );
+    assert_eq!(
+        irq::register(3, other_handler, smp::MAX_CORES),
+        Err(IrqError::NoSuchCore)
+    );
+    // The console took COM1's IRQ at boot
+    assert_eq!(irq::register(4, other_handler, me), Err(IrqError::InUse));
+    assert_eq!(irq::unregister(3), Err(IrqError::NoSuchIrq));
+
+    // The PIT is IRQ 0, which QEMU's MADT overrides to arrive on GSI 2
+    irq::register(0, pit_interrupt, me).unwrap();
+    unsafe {
+        // Channel 0, rate generator, at about 1000 Hz
+        machine::outb(0x43, 0b00110100);
+        machine::outb(0x40, 1193 & 0xff);
+        machine::outb(0x40, 1193 >> 8);
+    }
+    while PIT_INTERRUPTS.load(Ordering::SeqCst) < 10 {
+        core::sync::atomic::spin_loop_hint();
+    }
+    irq::unregister(0).unwrap();
+    // Once anything already in flight has landed, a masked IRQ stays quiet
+    spin(1_000_000);
+    let count = PIT_INTERRUPTS.load(Ordering::SeqCst);
+    spin(10_000_000);
+    assert_eq!(PIT_INTERRUPTS.load(Ordering::SeqCst), count);
+
+    println!("Irq Test PASSED");
+    machine::exit(machine::EXIT_QEMU_SUCCESS);
+}
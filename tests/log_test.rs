@@ -0,0 +1,77 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(box_syntax)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(crate::test_runner)]
+
+extern crate alloc;
+
+use oxos::boot_options::BootOptions;
+use oxos::config::mb_info;
+use oxos::kernel_init;
+use oxos::log;
+use oxos::log::LogLevel;
+use oxos::machine;
+use oxos::{debug, error, info, trace, warn};
+use oxos::{print, println};
+
+fn test_runner(tests: &[&dyn Fn()]) {
+    unimplemented!("test_runner not used so should never be called");
+}
+
+#[no_mangle]
+pub extern "C" fn _start(mb_config: &mb_info, end: u64) -> ! {
+    kernel_init(mb_config, end);
+    log_test();
+}
+
+pub fn log_test() -> ! {
+    println!("Running log test");
+    assert_eq!(LogLevel::parse("debug"), Some(LogLevel::Debug));
+    assert_eq!(LogLevel::parse("loud"), None);
+
+    log::set_level(LogLevel::Warn);
+    assert!(log::enabled(LogLevel::Error, module_path!()));
+    assert!(log::enabled(LogLevel::Warn, "oxos::vmm"));
+    assert!(!log::enabled(LogLevel::Info, "oxos::vmm"));
+    // Off is a filter, never a level to log at
+    log::set_level(LogLevel::Trace);
+    assert!(log::enabled(LogLevel::Trace, "oxos::thread"));
+    assert!(!log::enabled(LogLevel::Off, "oxos::thread"));
+
+    // Overrides from the command line apply to their target and the modules below it,
+    // and the longest matching one wins
+    let options = BootOptions::parse("log=warn log.vmm=debug log.vmm::walk=trace log.pci=off");
+    log::set_level(options.log);
+    log::set_targets(options.log_targets);
+    assert!(log::enabled(LogLevel::Debug, "oxos::vmm"));
+    assert!(!log::enabled(LogLevel::Trace, "oxos::vmm"));
+    assert!(log::enabled(LogLevel::Debug, "oxos::vmm::map"));
+    assert!(log::enabled(LogLevel::Trace, "oxos::vmm::walk"));
+    assert!(log::enabled(LogLevel::Trace, "oxos::vmm::walk::pt"));
+    assert!(!log::enabled(LogLevel::Error, "oxos::pci"));
+    // Only whole path segments match
+    assert!(!log::enabled(LogLevel::Info, "oxos::vmmx"));
+    assert!(!log::enabled(LogLevel::Trace, "oxos::vmm::walker"));
+    assert!(log::enabled(LogLevel::Warn, "oxos::thread"));
+    assert!(!log::enabled(LogLevel::Info, "oxos::thread"));
+    // The global level moves the rest, but not the overrides
+    log::set_level(LogLevel::Error);
+    assert!(!log::enabled(LogLevel::Warn, "oxos::thread"));
+    assert!(log::enabled(LogLevel::Debug, "oxos::vmm"));
+    log::set_targets([None; log::MAX_TARGETS]);
+    assert!(!log::enabled(LogLevel::Debug, "oxos::vmm"));
+    log::set_level(LogLevel::Trace);
+
+    error!("error {}", 1);
+    warn!("warn {}", 2);
+    info!("info {}", 3);
+    debug!("debug {}", 4);
+    trace!("trace {}", 5);
+    log::set_level(LogLevel::Off);
+    error!("this is never printed");
+    log::set_level(LogLevel::Info);
+    println!("Log Test PASSED");
+    machine::exit(machine::EXIT_QEMU_SUCCESS);
+}
@@ -0,0 +1,114 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(box_syntax)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(crate::test_runner)]
+
+extern crate alloc;
+
+use oxos::acpi;
+use oxos::acpi::ACPIHeader;
+use oxos::config::mb_info;
+use oxos::config::CONFIG;
+use oxos::kernel_init;
+use oxos::machine;
+use oxos::pmm;
+use oxos::pmm::FRAME_SIZE;
+use oxos::vmm;
+use oxos::HEAP_SIZE;
+use oxos::{print, println};
+
+use alloc::vec::Vec;
+
+fn test_runner(tests: &[&dyn Fn()]) {
+    unimplemented!("test_runner not used so should never be called");
+}
+
+#[no_mangle]
+pub extern "C" fn _start(mb_config: &mb_info, end: u64) -> ! {
+    kernel_init(mb_config, end);
+    pmm_test(end - vmm::KERNEL_BASE);
+}
+
+/// Whether the frame at addr overlaps memory the allocator must never hand out
+fn is_reserved(addr: u64, kernel_end: u64) -> bool {
+    let overlaps = |start: u64, end: u64| addr < end && start < addr + FRAME_SIZE;
+    unsafe {
+        overlaps(0, kernel_end)
+            || overlaps(CONFIG.heap_start, CONFIG.heap_start + HEAP_SIZE as u64)
+            || overlaps(CONFIG.mb_info_start, CONFIG.mb_info_end)
+            || CONFIG
+                .modules()
+                .iter()
+                .any(|module| overlaps(module.start, module.end))
+            || acpi::tables().any(|table| {
+                let start = vmm::virt_to_phys(table as *const ACPIHeader as u64);
+                overlaps(start, start + table.length() as u64)
+            })
+    }
+}
+
+fn check_frame(addr: u64, kernel_end: u64) {
+    assert_eq!(addr % FRAME_SIZE, 0);
+    assert!(
+        !is_reserved(addr, kernel_end),
+        "frame 0x{:x} is reserved",
+        addr
+    );
+}
+
+pub fn pmm_test(kernel_end: u64) -> ! {
+    println!("Running pmm test");
+    let before = pmm::stats();
+    assert_eq!(before.used_frames + before.free_frames, before.total_frames);
+    assert!(before.free_frames > 0);
+
+    // A freed frame is handed out again
+    let frame = pmm::alloc_frame().unwrap();
+    check_frame(frame, kernel_end);
+    assert_eq!(pmm::stats().free_frames, before.free_frames - 1);
+    assert_eq!(pmm::stats().used_frames, before.used_frames + 1);
+    pmm::free_frame(frame);
+    assert_eq!(pmm::stats().free_frames, before.free_frames);
+    assert_eq!(pmm::alloc_frame(), Some(frame));
+    pmm::free_frame(frame);
+
+    // Contiguous frames are consecutive and avoid reserved memory
+    let count = 16;
+    let start = pmm::alloc_contiguous(count).unwrap();
+    for i in 0..count as u64 {
+        check_frame(start + i * FRAME_SIZE, kernel_end);
+    }
+    assert_eq!(pmm::stats().free_frames, before.free_frames - count as u64);
+    // None of them can be handed out twice
+    let single = pmm::alloc_frame().unwrap();
+    assert!(single < start || single >= start + count as u64 * FRAME_SIZE);
+    pmm::free_frame(single);
+    pmm::free_contiguous(start, count);
+    assert_eq!(pmm::stats().free_frames, before.free_frames);
+
+    // Many frames at once are all distinct and usable
+    let mut frames: Vec<u64> = (0..1024).map(|_| pmm::alloc_frame().unwrap()).collect();
+    for &frame in frames.iter() {
+        check_frame(frame, kernel_end);
+        // The direct map reaches every frame
+        unsafe {
+            let word = vmm::phys_to_virt(frame) as *mut u64;
+            core::ptr::write_volatile(word, frame);
+            assert_eq!(core::ptr::read_volatile(word), frame);
+        }
+    }
+    frames.sort();
+    frames.dedup();
+    assert_eq!(frames.len(), 1024);
+    assert_eq!(pmm::stats().free_frames, before.free_frames - 1024);
+    for frame in frames {
+        pmm::free_frame(frame);
+    }
+    let after = pmm::stats();
+    assert_eq!(after.free_frames, before.free_frames);
+    assert_eq!(after.total_frames, before.total_frames);
+    println!("PMM Test PASSED");
+    machine::exit(machine::EXIT_QEMU_SUCCESS);
+}
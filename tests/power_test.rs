@@ -0,0 +1,67 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(box_syntax)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(crate::test_runner)]
+
+extern crate alloc;
+
+use oxos::config::mb_info;
+use oxos::kernel_init;
+use oxos::machine;
+use oxos::power;
+use oxos::power::SleepType;
+use oxos::{print, println};
+
+fn test_runner(tests: &[&dyn Fn()]) {
+    unimplemented!("test_runner not used so should never be called");
+}
+
+#[no_mangle]
+pub extern "C" fn _start(mb_config: &mb_info, end: u64) -> ! {
+    kernel_init(mb_config, end);
+    power_test();
+}
+
+pub fn power_test() -> ! {
+    println!("Running power test");
+    // Name(\_S5, Package(4) { 5, 0, Zero, Zero })
+    let rooted = [
+        0x10, 0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x0a, 0x04, 0x0a, 0x05, 0x0a, 0x00, 0x00,
+        0x00,
+    ];
+    assert_eq!(
+        power::parse_s5(&rooted),
+        Some(SleepType { pm1a: 5, pm1b: 0 })
+    );
+    // Name(_S5_, Package(2) { One, 0x107 }), with a two byte PkgLength
+    let word = [
+        0x08, b'_', b'S', b'5', b'_', 0x12, 0x40, 0x00, 0x02, 0x01, 0x0b, 0x07, 0x01,
+    ];
+    assert_eq!(
+        power::parse_s5(&word),
+        Some(SleepType {
+            pm1a: 1,
+            pm1b: 0x107
+        })
+    );
+    // A reference to _S5_ is skipped in favor of its definition
+    let reference = [
+        0x70, b'_', b'S', b'5', b'_', 0x60, 0x08, b'_', b'S', b'5', b'_', 0x12, 0x06, 0x02, 0x0a,
+        0x07, 0x00,
+    ];
+    assert_eq!(
+        power::parse_s5(&reference),
+        Some(SleepType { pm1a: 7, pm1b: 0 })
+    );
+    // Cut off before the second element
+    assert_eq!(power::parse_s5(&word[..10]), None);
+    assert_eq!(power::parse_s5(b"no sleep states"), None);
+
+    // QEMU's DSDT has \_S5
+    assert!(power::s5().is_some());
+
+    println!("Power Test PASSED");
+    machine::exit(machine::EXIT_QEMU_SUCCESS);
+}
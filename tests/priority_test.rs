@@ -0,0 +1,125 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(box_syntax)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(crate::test_runner)]
+
+extern crate alloc;
+
+use oxos::boot_options::SchedPolicy;
+use oxos::clock;
+use oxos::config::mb_info;
+use oxos::kernel_init;
+use oxos::machine;
+use oxos::scheduler;
+use oxos::semaphore::Semaphore;
+use oxos::smp;
+use oxos::thread;
+use oxos::thread::{Builder, ThreadState};
+use oxos::{print, println};
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+/// Enough low priority threads to keep every core busy, with some left waiting
+const SPINNERS: usize = 2 * smp::MAX_CORES;
+const LOW: u8 = 1;
+const HIGH: u8 = scheduler::MAX_PRIORITY;
+
+fn test_runner(tests: &[&dyn Fn()]) {
+    unimplemented!("test_runner not used so should never be called");
+}
+
+#[no_mangle]
+pub extern "C" fn _start(mb_config: &mb_info, end: u64) -> ! {
+    kernel_init(mb_config, end);
+    priority_test();
+}
+
+pub fn priority_test() -> ! {
+    println!("Running priority test");
+    thread::set_scheduler(scheduler::new(SchedPolicy::Priority));
+    // The test itself must get a core back whenever it wakes
+    thread::current().unwrap().set_priority(HIGH);
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let mut spinners = Vec::new();
+    for _ in 0..SPINNERS {
+        let stop = Arc::clone(&stop);
+        let spinner = Builder::new().name("spinner").priority(LOW).spawn(move || {
+            while !stop.load(Ordering::SeqCst) {
+                core::sync::atomic::spin_loop_hint();
+            }
+        });
+        assert_eq!(spinner.thread().priority(), LOW);
+        spinners.push(spinner);
+    }
+    // Let the spinners take over the cores at their own priority
+    thread::sleep(Duration::from_millis(10));
+
+    // It wakes from its sleep while the spinners hold every core, and must take one from
+    // them, then keep it for as long as it likes
+    let urgent = Builder::new().name("urgent").priority(HIGH).spawn(move || {
+        thread::sleep(Duration::from_millis(5));
+        let me = thread::current().unwrap();
+        let cpu_start = me.cpu_time();
+        let start = clock::now();
+        while clock::now() - start < 20 * clock::NANOS_PER_MILLI {
+            core::sync::atomic::spin_loop_hint();
+        }
+        (me.cpu_time() - cpu_start, clock::now() - start)
+    });
+    let (cpu_time, elapsed) = urgent.join().unwrap();
+    println!("urgent ran {} of {} ns", cpu_time, elapsed);
+    // Sharing a core with even one spinner would leave it half
+    assert!(cpu_time * 10 >= elapsed * 9);
+    assert!(!stop.load(Ordering::SeqCst));
+
+    // Still above the spinners, but below the threads it wakes, which take its core at once
+    thread::current().unwrap().set_priority(LOW + 1);
+    let sem = Semaphore::new(0);
+    let woken = Arc::new(AtomicBool::new(false));
+    let waiter = {
+        let sem = Arc::clone(&sem);
+        let woken = Arc::clone(&woken);
+        Builder::new().name("waiter").priority(HIGH).spawn(move || {
+            sem.down();
+            woken.store(true, Ordering::SeqCst);
+        })
+    };
+    while waiter.thread().state() != ThreadState::Blocked {
+        thread::sleep(Duration::from_millis(1));
+    }
+    // Another core may pick it up first, but it must not be left waiting
+    sem.up();
+    assert_ne!(waiter.thread().state(), ThreadState::Ready);
+    waiter.join().unwrap();
+    assert!(woken.load(Ordering::SeqCst));
+
+    // Below the spinners it never runs, until its priority goes up
+    let promoted_ran = Arc::new(AtomicBool::new(false));
+    let promoted = {
+        let promoted_ran = Arc::clone(&promoted_ran);
+        Builder::new()
+            .name("promoted")
+            .priority(0)
+            .spawn(move || promoted_ran.store(true, Ordering::SeqCst))
+    };
+    thread::sleep(Duration::from_millis(5));
+    assert!(!promoted_ran.load(Ordering::SeqCst));
+    assert_eq!(promoted.thread().state(), ThreadState::Ready);
+    promoted.thread().set_priority(HIGH);
+    assert_ne!(promoted.thread().state(), ThreadState::Ready);
+    promoted.join().unwrap();
+    assert!(promoted_ran.load(Ordering::SeqCst));
+
+    stop.store(true, Ordering::SeqCst);
+    for spinner in spinners {
+        spinner.join().unwrap();
+    }
+    println!("Priority Test PASSED");
+    machine::exit(machine::EXIT_QEMU_SUCCESS);
+}
@@ -0,0 +1,214 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(box_syntax)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(crate::test_runner)]
+
+extern crate alloc;
+
+use oxos::boot_options::SchedPolicy;
+use oxos::clock;
+use oxos::config::mb_info;
+use oxos::kernel_init;
+use oxos::machine;
+use oxos::scheduler;
+use oxos::scheduler::{FairShare, RoundRobin, Scheduler};
+use oxos::smp;
+use oxos::thread;
+use oxos::thread::{Builder, TCBInfo, ThreadId, ThreadInfo, TCB};
+use oxos::{print, println};
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+const LOW: u8 = 1;
+const HIGH: u8 = scheduler::MAX_PRIORITY;
+
+fn test_runner(tests: &[&dyn Fn()]) {
+    unimplemented!("test_runner not used so should never be called");
+}
+
+#[no_mangle]
+pub extern "C" fn _start(mb_config: &mb_info, end: u64) -> ! {
+    kernel_init(mb_config, end);
+    scheduler_test();
+}
+
+/// Stands in for the thread it describes, so a scheduler can hold it without running it
+struct Stub {
+    tcb_info: TCBInfo,
+    thread: Arc<ThreadInfo>,
+}
+
+impl TCB for Stub {
+    fn get_info(&mut self) -> *mut TCBInfo {
+        &mut self.tcb_info as *mut TCBInfo
+    }
+
+    fn get_work(&mut self) -> Box<dyn FnOnce() + Send> {
+        panic!("A stub never runs");
+    }
+
+    fn thread(&self) -> &Arc<ThreadInfo> {
+        &self.thread
+    }
+}
+
+fn stub(thread: &Arc<ThreadInfo>) -> Box<dyn TCB> {
+    Box::new(Stub {
+        tcb_info: TCBInfo::new(0),
+        thread: Arc::clone(thread),
+    })
+}
+
+/// A thread that has exited after running for at least millis of CPU time
+fn used(millis: u64) -> Arc<ThreadInfo> {
+    let handle = thread::spawn(move || {
+        let me = thread::current().unwrap();
+        while me.cpu_time() < millis * clock::NANOS_PER_MILLI {
+            core::sync::atomic::spin_loop_hint();
+        }
+    });
+    let thread = Arc::clone(handle.thread());
+    handle.join().unwrap();
+    thread
+}
+
+fn pop_id(scheduler: &mut dyn Scheduler) -> Option<ThreadId> {
+    scheduler.pop().map(|tcb| tcb.thread().id())
+}
+
+fn round_robin_order() {
+    let threads = [used(0), used(0), used(0)];
+    let mut rr = RoundRobin::new();
+    assert!(rr.is_empty());
+    for thread in threads.iter() {
+        rr.push(stub(thread));
+    }
+    assert_eq!(rr.len(), 3);
+    // Only a thread whose slice is over gives way, and only to another
+    let running = thread::current().unwrap();
+    assert!(rr.should_preempt(&running, true));
+    assert!(!rr.should_preempt(&running, false));
+    // Priority makes no difference
+    threads[2].set_priority(HIGH);
+    assert_eq!(pop_id(&mut rr), Some(threads[0].id()));
+    rr.push(stub(&threads[0]));
+    assert_eq!(
+        rr.remove(threads[1].id()).map(|tcb| tcb.thread().id()),
+        Some(threads[1].id())
+    );
+    assert!(rr.remove(threads[1].id()).is_none());
+    assert_eq!(pop_id(&mut rr), Some(threads[2].id()));
+    assert_eq!(pop_id(&mut rr), Some(threads[0].id()));
+    assert_eq!(pop_id(&mut rr), None);
+    assert!(!rr.should_preempt(&running, true));
+}
+
+fn fair_share_order() {
+    let light = used(2);
+    let heavy = used(8);
+    let medium = used(4);
+    let mut fair = FairShare::new();
+    fair.push(stub(&heavy));
+    fair.push(stub(&medium));
+    fair.push(stub(&light));
+    // The least CPU time first
+    assert_eq!(pop_id(&mut fair), Some(light.id()));
+    assert_eq!(pop_id(&mut fair), Some(medium.id()));
+    assert_eq!(pop_id(&mut fair), Some(heavy.id()));
+
+    // A higher priority ages more slowly
+    heavy.set_priority(HIGH);
+    medium.set_priority(LOW);
+    let mut fair = FairShare::new();
+    fair.push(stub(&medium));
+    fair.push(stub(&heavy));
+    assert_eq!(
+        fair.remove(medium.id()).map(|tcb| tcb.thread().id()),
+        Some(medium.id())
+    );
+    fair.push(stub(&medium));
+    assert_eq!(pop_id(&mut fair), Some(heavy.id()));
+    assert_eq!(pop_id(&mut fair), Some(medium.id()));
+
+    // Threads behind the last one picked start level with it, so they run in the order
+    // they became ready rather than the one that has run least taking over
+    let fresh = used(0);
+    let mut fair = FairShare::new();
+    fair.push(stub(&heavy));
+    assert_eq!(pop_id(&mut fair), Some(heavy.id()));
+    fair.push(stub(&light));
+    fair.push(stub(&fresh));
+    assert_eq!(pop_id(&mut fair), Some(light.id()));
+    assert_eq!(pop_id(&mut fair), Some(fresh.id()));
+    assert!(fair.is_empty());
+}
+
+/// Runs busy threads at low and high priority on every core, and returns the CPU time
+/// of each group
+fn share(policy: SchedPolicy) -> (Vec<u64>, Vec<u64>) {
+    thread::set_scheduler(scheduler::new(policy));
+    let online = (0..smp::MAX_CORES)
+        .filter(|&core| smp::is_online(core))
+        .count();
+    let stop = Arc::new(AtomicBool::new(false));
+    let spawn = |priority: u8| {
+        let stop = Arc::clone(&stop);
+        Builder::new()
+            .name("spinner")
+            .priority(priority)
+            .spawn(move || {
+                while !stop.load(Ordering::SeqCst) {
+                    core::sync::atomic::spin_loop_hint();
+                }
+            })
+    };
+    // Twice as many as there are cores, so they take turns
+    let low: Vec<_> = (0..online).map(|_| spawn(LOW)).collect();
+    let high: Vec<_> = (0..online).map(|_| spawn(HIGH)).collect();
+    thread::sleep(Duration::from_millis(200));
+    stop.store(true, Ordering::SeqCst);
+    let cpu_times = |handles: Vec<thread::JoinHandle<()>>| {
+        handles
+            .into_iter()
+            .map(|handle| {
+                let thread = Arc::clone(handle.thread());
+                handle.join().unwrap();
+                thread.cpu_time()
+            })
+            .collect::<Vec<u64>>()
+    };
+    let low = cpu_times(low);
+    let high = cpu_times(high);
+    println!("{:?}: low {:?}, high {:?}", policy, low, high);
+    (low, high)
+}
+
+fn total(cpu_times: &[u64]) -> u64 {
+    cpu_times.iter().sum()
+}
+
+pub fn scheduler_test() -> ! {
+    println!("Running scheduler test");
+    round_robin_order();
+    fair_share_order();
+
+    // Round robin shares the cores evenly, whatever the priorities
+    let (low, high) = share(SchedPolicy::RoundRobin);
+    assert!(low.iter().chain(high.iter()).all(|&cpu_time| cpu_time > 0));
+    assert!(total(&high) < total(&low) * 2);
+    assert!(total(&low) < total(&high) * 2);
+
+    // Fair share gives higher priorities a bigger share, but never all of it
+    let (low, high) = share(SchedPolicy::FairShare);
+    assert!(low.iter().all(|&cpu_time| cpu_time > 0));
+    assert!(total(&high) > total(&low) * 2);
+
+    println!("Scheduler Test PASSED");
+    machine::exit(machine::EXIT_QEMU_SUCCESS);
+}
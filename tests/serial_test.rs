@@ -0,0 +1,80 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(box_syntax)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(crate::test_runner)]
+
+extern crate alloc;
+
+use oxos::config::mb_info;
+use oxos::kernel_init;
+use oxos::machine;
+use oxos::semaphore::Semaphore;
+use oxos::thread;
+use oxos::thread::TCBImpl;
+use oxos::u8250;
+use oxos::{print, println};
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// Sent through loopback. Other cores can log while it is on, and their
+/// lines come back too, so the reader looks for this rather than for exact input.
+const MARKER: &[u8] = b"<serial test loopback marker>";
+
+fn test_runner(tests: &[&dyn Fn()]) {
+    unimplemented!("test_runner not used so should never be called");
+}
+
+#[no_mangle]
+pub extern "C" fn _start(mb_config: &mb_info, end: u64) -> ! {
+    kernel_init(mb_config, end);
+    serial_test();
+}
+
+pub fn serial_test() -> ! {
+    println!("Running serial test");
+    assert!(u8250::interrupt_driven());
+    // Nobody is typing
+    assert_eq!(u8250::try_get(), None);
+
+    // With interrupts off, printing queues the line rather than waiting for the port
+    let was = machine::disable();
+    println!("a line that waits in the transmit queue");
+    assert!(u8250::pending_output() > 0);
+    machine::enable(was);
+    // The port's transmit interrupt drains the queue
+    while u8250::pending_output() > 0 {
+        core::sync::atomic::spin_loop_hint();
+    }
+
+    // More output than the queue holds waits for room instead of dropping any
+    let was = machine::disable();
+    for line in 0..u8250::QUEUE_SIZE / 32 + 1 {
+        println!("filling the transmit queue, line {:4}", line);
+    }
+    machine::enable(was);
+    u8250::flush();
+    assert_eq!(u8250::pending_output(), 0);
+
+    // In loopback the port receives what it sends, through its receive interrupt
+    let done = Semaphore::new(0);
+    let reader_done = Arc::clone(&done);
+    thread::schedule(box TCBImpl::new(box move || {
+        let mut received = Vec::new();
+        while !received.ends_with(MARKER) {
+            received.push(u8250::get());
+        }
+        reader_done.up();
+    }));
+    u8250::set_loopback(true);
+    u8250::write_bytes(MARKER);
+    done.down();
+    u8250::set_loopback(false);
+    // Anything else that came back was output, not typed
+    while u8250::try_get().is_some() {}
+
+    println!("Serial Test PASSED");
+    machine::exit(machine::EXIT_QEMU_SUCCESS);
+}
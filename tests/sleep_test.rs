@@ -0,0 +1,90 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(box_syntax)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(crate::test_runner)]
+
+extern crate alloc;
+
+use oxos::clock;
+use oxos::config::mb_info;
+use oxos::ismutex::ISMutex;
+use oxos::kernel_init;
+use oxos::machine;
+use oxos::semaphore::Semaphore;
+use oxos::thread;
+use oxos::thread::TCBImpl;
+use oxos::{print, println};
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+fn test_runner(tests: &[&dyn Fn()]) {
+    unimplemented!("test_runner not used so should never be called");
+}
+
+#[no_mangle]
+pub extern "C" fn _start(mb_config: &mb_info, end: u64) -> ! {
+    kernel_init(mb_config, end);
+    sleep_test();
+}
+
+fn millis_since(start: u64) -> u64 {
+    (clock::now() - start) / clock::NANOS_PER_MILLI
+}
+
+pub fn sleep_test() -> ! {
+    println!("Running sleep test");
+    let start = clock::now();
+    thread::sleep(Duration::from_millis(20));
+    assert!(millis_since(start) >= 20);
+
+    let deadline = clock::now() + 10 * clock::NANOS_PER_MILLI;
+    thread::sleep_until(deadline);
+    assert!(clock::now() >= deadline);
+    // A deadline in the past returns at once
+    thread::sleep_until(0);
+
+    // Sleepers wake in deadline order, whatever order they went to sleep in
+    let woken = Arc::new(ISMutex::new(Vec::new()));
+    for &millis in [30u64, 10, 20].iter() {
+        let woken = Arc::clone(&woken);
+        let sleeper = TCBImpl::new(box move || {
+            thread::sleep(Duration::from_millis(millis));
+            woken.lock().push(millis);
+        });
+        thread::schedule(box sleeper);
+    }
+    while woken.lock().len() < 3 {
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(*woken.lock(), [10, 20, 30]);
+
+    // Timed out waits return false, no sooner than asked
+    let sem = Semaphore::new(0);
+    let start = clock::now();
+    assert!(!sem.down_timeout(Duration::from_millis(10)));
+    assert!(millis_since(start) >= 10);
+    assert!(!sem.down_timeout(Duration::from_millis(0)));
+
+    // Waits that get an up return true, without waiting out the timeout
+    let upper = Arc::clone(&sem);
+    let start = clock::now();
+    let handle = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(5));
+        upper.up();
+    });
+    assert!(sem.down_timeout(Duration::from_secs(10)));
+    assert!(millis_since(start) < 5_000);
+    handle.join().unwrap();
+    // The up cancelled the timeout, which would have kept the semaphore for 10 s
+    assert_eq!(Arc::strong_count(&sem), 1);
+
+    sem.up();
+    assert!(sem.down_timeout(Duration::from_millis(0)));
+
+    println!("Sleep Test PASSED");
+    machine::exit(machine::EXIT_QEMU_SUCCESS);
+}
@@ -0,0 +1,114 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(box_syntax)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(crate::test_runner)]
+
+extern crate alloc;
+
+use oxos::clock;
+use oxos::config::mb_info;
+use oxos::kernel_init;
+use oxos::machine;
+use oxos::semaphore::Semaphore;
+use oxos::smp;
+use oxos::thread;
+use oxos::thread::{ThreadId, ThreadInfo, ThreadState};
+use oxos::{print, println};
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+fn test_runner(tests: &[&dyn Fn()]) {
+    unimplemented!("test_runner not used so should never be called");
+}
+
+#[no_mangle]
+pub extern "C" fn _start(mb_config: &mb_info, end: u64) -> ! {
+    kernel_init(mb_config, end);
+    thread_info_test();
+}
+
+fn wait_for_state(thread: &ThreadInfo, state: ThreadState) {
+    while thread.state() != state {
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+fn listed(thread: &ThreadInfo) -> bool {
+    thread::threads().iter().any(|t| t.id() == thread.id())
+}
+
+fn idle_threads() -> Vec<ThreadId> {
+    thread::threads()
+        .iter()
+        .filter(|t| t.name() == Some("idle"))
+        .map(|t| t.id())
+        .collect()
+}
+
+pub fn thread_info_test() -> ! {
+    println!("Running thread info test");
+    // The code that booted the kernel is a thread too
+    let me = thread::current().unwrap();
+    assert_eq!(me.name(), Some("boot"));
+    assert_eq!(me.state(), ThreadState::Running);
+    assert!(listed(&me));
+
+    // Blocked on a semaphore until it goes up
+    let sem = Semaphore::new(0);
+    let waiter_sem = Arc::clone(&sem);
+    let waiter = thread::spawn_named("waiter", move || waiter_sem.down());
+    let waiter_info = Arc::clone(waiter.thread());
+    assert_eq!(waiter_info.name(), Some("waiter"));
+    assert!(waiter_info.id() > me.id());
+    wait_for_state(&waiter_info, ThreadState::Blocked);
+    assert!(listed(&waiter_info));
+    sem.up();
+    assert_eq!(waiter.join(), Ok(()));
+    assert_eq!(waiter_info.state(), ThreadState::Exited);
+    assert!(!listed(&waiter_info));
+
+    // Asleep until its timer wakes it
+    let sleeper = thread::spawn(|| thread::sleep(Duration::from_millis(50)));
+    assert_eq!(sleeper.thread().name(), None);
+    wait_for_state(sleeper.thread(), ThreadState::Sleeping);
+    let sleeper_info = Arc::clone(sleeper.thread());
+    sleeper.join().unwrap();
+    assert_eq!(sleeper_info.state(), ThreadState::Exited);
+    assert!(sleeper_info.switches() >= 2);
+
+    // CPU time only counts while the thread runs
+    let spinner = thread::spawn_named("spinner", || {
+        let me = thread::current().unwrap();
+        while me.cpu_time() < 5 * clock::NANOS_PER_MILLI {
+            core::sync::atomic::spin_loop_hint();
+        }
+        me.id()
+    });
+    let spinner_info = Arc::clone(spinner.thread());
+    assert_eq!(spinner.join(), Ok(spinner_info.id()));
+    assert!(spinner_info.cpu_time() >= 5 * clock::NANOS_PER_MILLI);
+    assert!(spinner_info.switches() >= 1);
+    assert!(spinner_info.core().is_some());
+
+    // A core gets one idle thread, however often it runs out of work
+    thread::sleep(Duration::from_millis(10));
+    let idle = idle_threads();
+    assert!(!idle.is_empty());
+    for _ in 0..20 {
+        thread::sleep(Duration::from_millis(1));
+    }
+    let online = (0..smp::MAX_CORES)
+        .filter(|&core| smp::is_online(core))
+        .count();
+    let idle_after = idle_threads();
+    assert!(idle_after.len() <= online);
+    assert!(idle.iter().all(|id| idle_after.contains(id)));
+
+    thread::print_threads();
+    println!("Thread Info Test PASSED");
+    machine::exit(machine::EXIT_QEMU_SUCCESS);
+}
@@ -0,0 +1,93 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(box_syntax)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(crate::test_runner)]
+
+extern crate alloc;
+
+use oxos::clock;
+use oxos::config::mb_info;
+use oxos::kernel_init;
+use oxos::machine;
+use oxos::timer;
+use oxos::{print, println};
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+static FIRED: AtomicU64 = AtomicU64::new(0);
+static FIRST_AT: AtomicU64 = AtomicU64::new(0);
+static SECOND_AT: AtomicU64 = AtomicU64::new(0);
+static CHAINED: AtomicU64 = AtomicU64::new(0);
+
+fn test_runner(tests: &[&dyn Fn()]) {
+    unimplemented!("test_runner not used so should never be called");
+}
+
+#[no_mangle]
+pub extern "C" fn _start(mb_config: &mb_info, end: u64) -> ! {
+    kernel_init(mb_config, end);
+    timer_test();
+}
+
+fn wait_until(deadline: u64) {
+    while clock::now() < deadline {
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+fn chain(remaining: u64) {
+    CHAINED.fetch_add(1, Ordering::SeqCst);
+    if remaining > 0 {
+        timer::after(Duration::from_millis(1), move || chain(remaining - 1));
+    }
+}
+
+pub fn timer_test() -> ! {
+    println!("Running timer test");
+    let start = clock::now();
+
+    // Added out of order, fired in deadline order, never early
+    let second = timer::after(Duration::from_millis(20), || {
+        SECOND_AT.store(clock::now(), Ordering::SeqCst);
+        FIRED.fetch_add(1, Ordering::SeqCst);
+    });
+    let first = timer::after(Duration::from_millis(10), || {
+        assert_eq!(FIRED.load(Ordering::SeqCst), 0);
+        FIRST_AT.store(clock::now(), Ordering::SeqCst);
+        FIRED.fetch_add(1, Ordering::SeqCst);
+    });
+    assert!(first.deadline() < second.deadline());
+    while FIRED.load(Ordering::SeqCst) < 2 {
+        core::sync::atomic::spin_loop_hint();
+    }
+    let first_at = FIRST_AT.load(Ordering::SeqCst);
+    let second_at = SECOND_AT.load(Ordering::SeqCst);
+    assert!(first_at >= first.deadline());
+    assert!(second_at >= second.deadline());
+    // Generous, for slow emulation
+    assert!(second_at - start < 100 * clock::NANOS_PER_MILLI);
+    // Both already ran
+    assert!(!timer::cancel(first));
+    assert!(!timer::cancel(second));
+
+    // A cancelled callback never runs
+    let cancelled = timer::after(Duration::from_millis(5), || {
+        FIRED.fetch_add(1, Ordering::SeqCst);
+    });
+    assert!(timer::cancel(cancelled));
+    assert!(!timer::cancel(cancelled));
+    wait_until(clock::now() + 20 * clock::NANOS_PER_MILLI);
+    assert_eq!(FIRED.load(Ordering::SeqCst), 2);
+
+    // Callbacks can add timers
+    chain(4);
+    while CHAINED.load(Ordering::SeqCst) < 5 {
+        core::sync::atomic::spin_loop_hint();
+    }
+
+    println!("Timer Test PASSED");
+    machine::exit(machine::EXIT_QEMU_SUCCESS);
+}
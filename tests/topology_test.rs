@@ -0,0 +1,53 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(box_syntax)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(crate::test_runner)]
+
+extern crate alloc;
+
+use oxos::config::mb_info;
+use oxos::config::CONFIG;
+use oxos::kernel_init;
+use oxos::machine;
+use oxos::smp;
+use oxos::{print, println};
+
+fn test_runner(tests: &[&dyn Fn()]) {
+    unimplemented!("test_runner not used so should never be called");
+}
+
+#[no_mangle]
+pub extern "C" fn _start(mb_config: &mb_info, end: u64) -> ! {
+    kernel_init(mb_config, end);
+    topology_test();
+}
+
+pub fn topology_test() -> ! {
+    println!("Running topology test");
+    let topology = unsafe { &CONFIG.topology };
+    assert!(!topology.is_empty());
+    assert_eq!(topology.len(), unsafe { CONFIG.total_procs } as usize);
+
+    // The bootstrap core is core 0, whatever its APIC ID
+    assert_eq!(smp::me(), 0);
+    assert_eq!(topology.apic_id(0), Some(smp::apic_id()));
+    assert!(smp::is_online(0));
+
+    for (core, cpu) in topology.cpus().iter().enumerate() {
+        assert_eq!(topology.core_of(cpu.apic_id), Some(core));
+        assert_eq!(topology.apic_id(core), Some(cpu.apic_id));
+        // Only IDs xAPIC mode can address make it into the topology
+        assert!(cpu.apic_id <= smp::MAX_XAPIC_ID);
+    }
+    assert_eq!(topology.x2apic_only, 0);
+    assert_eq!(topology.apic_id(topology.len()), None);
+
+    // QEMU wires every processor's LINT1 to NMI
+    assert!(topology.nmis_for(0).any(|nmi| nmi.lint == 1));
+    assert_eq!(topology.nmis_for(topology.len()).count(), 0);
+
+    println!("Topology Test PASSED");
+    machine::exit(machine::EXIT_QEMU_SUCCESS);
+}
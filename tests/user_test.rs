@@ -0,0 +1,49 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(box_syntax)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(crate::test_runner)]
+
+extern crate alloc;
+
+use oxos::config::mb_info;
+use oxos::dmesg;
+use oxos::kernel_init;
+use oxos::machine;
+use oxos::process;
+use oxos::{print, println};
+
+/// Assembled from src/user/hello.S by build.rs
+static HELLO: &[u8] = include_bytes!("../BUILD_FILES/hello.bin");
+/// What hello.S writes to stdout, and so its exit code
+const HELLO_MESSAGE: &str = "Hello from user mode!\n";
+
+fn test_runner(tests: &[&dyn Fn()]) {
+    unimplemented!("test_runner not used so should never be called");
+}
+
+#[no_mangle]
+pub extern "C" fn _start(mb_config: &mb_info, end: u64) -> ! {
+    kernel_init(mb_config, end);
+    user_test();
+}
+
+pub fn user_test() -> ! {
+    println!("Running user test");
+    let process = match process::spawn_flat(HELLO) {
+        Ok(process) => process,
+        Err(error) => panic!("Failed to load hello: {:?}", error),
+    };
+    let exit_code = process.wait();
+    println!("process {} exited with {}", process.pid(), exit_code);
+    assert_eq!(exit_code, HELLO_MESSAGE.len() as i64);
+    // What it wrote went to the console, which dmesg keeps a copy of
+    let mut written = false;
+    dmesg::for_each_recent(usize::MAX, |entry| {
+        written |= entry.text() == HELLO_MESSAGE.trim_end();
+    });
+    assert!(written, "the process's output never reached the console");
+    println!("User Test PASSED");
+    machine::exit(machine::EXIT_QEMU_SUCCESS);
+}
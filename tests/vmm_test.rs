@@ -0,0 +1,144 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(box_syntax)]
+#![reexport_test_harness_main = "test_main"]
+#![test_runner(crate::test_runner)]
+
+extern crate alloc;
+
+use oxos::config::mb_info;
+use oxos::kernel_init;
+use oxos::machine;
+use oxos::semaphore::Semaphore;
+use oxos::smp;
+use oxos::thread;
+use oxos::thread::TCBImpl;
+use oxos::vmm;
+use oxos::vmm::{PageFlags, VmmError, PAGE_SIZE};
+use oxos::{print, println};
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// The last page of the kernel stack region, which stacks never reach.
+/// Its tables are shared by every core's address space.
+const TEST_PAGE: u64 = vmm::KERNEL_STACK_REGION_END - PAGE_SIZE;
+/// Batches of threads to schedule before giving up on a core running one
+const MAX_BATCHES: usize = 100;
+
+fn test_runner(tests: &[&dyn Fn()]) {
+    unimplemented!("test_runner not used so should never be called");
+}
+
+#[no_mangle]
+pub extern "C" fn _start(mb_config: &mb_info, end: u64) -> ! {
+    kernel_init(mb_config, end);
+    vmm_test();
+}
+
+fn online_cores() -> u32 {
+    (0..smp::MAX_CORES)
+        .filter(|&core| smp::is_online(core))
+        .fold(0, |mask, core| mask | 1 << core)
+}
+
+/// Runs check on every online core, by scheduling threads until each core has run one
+fn on_every_core<F: 'static + Fn() + Send + Sync>(check: F) {
+    let check = Arc::new(check);
+    let seen = Arc::new(AtomicU32::new(0));
+    let online = online_cores();
+    let mut batches = 0;
+    while seen.load(Ordering::SeqCst) != online {
+        assert!(batches < MAX_BATCHES, "a core never ran a check");
+        batches += 1;
+        let done = Semaphore::new(0);
+        for _ in 0..2 * smp::MAX_CORES {
+            let check = Arc::clone(&check);
+            let seen = Arc::clone(&seen);
+            let done = Arc::clone(&done);
+            thread::schedule(box TCBImpl::new(box move || {
+                // Stay on one core from the check to recording it
+                let was = machine::disable();
+                check();
+                seen.fetch_or(1 << smp::me(), Ordering::SeqCst);
+                machine::enable(was);
+                done.up();
+            }));
+        }
+        for _ in 0..2 * smp::MAX_CORES {
+            done.down();
+        }
+    }
+}
+
+/// Where vaddr maps, through the tables every core shares for the stack region
+fn translate(vaddr: u64) -> Option<u64> {
+    vmm::KERNEL_MAP.lock().translate(vaddr)
+}
+
+fn read(vaddr: u64) -> u64 {
+    unsafe { core::ptr::read_volatile(vaddr as *const u64) }
+}
+
+pub fn vmm_test() -> ! {
+    println!("Running vmm test");
+    let first = vmm::alloc();
+    let second = vmm::alloc();
+    unsafe {
+        core::ptr::write_volatile(vmm::phys_to_virt(first) as *mut u64, 1);
+        core::ptr::write_volatile(vmm::phys_to_virt(second) as *mut u64, 2);
+    }
+    assert_eq!(translate(TEST_PAGE), None);
+    let flags = PageFlags::WRITABLE | PageFlags::NO_EXECUTE;
+
+    // Mapped, and every core reads through it, filling its TLB
+    vmm::KERNEL_MAP.lock().map(TEST_PAGE, first, flags).unwrap();
+    assert_eq!(
+        vmm::KERNEL_MAP.lock().map(TEST_PAGE, second, flags),
+        Err(VmmError::AlreadyMapped)
+    );
+    assert_eq!(translate(TEST_PAGE + 8), Some(first + 8));
+    on_every_core(move || {
+        assert_eq!(translate(TEST_PAGE), Some(first));
+        assert_eq!(read(TEST_PAGE), 1);
+    });
+
+    // Read-only, still mapped to the same frame everywhere
+    vmm::KERNEL_MAP
+        .lock()
+        .protect(TEST_PAGE, PageFlags::NO_EXECUTE)
+        .unwrap();
+    on_every_core(move || {
+        assert_eq!(translate(TEST_PAGE), Some(first));
+        assert_eq!(read(TEST_PAGE), 1);
+    });
+
+    // Unmapping shoots down every core's TLB entry, so once the page maps another frame,
+    // no core can still read the old one
+    assert_eq!(vmm::KERNEL_MAP.lock().unmap(TEST_PAGE), Ok(first));
+    assert_eq!(translate(TEST_PAGE), None);
+    on_every_core(|| assert_eq!(translate(TEST_PAGE), None));
+    vmm::KERNEL_MAP
+        .lock()
+        .map(TEST_PAGE, second, flags)
+        .unwrap();
+    on_every_core(move || {
+        assert_eq!(translate(TEST_PAGE), Some(second));
+        assert_eq!(read(TEST_PAGE), 2);
+    });
+
+    assert_eq!(vmm::KERNEL_MAP.lock().unmap(TEST_PAGE), Ok(second));
+    assert_eq!(
+        vmm::KERNEL_MAP.lock().unmap(TEST_PAGE),
+        Err(VmmError::NotMapped)
+    );
+    assert_eq!(
+        vmm::KERNEL_MAP.lock().protect(TEST_PAGE, flags),
+        Err(VmmError::NotMapped)
+    );
+    vmm::free(first);
+    vmm::free(second);
+    println!("VMM Test PASSED");
+    machine::exit(machine::EXIT_QEMU_SUCCESS);
+}